@@ -0,0 +1,372 @@
+// Copyright 2020 Oxide Computer Company
+
+//! An audit of the version definitions and exported symbols of each shared
+//! object in an archive (or a directory, such as an unpacked sysroot).
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+
+use crate::elf::{self, Object};
+use crate::error::{self, Error};
+use crate::report::Reporter;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Export {
+    pub name: String,
+    /// Whether the symbol is a data object, rather than a function
+    pub data: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Version {
+    pub name: String,
+    /// Whether this is the base version, named for the object itself
+    pub base: bool,
+    pub parents: Vec<String>,
+    pub symbols: Vec<Export>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Library {
+    pub path: String,
+    pub soname: Option<String>,
+    pub versions: Vec<Version>,
+    /// Exported symbols which have no version
+    pub unversioned: Vec<Export>,
+}
+
+impl Library {
+    /// Read the shared object in `data`, or return `None` if the data is
+    /// some other kind of file.
+    pub fn read(path: &str, data: &[u8]) -> Result<Option<Library>, String> {
+        if !Object::is_elf(data) {
+            return Ok(None);
+        }
+        let obj = Object::parse(data)?;
+        if obj.kind != elf::ET_DYN {
+            return Ok(None);
+        }
+
+        let mut versions: Vec<(u16, Version)> = obj
+            .verdefs()?
+            .into_iter()
+            .map(|d| {
+                (d.index, Version {
+                    name: d.name,
+                    base: d.flags & elf::VER_FLG_BASE != 0,
+                    parents: d.parents,
+                    symbols: Vec::new(),
+                })
+            })
+            .collect();
+        let mut unversioned = Vec::new();
+
+        for sym in obj.exports()? {
+            let v = versions
+                .iter_mut()
+                .find(|(i, _)| Some(*i) == sym.version)
+                .map(|(_, v)| v);
+            let export = Export {
+                data: sym.kind == elf::STT_OBJECT,
+                name: sym.name,
+            };
+            match v {
+                Some(v) => v.symbols.push(export),
+                None => unversioned.push(export),
+            }
+        }
+
+        let mut versions: Vec<Version> =
+            versions.into_iter().map(|(_, v)| v).collect();
+        for v in versions.iter_mut() {
+            v.symbols.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        unversioned.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(Some(Library {
+            path: path.to_string(),
+            soname: obj.dynamic_strings(elf::DT_SONAME)?.into_iter().next(),
+            versions,
+            unversioned,
+        }))
+    }
+}
+
+fn write_symbols(f: &mut fmt::Formatter, symbols: &[Export]) -> fmt::Result {
+    for sym in symbols {
+        if sym.data {
+            writeln!(f, "\t\t{} (data)", sym.name)?;
+        } else {
+            writeln!(f, "\t\t{}", sym.name)?;
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Library {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.soname {
+            Some(soname) => writeln!(f, "{}: {}", self.path, soname)?,
+            None => writeln!(f, "{}", self.path)?,
+        }
+        for v in &self.versions {
+            write!(f, "\t{}", v.name)?;
+            if v.base {
+                write!(f, " (base)")?;
+            }
+            if !v.parents.is_empty() {
+                write!(f, " (inherits {})", v.parents.join(", "))?;
+            }
+            writeln!(f)?;
+            write_symbols(f, &v.symbols)?;
+        }
+        if !self.unversioned.is_empty() {
+            writeln!(f, "\t(unversioned)")?;
+            write_symbols(f, &self.unversioned)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read a tar archive, which may be compressed with gzip.
+pub fn tar_archive<'a, R: Read + 'a>(
+    mut input: R,
+) -> io::Result<tar::Archive<Box<dyn Read + 'a>>> {
+    let mut magic = [0u8; 2];
+    input.read_exact(&mut magic)?;
+    let input = io::Cursor::new(magic).chain(input);
+    let input: Box<dyn Read> = if magic == [0x1f, 0x8b] {
+        Box::new(GzDecoder::new(input))
+    } else {
+        Box::new(input)
+    };
+    Ok(tar::Archive::new(input))
+}
+
+/// A regular file, with its contents, or a symbolic link, with its target.
+pub enum Member<'a> {
+    File(&'a [u8]),
+    Link(&'a str),
+}
+
+/// Call `func` with the path of each regular file and symbolic link in a tar
+/// archive (which may be compressed with gzip).
+fn each_tar_member<R: Read>(
+    input: R,
+    func: &mut dyn FnMut(&str, Member),
+) -> io::Result<()> {
+    let mut archive = tar_archive(input)?;
+    for ent in archive.entries()? {
+        let mut ent = ent?;
+        let path = ent.path()?.to_string_lossy().into_owned();
+        let path = crate::vfs::normalize(&path);
+        match ent.header().entry_type() {
+            tar::EntryType::Regular => {
+                let mut data = Vec::new();
+                ent.read_to_end(&mut data)?;
+                func(&path, Member::File(&data));
+            }
+            tar::EntryType::Symlink => {
+                let target = ent
+                    .link_name()?
+                    .map(|t| t.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                func(&path, Member::Link(&target));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Call `func` with the path (relative to `root`) of each regular file and
+/// symbolic link under the directory `dir`.  Links are not followed.
+fn each_dir_member(
+    root: &Path,
+    dir: &Path,
+    func: &mut dyn FnMut(&str, Member),
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for ent in entries {
+        let path = ent.path();
+        let rel = path.strip_prefix(root).unwrap().to_string_lossy();
+        let ft = ent.file_type()?;
+        if ft.is_dir() {
+            each_dir_member(root, &path, func)?;
+        } else if ft.is_file() {
+            func(&rel, Member::File(&fs::read(&path)?));
+        } else if ft.is_symlink() {
+            let target = fs::read_link(&path)?;
+            func(&rel, Member::Link(&target.to_string_lossy()));
+        }
+    }
+    Ok(())
+}
+
+/// Call `func` with the path of each regular file and symbolic link in the
+/// archive or directory at `path`.
+pub fn each_member(
+    path: &Path,
+    func: &mut dyn FnMut(&str, Member),
+) -> io::Result<()> {
+    if path.is_dir() {
+        each_dir_member(path, path, func)
+    } else {
+        each_tar_member(File::open(path)?, func)
+    }
+}
+
+/// Call `func` with the path and contents of each regular file in the
+/// archive or directory at `path`.
+pub fn each_file(
+    path: &Path,
+    func: &mut dyn FnMut(&str, &[u8]),
+) -> io::Result<()> {
+    each_member(path, &mut |path, member| {
+        if let Member::File(data) = member {
+            func(path, data);
+        }
+    })
+}
+
+/// Read each shared object in the archive or directory at `path`, returning
+/// those which could be read, and the path of each which could not (with the
+/// reason).
+#[allow(clippy::type_complexity)]
+pub fn scan(
+    path: &Path,
+) -> io::Result<(Vec<Library>, Vec<(String, String)>)> {
+    let mut libraries = Vec::new();
+    let mut errors = Vec::new();
+    each_file(path, &mut |path, data| match Library::read(path, data) {
+        Ok(Some(lib)) => libraries.push(lib),
+        Ok(None) => {}
+        Err(e) => errors.push((path.to_string(), e)),
+    })?;
+
+    libraries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok((libraries, errors))
+}
+
+/// Write the versions and symbols of each library in each of `paths`; it is
+/// an error if any library could not be read.
+pub fn write(
+    paths: &[PathBuf],
+    output: Option<&Path>,
+    report: &mut Reporter,
+) -> error::Result<()> {
+    let io_error = |error| Error::Io {
+        path: output.unwrap_or(Path::new("-")).to_path_buf(),
+        error,
+    };
+    let mut out: Box<dyn io::Write> = match output {
+        Some(path) => Box::new(File::create(path).map_err(io_error)?),
+        None => Box::new(io::stdout()),
+    };
+
+    let mut unread = 0;
+    for path in paths {
+        let (libraries, errors) = scan(path).map_err(|error| {
+            Error::Tar { path: Some(path.display().to_string()), error }
+        })?;
+        for lib in libraries {
+            write!(out, "{}", lib).map_err(io_error)?;
+        }
+        for (lib, e) in errors {
+            warning!(report, "{}: {}: {}", path.display(), lib, e);
+            unread += 1;
+        }
+    }
+
+    if unread > 0 {
+        return Err(Error::Check(format!("{} librar(y/ies) could not be \
+            audited", unread)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::elf::{Machine, Stub, StubSymbol, SymbolKind};
+
+    fn stub() -> Vec<u8> {
+        let sym = |name, kind, version| {
+            StubSymbol::new(name, kind, 4, Some(version))
+        };
+        Stub::library(Machine::I386, "libt.so.1")
+            .version("T_1", &[])
+            .version("T_2", &["T_1"])
+            .symbol(sym("zeta", SymbolKind::Function, "T_1"))
+            .symbol(sym("alpha", SymbolKind::Function, "T_1"))
+            .symbol(sym("counter", SymbolKind::Data, "T_2"))
+            .write()
+            .unwrap()
+    }
+
+    #[test]
+    fn report() {
+        let lib = Library::read("usr/lib/libt.so.1", &stub())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            lib.to_string(),
+            "usr/lib/libt.so.1: libt.so.1
+\tlibt.so.1 (base)
+\tT_1
+\t\talpha
+\t\tzeta
+\tT_2 (inherits T_1)
+\t\tcounter (data)
+"
+        );
+        assert_eq!(Library::read("README", b"text").unwrap(), None);
+        assert!(Library::read("bad.so", b"\x7fELF\x01").is_err());
+    }
+
+    #[test]
+    fn archive() {
+        let mut b = tar::Builder::new(Vec::new());
+        let data = stub();
+        let mut header = tar::Header::new_ustar();
+        header.set_path("./usr/lib/libt.so.1").unwrap();
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        b.append(&header, data.as_slice()).unwrap();
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_path("./usr/lib/libt.so").unwrap();
+        header.set_link_name("libt.so.1").unwrap();
+        header.set_size(0);
+        header.set_cksum();
+        b.append(&header, io::empty()).unwrap();
+        let tar = b.into_inner().unwrap();
+
+        let mut gz = flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        );
+        io::Write::write_all(&mut gz, &tar).unwrap();
+        let tgz = gz.finish().unwrap();
+
+        for input in &[tar, tgz] {
+            let mut members = Vec::new();
+            each_tar_member(input.as_slice(), &mut |p, member| {
+                members.push(match member {
+                    Member::File(_) => format!("f {}", p),
+                    Member::Link(target) => format!("l {} -> {}", p, target),
+                })
+            })
+            .unwrap();
+            assert_eq!(
+                members,
+                vec!["f usr/lib/libt.so.1", "l usr/lib/libt.so -> libt.so.1"]
+            );
+        }
+    }
+}
@@ -0,0 +1,544 @@
+// Copyright 2020 Oxide Computer Company
+
+//! A comparison of two sysroots (as archives or directories): the files
+//! added and removed, changes to headers, and changes to the SONAME,
+//! versions and exported symbols of each library.  Anything removed breaks
+//! the ABI for consumers of the sysroot.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::audit::{self, Library, Member};
+use crate::repo::hash_buf;
+
+/// Lines of context around each change to a header
+const CONTEXT: usize = 3;
+
+enum Content {
+    Header(String),
+    Library(Library),
+    Other(String),
+    /// A symbolic link, with its target
+    Link(String),
+}
+
+/// The contents of one sysroot, by path.
+struct Snapshot {
+    files: BTreeMap<String, Content>,
+}
+
+fn is_header(path: &str) -> bool {
+    path.starts_with("usr/include/")
+}
+
+impl Snapshot {
+    fn read(path: &Path) -> io::Result<Snapshot> {
+        let mut files = BTreeMap::new();
+        audit::each_member(path, &mut |path, member| {
+            let data = match member {
+                Member::File(data) => data,
+                Member::Link(target) => {
+                    files.insert(path.to_string(),
+                        Content::Link(target.to_string()));
+                    return;
+                }
+            };
+            let content = if is_header(path) {
+                Content::Header(String::from_utf8_lossy(data).into_owned())
+            } else {
+                match Library::read(path, data) {
+                    Ok(Some(lib)) => Content::Library(lib),
+                    // An object which cannot be read is compared as any
+                    // other file would be.
+                    Ok(None) | Err(_) => Content::Other(hash_buf(data)),
+                }
+            };
+            files.insert(path.to_string(), content);
+        })?;
+        Ok(Snapshot { files })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct LibraryDiff {
+    pub soname: Option<(Option<String>, Option<String>)>,
+    pub versions: Vec<(Change, String)>,
+    /// Symbols, named as "symbol@version"
+    pub symbols: Vec<(Change, String)>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Diff {
+    pub files: Vec<(Change, String)>,
+    /// The unified diff of each changed header
+    pub headers: Vec<(String, String)>,
+    pub libraries: Vec<(String, LibraryDiff)>,
+}
+
+impl LibraryDiff {
+    fn new(old: &Library, new: &Library) -> LibraryDiff {
+        let mut diff = LibraryDiff::default();
+        if old.soname != new.soname {
+            diff.soname = Some((old.soname.clone(), new.soname.clone()));
+        }
+
+        let versions = |lib: &Library| -> BTreeSet<String> {
+            lib.versions
+                .iter()
+                .filter(|v| !v.base)
+                .map(|v| v.name.clone())
+                .collect()
+        };
+        diff.versions = compare_sets(&versions(old), &versions(new));
+
+        let symbols = |lib: &Library| -> BTreeSet<String> {
+            let versioned = lib.versions.iter().flat_map(|v| {
+                v.symbols.iter().map(move |s| {
+                    // Symbols of the base version are named for the
+                    // object, not the version, so that a change of SONAME
+                    // is not reported for every symbol.
+                    if v.base {
+                        s.name.clone()
+                    } else {
+                        format!("{}@{}", s.name, v.name)
+                    }
+                })
+            });
+            versioned
+                .chain(lib.unversioned.iter().map(|s| s.name.clone()))
+                .collect()
+        };
+        diff.symbols = compare_sets(&symbols(old), &symbols(new));
+
+        diff
+    }
+
+    fn is_empty(&self) -> bool {
+        self.soname.is_none()
+            && self.versions.is_empty()
+            && self.symbols.is_empty()
+    }
+
+    fn breaks(&self) -> usize {
+        let removed = |v: &[(Change, String)]| {
+            v.iter().filter(|(c, _)| *c == Change::Removed).count()
+        };
+        usize::from(self.soname.is_some())
+            + removed(&self.versions)
+            + removed(&self.symbols)
+    }
+}
+
+fn compare_sets(
+    old: &BTreeSet<String>,
+    new: &BTreeSet<String>,
+) -> Vec<(Change, String)> {
+    let mut out: Vec<_> = old
+        .difference(new)
+        .map(|n| (Change::Removed, n.clone()))
+        .chain(new.difference(old).map(|n| (Change::Added, n.clone())))
+        .collect();
+    out.sort_by(|a, b| a.1.cmp(&b.1));
+    out
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// The limit on the number of edits which `edit_script` will search for;
+/// texts differing by more are treated as entirely replaced.
+const MAX_EDITS: isize = 2000;
+
+/// Find the shortest sequence of line deletions and insertions which turns
+/// `a` into `b`, with the algorithm of Myers ("An O(ND) Difference Algorithm
+/// and Its Variations").
+fn edit_script(a: &[&str], b: &[&str]) -> Vec<Op> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (n + m).min(MAX_EDITS);
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    // For each number of edits, the furthest point reached on each diagonal
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    let mut edits = None;
+
+    'search: for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[i - 1] < v[i + 1]) {
+                v[i + 1]
+            } else {
+                v[i - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+            if x >= n && y >= m {
+                edits = Some(d);
+                break 'search;
+            }
+        }
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+    }
+
+    let edits = match edits {
+        Some(d) => d,
+        None => {
+            let mut ops = vec![Op::Delete; a.len()];
+            ops.extend(vec![Op::Insert; b.len()]);
+            return ops;
+        }
+    };
+
+    // Walk back through the furthest points reached at each step.
+    let mut ops = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (1..=edits).rev() {
+        let prev = &trace[d as usize - 1];
+        let at = |k: isize| prev[(k + d - 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            ops.push(Op::Equal);
+            x -= 1;
+            y -= 1;
+        }
+        ops.push(if x == prev_x { Op::Insert } else { Op::Delete });
+        x = prev_x;
+        y = prev_y;
+    }
+    while x > 0 && y > 0 {
+        ops.push(Op::Equal);
+        x -= 1;
+        y -= 1;
+    }
+    ops.reverse();
+    ops
+}
+
+/// Produce a unified diff of two texts, without the file header lines.
+fn unified(old: &str, new: &str) -> String {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    let ops = edit_script(&a, &b);
+
+    // The position in each text before each operation
+    let mut pos = Vec::with_capacity(ops.len() + 1);
+    let (mut x, mut y) = (0, 0);
+    for op in &ops {
+        pos.push((x, y));
+        match op {
+            Op::Equal => {
+                x += 1;
+                y += 1;
+            }
+            Op::Delete => x += 1,
+            Op::Insert => y += 1,
+        }
+    }
+    pos.push((x, y));
+
+    let mut out = String::new();
+    let mut i = 0;
+    while i < ops.len() {
+        if ops[i] == Op::Equal {
+            i += 1;
+            continue;
+        }
+        // Extend the hunk while the changes are separated by no more than
+        // twice the context.
+        let start = i.saturating_sub(CONTEXT);
+        let mut end = i;
+        let mut equal = 0;
+        while end < ops.len() && equal <= 2 * CONTEXT {
+            if ops[end] == Op::Equal {
+                equal += 1;
+            } else {
+                equal = 0;
+            }
+            end += 1;
+        }
+        let end = end - equal.saturating_sub(CONTEXT);
+
+        // Ranges are written as diff(1) does: an empty range names the
+        // line before it, and a length of one is implied.
+        let range = |from: usize, to: usize| match to - from {
+            0 => format!("{},0", from),
+            1 => format!("{}", from + 1),
+            n => format!("{},{}", from + 1, n),
+        };
+        let ((x0, y0), (x1, y1)) = (pos[start], pos[end]);
+        out.push_str(&format!("@@ -{} +{} @@\n", range(x0, x1),
+            range(y0, y1)));
+        for (op, &(x, y)) in ops[start..end].iter().zip(&pos[start..end]) {
+            match op {
+                Op::Equal => out.push_str(&format!(" {}\n", a[x])),
+                Op::Delete => out.push_str(&format!("-{}\n", a[x])),
+                Op::Insert => out.push_str(&format!("+{}\n", b[y])),
+            }
+        }
+        i = end;
+    }
+    out
+}
+
+impl Diff {
+    /// Compare the sysroots (archives or directories) at `old` and `new`.
+    pub fn new(old: &Path, new: &Path) -> io::Result<Diff> {
+        let read = |path: &Path| {
+            Snapshot::read(path).map_err(|e| {
+                io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+            })
+        };
+        Ok(Diff::compare(&read(old)?, &read(new)?))
+    }
+
+    fn compare(old: &Snapshot, new: &Snapshot) -> Diff {
+        let mut diff = Diff::default();
+
+        for (path, content) in &old.files {
+            let other = match new.files.get(path) {
+                Some(other) => other,
+                None => {
+                    diff.files.push((Change::Removed, path.clone()));
+                    continue;
+                }
+            };
+            match (content, other) {
+                (Content::Header(a), Content::Header(b)) => {
+                    if a != b {
+                        diff.files.push((Change::Changed, path.clone()));
+                        diff.headers.push((path.clone(), unified(a, b)));
+                    }
+                }
+                (Content::Library(a), Content::Library(b)) => {
+                    let ld = LibraryDiff::new(a, b);
+                    if !ld.is_empty() {
+                        diff.files.push((Change::Changed, path.clone()));
+                        diff.libraries.push((path.clone(), ld));
+                    }
+                }
+                (Content::Other(a), Content::Other(b)) if a == b => {}
+                (Content::Link(a), Content::Link(b)) if a == b => {}
+                _ => diff.files.push((Change::Changed, path.clone())),
+            }
+        }
+        for path in new.files.keys() {
+            if !old.files.contains_key(path) {
+                diff.files.push((Change::Added, path.clone()));
+            }
+        }
+        diff.files.sort_by(|a, b| a.1.cmp(&b.1));
+
+        diff
+    }
+
+    /// The number of changes which break the ABI: anything removed, or a
+    /// change of SONAME.
+    pub fn breaks(&self) -> usize {
+        self.files.iter().filter(|(c, _)| *c == Change::Removed).count()
+            + self.libraries.iter().map(|(_, l)| l.breaks()).sum::<usize>()
+    }
+}
+
+const BREAK: &str = "  [ABI BREAK]";
+
+fn write_changes(
+    f: &mut fmt::Formatter,
+    what: &str,
+    changes: &[(Change, String)],
+) -> fmt::Result {
+    for (change, name) in changes {
+        match change {
+            Change::Added => writeln!(f, "\t+ {} {}", what, name)?,
+            Change::Removed => writeln!(f, "\t- {} {}{}", what, name, BREAK)?,
+            Change::Changed => writeln!(f, "\t* {} {}", what, name)?,
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.files.is_empty() {
+            writeln!(f, "FILES:")?;
+            write_changes(f, "file", &self.files)?;
+        }
+
+        if !self.libraries.is_empty() {
+            writeln!(f, "LIBRARIES:")?;
+        }
+        for (path, lib) in &self.libraries {
+            writeln!(f, "{}:", path)?;
+            if let Some((old, new)) = &lib.soname {
+                let name = |n: &Option<String>| {
+                    n.clone().unwrap_or_else(|| "(none)".to_string())
+                };
+                writeln!(f, "\t* SONAME {} -> {}{}", name(old), name(new),
+                    BREAK)?;
+            }
+            write_changes(f, "version", &lib.versions)?;
+            write_changes(f, "symbol", &lib.symbols)?;
+        }
+
+        if !self.headers.is_empty() {
+            writeln!(f, "HEADERS:")?;
+        }
+        for (path, diff) in &self.headers {
+            writeln!(f, "--- a/{}", path)?;
+            writeln!(f, "+++ b/{}", path)?;
+            write!(f, "{}", diff)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audit::{Export, Version};
+
+    #[test]
+    fn text_diff() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\n";
+        let new = "a\nb\nc\nD\ne\nf\ng\nh\ni\nj\nk\nl\nm\nn\n";
+        assert_eq!(
+            unified(old, new),
+            "@@ -1,7 +1,7 @@
+ a
+ b
+ c
+-d
++D
+ e
+ f
+ g
+@@ -11,3 +11,4 @@
+ k
+ l
+ m
++n
+"
+        );
+
+        assert_eq!(unified("x\n", "x\n"), "");
+        assert_eq!(unified("x\n", "y\n"), "@@ -1 +1 @@\n-x\n+y\n");
+        assert_eq!(unified("", "x\n"), "@@ -0,0 +1 @@\n+x\n");
+        assert_eq!(
+            unified("a\nb\nc\n", "a\nc\n"),
+            "@@ -1,3 +1,2 @@\n a\n-b\n c\n"
+        );
+    }
+
+    fn library(soname: &str, versions: &[(&str, &[&str])]) -> Content {
+        Content::Library(Library {
+            path: String::new(),
+            soname: Some(soname.to_string()),
+            versions: versions
+                .iter()
+                .enumerate()
+                .map(|(i, (name, syms))| Version {
+                    name: name.to_string(),
+                    base: i == 0,
+                    parents: Vec::new(),
+                    symbols: syms
+                        .iter()
+                        .map(|s| Export {
+                            name: s.to_string(),
+                            data: false,
+                        })
+                        .collect(),
+                })
+                .collect(),
+            unversioned: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn compare() {
+        let mut old = Snapshot {
+            files: BTreeMap::new(),
+        };
+        let mut new = Snapshot {
+            files: BTreeMap::new(),
+        };
+        let header = |s: &str| Content::Header(s.to_string());
+        old.files.insert("usr/include/a.h".into(), header("int a;\n"));
+        new.files.insert("usr/include/a.h".into(), header("long a;\n"));
+        old.files.insert("usr/include/gone.h".into(), header(""));
+        new.files.insert("usr/include/new.h".into(), header(""));
+        old.files.insert("etc/same".into(), Content::Other("1".into()));
+        new.files.insert("etc/same".into(), Content::Other("1".into()));
+        let link = |s: &str| Content::Link(s.to_string());
+        old.files.insert("usr/lib/libt.so".into(), link("libt.so.1"));
+        new.files.insert("usr/lib/libt.so".into(), link("libt.so.2"));
+        old.files.insert("usr/lib/libgone.so".into(), link("libgone.so.1"));
+        old.files.insert("usr/lib/libs.so".into(), link("libs.so.1"));
+        new.files.insert("usr/lib/libs.so".into(), link("libs.so.1"));
+        old.files.insert(
+            "usr/lib/libt.so.1".into(),
+            library("libt.so.1", &[("libt.so.1", &[]), ("T_1", &["f", "g"])]),
+        );
+        new.files.insert(
+            "usr/lib/libt.so.1".into(),
+            library("libt.so.2", &[
+                ("libt.so.2", &["h"]),
+                ("T_1", &["f"]),
+                ("T_2", &["g"]),
+            ]),
+        );
+
+        let diff = Diff::compare(&old, &new);
+        assert_eq!(
+            diff.files,
+            vec![
+                (Change::Changed, "usr/include/a.h".to_string()),
+                (Change::Removed, "usr/include/gone.h".to_string()),
+                (Change::Added, "usr/include/new.h".to_string()),
+                (Change::Removed, "usr/lib/libgone.so".to_string()),
+                (Change::Changed, "usr/lib/libt.so".to_string()),
+                (Change::Changed, "usr/lib/libt.so.1".to_string()),
+            ]
+        );
+        let lib = &diff.libraries[0].1;
+        assert_eq!(
+            lib.soname,
+            Some((Some("libt.so.1".into()), Some("libt.so.2".into())))
+        );
+        assert_eq!(lib.versions, vec![(Change::Added, "T_2".to_string())]);
+        assert_eq!(
+            lib.symbols,
+            vec![
+                (Change::Removed, "g@T_1".to_string()),
+                (Change::Added, "g@T_2".to_string()),
+                (Change::Added, "h".to_string()),
+            ]
+        );
+        // gone.h, libgone.so, the SONAME and g@T_1
+        assert_eq!(diff.breaks(), 4);
+        assert!(diff.to_string().contains(
+            "\t- symbol g@T_1  [ABI BREAK]\n"
+        ));
+    }
+}
@@ -0,0 +1,301 @@
+// Copyright 2020 Oxide Computer Company
+
+use super::read::Section;
+use super::*;
+
+/// Store `value` as an address-sized field at `off` in `buf`.
+fn put_word(buf: &mut [u8], off: u64, value: u64, is64: bool) {
+    let off = off as usize;
+    if is64 {
+        buf[off..off + 8].copy_from_slice(&value.to_le_bytes());
+    } else {
+        buf[off..off + 4].copy_from_slice(&(value as u32).to_le_bytes());
+    }
+}
+
+fn put_u32(buf: &mut [u8], off: u64, value: u32) {
+    let off = off as usize;
+    buf[off..off + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u16(buf: &mut [u8], off: u64, value: u16) {
+    let off = off as usize;
+    buf[off..off + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Whether the `info` field of a section header holds a section index.
+fn info_is_section(s: &Section) -> bool {
+    s.kind == SHT_REL || s.kind == SHT_RELA || s.flags & SHF_INFO_LINK != 0
+}
+
+/// Remove from the object in `data` each section for which `matches` is
+/// true of its name, returning the new object and the names of the sections
+/// removed, or `None` if there are none.  Only sections which are not
+/// allocated (i.e., not loaded at run time) are removed, with any relocation
+/// sections that apply to them; as such sections follow the loaded segments,
+/// the rest of the object is left where it is.
+#[allow(clippy::type_complexity)]
+pub fn remove_sections(
+    data: &[u8],
+    matches: &dyn Fn(&str) -> bool,
+) -> Result<Option<(Vec<u8>, Vec<String>)>, String> {
+    let obj = Object::parse(data)?;
+    let sections = &obj.sections;
+
+    let mut removed: Vec<bool> = sections
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            i != 0
+                && i != obj.shstrndx
+                && s.flags & SHF_ALLOC == 0
+                && matches(&s.name)
+        })
+        .collect();
+    for (i, s) in sections.iter().enumerate() {
+        if s.flags & SHF_ALLOC == 0
+            && info_is_section(s)
+            && removed.get(s.info as usize) == Some(&true)
+        {
+            removed[i] = true;
+        }
+    }
+    if !removed.contains(&true) {
+        return Ok(None);
+    }
+
+    // The new index of each section which remains.
+    let mut index = Vec::new();
+    let mut next = 0u32;
+    for &r in &removed {
+        index.push(if r { None } else { Some(next) });
+        if !r {
+            next += 1;
+        }
+    }
+    let renumber = |what: &str, i: u32| -> Result<u32, String> {
+        match index.get(i as usize) {
+            Some(Some(n)) => Ok(*n),
+            Some(None) => Err(format!("{} refers to a removed section", what)),
+            None => Err(format!("{} refers to an invalid section", what)),
+        }
+    };
+
+    // Everything up to the first section which is not loaded is kept as it
+    // is, so none of the segments may extend beyond it.  (Object::parse has
+    // checked that each section and segment is within the object.)
+    let is64 = obj.is_64();
+    let cut = sections
+        .iter()
+        .skip(1)
+        .filter(|s| s.flags & SHF_ALLOC == 0 && s.kind != SHT_NOBITS)
+        .map(|s| s.offset)
+        .min()
+        .unwrap_or(data.len() as u64);
+    let loaded = sections
+        .iter()
+        .filter(|s| s.flags & SHF_ALLOC != 0 && s.kind != SHT_NOBITS)
+        .map(|s| s.offset + s.size);
+    let segments = obj.segments.iter().map(|p| p.offset + p.filesz);
+    if loaded.chain(segments).any(|end| end > cut) {
+        return Err("loaded sections follow those which are not".into());
+    }
+    if cut < if is64 { 64 } else { 52 } {
+        return Err("sections overlap the ELF header".into());
+    }
+
+    let mut out = data
+        .get(..cut as usize)
+        .ok_or_else(|| "truncated ELF object".to_string())?
+        .to_vec();
+    let mut offsets = Vec::new();
+    for (s, r) in sections.iter().zip(&removed) {
+        if *r {
+            offsets.push(0);
+        } else if s.flags & SHF_ALLOC != 0 || s.kind == SHT_NULL {
+            offsets.push(s.offset);
+        } else {
+            let align = s.align.max(1);
+            if align > data.len() as u64 {
+                return Err(format!("section {} is aligned to {} bytes",
+                    s.name, align));
+            }
+            let off = (out.len() as u64).div_ceil(align) * align;
+            out.resize(off as usize, 0);
+            if s.kind != SHT_NOBITS {
+                out.extend_from_slice(obj.data(s)?);
+            }
+            offsets.push(off);
+        }
+    }
+
+    // Symbols refer to the sections in which they are defined.
+    let (entsize, shndx_at) = if is64 { (24, 6) } else { (16, 14) };
+    for (i, s) in sections.iter().enumerate() {
+        if removed[i] {
+            continue;
+        }
+        if s.kind == SHT_SYMTAB_SHNDX {
+            return Err("extended section indexes are not supported".into());
+        }
+        if s.kind != SHT_SYMTAB
+            && s.kind != SHT_DYNSYM
+            && s.kind != SHT_SUNW_LDYNSYM
+        {
+            continue;
+        }
+        for n in 0..s.size / entsize {
+            let at = offsets[i] + n * entsize + shndx_at;
+            let b = out
+                .get(at as usize..at as usize + 2)
+                .ok_or_else(|| "truncated symbol table".to_string())?;
+            let shndx = u16::from_le_bytes([b[0], b[1]]);
+            if shndx != SHN_UNDEF && shndx < SHN_LORESERVE {
+                let what = format!("a symbol in {}", s.name);
+                let new = renumber(&what, u32::from(shndx))?;
+                put_u16(&mut out, at, new as u16);
+            }
+        }
+    }
+
+    let at = if is64 { 58 } else { 46 };
+    let shentsize = u64::from(u16::from_le_bytes([data[at], data[at + 1]]));
+    let addrsize = if is64 { 8 } else { 4 };
+    let shoff = (out.len() as u64).div_ceil(addrsize) * addrsize;
+    out.resize(shoff as usize, 0);
+    let mut names = Vec::new();
+    for (i, s) in sections.iter().enumerate() {
+        if removed[i] {
+            names.push(s.name.clone());
+            continue;
+        }
+        let at = out.len() as u64;
+        let header = data
+            .get(s.header as usize..(s.header + shentsize) as usize)
+            .ok_or_else(|| "truncated ELF object".to_string())?;
+        out.extend_from_slice(header);
+        let (offset_at, link_at, info_at) =
+            if is64 { (24, 40, 44) } else { (16, 24, 28) };
+        put_word(&mut out, at + offset_at, offsets[i], is64);
+        if s.link != 0 {
+            let link = renumber(&format!("section {}", s.name), s.link)?;
+            put_u32(&mut out, at + link_at, link);
+        }
+        if info_is_section(s) && s.info != 0 {
+            let info = renumber(&format!("section {}", s.name), s.info)?;
+            put_u32(&mut out, at + info_at, info);
+        }
+    }
+
+    let shstrndx = renumber("the section name table", obj.shstrndx as u32)?;
+    if is64 {
+        put_word(&mut out, 40, shoff, true);
+        put_u16(&mut out, 60, next as u16);
+        put_u16(&mut out, 62, shstrndx as u16);
+    } else {
+        put_word(&mut out, 32, shoff, false);
+        put_u16(&mut out, 48, next as u16);
+        put_u16(&mut out, 50, shstrndx as u16);
+    }
+
+    Ok(Some((out, names)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Append a section to the (64-bit) object in `data`.
+    fn add_section(
+        data: &[u8],
+        name: &str,
+        kind: u32,
+        flags: u64,
+        link: u32,
+        info: u32,
+        contents: &[u8],
+    ) -> Vec<u8> {
+        let obj = Object::parse(data).unwrap();
+        let shstrtab = &obj.sections[obj.shstrndx];
+        let mut out = data.to_vec();
+
+        let offset = out.len() as u64;
+        out.extend_from_slice(contents);
+        let names = out.len() as u64;
+        out.extend_from_slice(obj.data(shstrtab).unwrap());
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        out.resize(out.len().div_ceil(8) * 8, 0);
+
+        let shoff = out.len() as u64;
+        for s in &obj.sections {
+            let at = out.len() as u64;
+            let header = &data[s.header as usize..s.header as usize + 64];
+            out.extend_from_slice(header);
+            if std::ptr::eq(s, shstrtab) {
+                put_word(&mut out, at + 24, names, true);
+                put_word(&mut out, at + 32, shstrtab.size + name.len() as u64
+                    + 1, true);
+            }
+        }
+        let mut header = [0u8; 64];
+        put_u32(&mut header, 0, shstrtab.size as u32);
+        put_u32(&mut header, 4, kind);
+        put_word(&mut header, 8, flags, true);
+        put_word(&mut header, 24, offset, true);
+        put_word(&mut header, 32, contents.len() as u64, true);
+        put_u32(&mut header, 40, link);
+        put_u32(&mut header, 44, info);
+        put_word(&mut header, 48, 1, true);
+        out.extend_from_slice(&header);
+
+        put_word(&mut out, 40, shoff, true);
+        put_u16(&mut out, 60, obj.sections.len() as u16 + 1);
+        out
+    }
+
+    #[test]
+    fn removal() {
+        let stub = Stub::library(Machine::Amd64, "libt.so.1")
+            .symbol(StubSymbol::new("f", SymbolKind::Function, 0, None));
+        let mut data = stub.write().unwrap();
+        let n = Object::parse(&data).unwrap().sections.len() as u32;
+        let dynsym = 2;
+        data = add_section(&data, ".comment", SHT_PROGBITS, 0, 0, 0, b"cc");
+        data = add_section(&data, ".SUNW_ctf", SHT_PROGBITS, 0, n + 4, 0,
+            b"ctf");
+        data = add_section(&data, ".debug_info", SHT_PROGBITS, 0, 0, 0,
+            b"dwarf");
+        data = add_section(&data, ".rela.debug_info", SHT_RELA,
+            SHF_INFO_LINK, dynsym, n + 2, b"");
+        data = add_section(&data, ".keep", SHT_STRTAB, 0, 0, 0, b"\0k\0");
+
+        let remove = |name: &str| name == ".comment" || name == ".debug_info";
+        let (out, removed) = remove_sections(&data, &remove).unwrap().unwrap();
+        assert_eq!(
+            removed,
+            vec![".comment", ".debug_info", ".rela.debug_info"]
+        );
+
+        let obj = Object::parse(&out).unwrap();
+        let names: Vec<&str> =
+            obj.sections.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(&names[n as usize..], &[".SUNW_ctf", ".keep"]);
+        let ctf = &obj.sections[n as usize];
+        assert_eq!(obj.data(ctf).unwrap(), b"ctf");
+        assert_eq!(obj.sections[ctf.link as usize].name, ".keep");
+        assert_eq!(obj.string(&obj.sections[ctf.link as usize], 1).unwrap(),
+            "k");
+        let syms = obj.dynsyms().unwrap();
+        assert_eq!(syms, Object::parse(&data).unwrap().dynsyms().unwrap());
+
+        // The CTF data refers to the string table, so it cannot go alone.
+        let remove = |name: &str| name == ".keep";
+        assert!(remove_sections(&data, &remove).is_err());
+        assert_eq!(remove_sections(&data, &|_| false).unwrap(), None);
+
+        // A malformed object is refused, rather than edited.
+        data[58..60].copy_from_slice(&16u16.to_le_bytes());
+        assert!(remove_sections(&data, &remove).is_err());
+    }
+}
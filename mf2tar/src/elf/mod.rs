@@ -0,0 +1,150 @@
+// Copyright 2020 Oxide Computer Company
+
+//! Support for the ELF objects delivered in a sysroot.  Only little-endian
+//! x86 objects (i386 and amd64) are handled.
+
+mod edit;
+mod read;
+mod stub;
+
+pub use edit::remove_sections;
+pub use read::{DynSym, Object};
+pub use stub::{Stub, StubSymbol, SymbolKind, VersionDef};
+
+pub const ELFCLASS32: u8 = 1;
+pub const ELFCLASS64: u8 = 2;
+pub const ELFDATA2LSB: u8 = 1;
+pub const EV_CURRENT: u8 = 1;
+pub const ELFOSABI_SOLARIS: u8 = 6;
+
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+pub const EM_386: u16 = 3;
+pub const EM_AMD64: u16 = 62;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+pub const SHT_NULL: u32 = 0;
+pub const SHT_PROGBITS: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+pub const SHT_RELA: u32 = 4;
+pub const SHT_HASH: u32 = 5;
+pub const SHT_DYNAMIC: u32 = 6;
+pub const SHT_NOBITS: u32 = 8;
+pub const SHT_REL: u32 = 9;
+pub const SHT_DYNSYM: u32 = 11;
+pub const SHT_SYMTAB_SHNDX: u32 = 18;
+pub const SHT_SUNW_LDYNSYM: u32 = 0x6fff_fff3;
+pub const SHT_SUNW_VERDEF: u32 = 0x6fff_fffd;
+pub const SHT_SUNW_VERSYM: u32 = 0x6fff_ffff;
+
+pub const SHF_WRITE: u64 = 0x1;
+pub const SHF_ALLOC: u64 = 0x2;
+pub const SHF_EXECINSTR: u64 = 0x4;
+pub const SHF_INFO_LINK: u64 = 0x40;
+
+pub const SHN_UNDEF: u16 = 0;
+pub const SHN_LORESERVE: u16 = 0xff00;
+pub const SHN_ABS: u16 = 0xfff1;
+
+pub const STB_LOCAL: u8 = 0;
+pub const STB_GLOBAL: u8 = 1;
+pub const STB_WEAK: u8 = 2;
+pub const STT_NOTYPE: u8 = 0;
+pub const STT_OBJECT: u8 = 1;
+pub const STT_FUNC: u8 = 2;
+
+pub const DT_NULL: u64 = 0;
+pub const DT_NEEDED: u64 = 1;
+pub const DT_HASH: u64 = 4;
+pub const DT_STRTAB: u64 = 5;
+pub const DT_SYMTAB: u64 = 6;
+pub const DT_STRSZ: u64 = 10;
+pub const DT_SYMENT: u64 = 11;
+pub const DT_SONAME: u64 = 14;
+pub const DT_RPATH: u64 = 15;
+pub const DT_RUNPATH: u64 = 29;
+pub const DT_VERSYM: u64 = 0x6fff_fff0;
+pub const DT_VERDEF: u64 = 0x6fff_fffc;
+pub const DT_VERDEFNUM: u64 = 0x6fff_fffd;
+
+pub const VER_DEF_CURRENT: u16 = 1;
+pub const VER_FLG_BASE: u16 = 0x1;
+/// The bit of a versym entry which marks a hidden (non-default) version
+pub const VERSYM_HIDDEN: u16 = 0x8000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Machine {
+    I386,
+    Amd64,
+}
+
+impl Machine {
+    pub fn from_name(name: &str) -> Option<Machine> {
+        match name {
+            "i386" => Some(Machine::I386),
+            "amd64" => Some(Machine::Amd64),
+            _ => None,
+        }
+    }
+
+    pub fn from_em(em: u16) -> Option<Machine> {
+        match em {
+            EM_386 => Some(Machine::I386),
+            EM_AMD64 => Some(Machine::Amd64),
+            _ => None,
+        }
+    }
+
+    pub fn is_64(self) -> bool {
+        self == Machine::Amd64
+    }
+
+    /// The size, in bytes, of an address on this machine
+    pub fn addrsize(self) -> u64 {
+        if self.is_64() {
+            8
+        } else {
+            4
+        }
+    }
+
+    pub fn em(self) -> u16 {
+        match self {
+            Machine::I386 => EM_386,
+            Machine::Amd64 => EM_AMD64,
+        }
+    }
+}
+
+/// The System V ABI hash function, as used for `.hash` and `vd_hash`.
+pub fn elf_hash(name: &[u8]) -> u32 {
+    let mut h: u32 = 0;
+    for &c in name {
+        h = (h << 4).wrapping_add(u32::from(c));
+        let g = h & 0xf000_0000;
+        if g != 0 {
+            h ^= g >> 24;
+        }
+        h &= !g;
+    }
+    h
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hash() {
+        assert_eq!(elf_hash(b""), 0);
+        assert_eq!(elf_hash(b"printf"), 0x077905a6);
+        assert_eq!(elf_hash(b"exit"), 0x0006cf04);
+        assert_eq!(elf_hash(b"__register_frame_info_table_bases"), 0x0fbc_a5f3);
+    }
+}
@@ -0,0 +1,490 @@
+// Copyright 2020 Oxide Computer Company
+
+use super::*;
+
+/// A section header, with its name resolved.
+#[derive(Clone, Debug)]
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub flags: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+    pub info: u32,
+    pub align: u64,
+    /// The offset of the section header itself
+    pub header: u64,
+}
+
+/// The extent of a segment in the file.
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub offset: u64,
+    pub filesz: u64,
+}
+
+/// A symbol from the dynamic symbol table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynSym {
+    pub name: String,
+    pub bind: u8,
+    pub kind: u8,
+    pub shndx: u16,
+    pub size: u64,
+    /// The index of the symbol's version definition, if the object has
+    /// versions (without `VERSYM_HIDDEN`)
+    pub version: Option<u16>,
+}
+
+/// A version definition, with the names of the versions it inherits.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerDef {
+    pub index: u16,
+    pub flags: u16,
+    pub name: String,
+    pub parents: Vec<String>,
+}
+
+/// An ELF object read from memory.
+pub struct Object<'a> {
+    data: &'a [u8],
+    is64: bool,
+    pub kind: u16,
+    pub machine: u16,
+    pub sections: Vec<Section>,
+    /// The index of the section header string table
+    pub shstrndx: usize,
+    pub segments: Vec<Segment>,
+}
+
+fn field(data: &[u8], off: u64, len: usize) -> Result<&[u8], String> {
+    let off = off as usize;
+    data.get(off..off.wrapping_add(len))
+        .ok_or_else(|| "truncated ELF object".to_string())
+}
+
+/// Check that the `size` bytes at `off` are all within `data`.
+fn extent(data: &[u8], off: u64, size: u64) -> Result<(), String> {
+    match off.checked_add(size) {
+        Some(end) if end <= data.len() as u64 => Ok(()),
+        _ => Err("truncated ELF object".to_string()),
+    }
+}
+
+impl<'a> Object<'a> {
+    /// Whether the data looks like an ELF object at all; other files are
+    /// quietly ignored by callers.
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(b"\x7fELF")
+    }
+
+    pub fn parse(data: &'a [u8]) -> Result<Object<'a>, String> {
+        if !Self::is_elf(data) || data.len() < 16 {
+            return Err("not an ELF object".to_string());
+        }
+        let is64 = match data[4] {
+            ELFCLASS32 => false,
+            ELFCLASS64 => true,
+            c => return Err(format!("unknown ELF class {}", c)),
+        };
+        if data[5] != ELFDATA2LSB {
+            return Err("big-endian ELF objects are not supported".into());
+        }
+
+        let mut obj = Object {
+            data,
+            is64,
+            kind: 0,
+            machine: 0,
+            sections: Vec::new(),
+            shstrndx: 0,
+            segments: Vec::new(),
+        };
+        obj.kind = obj.u16(16)?;
+        obj.machine = obj.u16(18)?;
+        let (phoff, phentsize, phnum) = if is64 {
+            (obj.u64(32)?, obj.u16(54)?, obj.u16(56)?)
+        } else {
+            (u64::from(obj.u32(28)?), obj.u16(42)?, obj.u16(44)?)
+        };
+        let (shoff, shentsize, shnum, shstrndx) = if is64 {
+            (obj.u64(40)?, obj.u16(58)?, obj.u16(60)?, obj.u16(62)?)
+        } else {
+            (
+                u64::from(obj.u32(32)?),
+                obj.u16(46)?,
+                obj.u16(48)?,
+                obj.u16(50)?,
+            )
+        };
+        obj.shstrndx = shstrndx as usize;
+
+        // The fields of each header are read at fixed offsets within it.
+        let (phmin, shmin) = if is64 { (56, 64) } else { (32, 40) };
+        if phnum != 0 && phentsize < phmin {
+            return Err(format!("program headers of {} bytes are too small",
+                phentsize));
+        }
+        if shnum != 0 && shentsize < shmin {
+            return Err(format!("section headers of {} bytes are too small",
+                shentsize));
+        }
+
+        let phentsize = u64::from(phentsize);
+        for i in 0..u64::from(phnum) {
+            extent(data, phoff, (i + 1) * phentsize)?;
+            let ph = phoff + i * phentsize;
+            let (offset, filesz) = if is64 {
+                (obj.u64(ph + 8)?, obj.u64(ph + 32)?)
+            } else {
+                (u64::from(obj.u32(ph + 4)?), u64::from(obj.u32(ph + 16)?))
+            };
+            extent(data, offset, filesz)?;
+            obj.segments.push(Segment { offset, filesz });
+        }
+
+        let shentsize = u64::from(shentsize);
+        let mut raw = Vec::new();
+        for i in 0..u64::from(shnum) {
+            extent(data, shoff, (i + 1) * shentsize)?;
+            let sh = shoff + i * shentsize;
+            let name = obj.u32(sh)?;
+            let kind = obj.u32(sh + 4)?;
+            let (flags, offset, size, link, info, align) = if is64 {
+                (
+                    obj.u64(sh + 8)?,
+                    obj.u64(sh + 24)?,
+                    obj.u64(sh + 32)?,
+                    obj.u32(sh + 40)?,
+                    obj.u32(sh + 44)?,
+                    obj.u64(sh + 48)?,
+                )
+            } else {
+                (
+                    u64::from(obj.u32(sh + 8)?),
+                    u64::from(obj.u32(sh + 16)?),
+                    u64::from(obj.u32(sh + 20)?),
+                    obj.u32(sh + 24)?,
+                    obj.u32(sh + 28)?,
+                    u64::from(obj.u32(sh + 32)?),
+                )
+            };
+            if kind != SHT_NOBITS {
+                extent(data, offset, size)?;
+            }
+            raw.push((name, Section {
+                name: String::new(),
+                kind,
+                flags,
+                offset,
+                size,
+                link,
+                info,
+                align,
+                header: sh,
+            }));
+        }
+        if let Some((_, shstrtab)) = raw.get(shstrndx as usize) {
+            let shstrtab = shstrtab.clone();
+            for (name, s) in raw.iter_mut() {
+                s.name = obj.string(&shstrtab, *name)?;
+            }
+        }
+        obj.sections = raw.into_iter().map(|(_, s)| s).collect();
+
+        Ok(obj)
+    }
+
+    pub fn is_64(&self) -> bool {
+        self.is64
+    }
+
+    fn u16(&self, off: u64) -> Result<u16, String> {
+        let b = field(self.data, off, 2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&self, off: u64) -> Result<u32, String> {
+        let b = field(self.data, off, 4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&self, off: u64) -> Result<u64, String> {
+        let b = field(self.data, off, 8)?;
+        let mut a = [0u8; 8];
+        a.copy_from_slice(b);
+        Ok(u64::from_le_bytes(a))
+    }
+
+    fn word(&self, off: u64) -> Result<u64, String> {
+        if self.is64 {
+            self.u64(off)
+        } else {
+            self.u32(off).map(u64::from)
+        }
+    }
+
+    /// The contents of a section.
+    pub fn data(&self, section: &Section) -> Result<&'a [u8], String> {
+        field(self.data, section.offset, section.size as usize)
+    }
+
+    /// Read the string at `index` in the string table `strtab`.
+    pub fn string(
+        &self,
+        strtab: &Section,
+        index: u32,
+    ) -> Result<String, String> {
+        let table = self.data(strtab)?;
+        let s = table
+            .get(index as usize..)
+            .ok_or_else(|| format!("string index {} out of range", index))?;
+        let end = s.iter().position(|&c| c == 0).unwrap_or(s.len());
+        Ok(String::from_utf8_lossy(&s[..end]).into_owned())
+    }
+
+    /// The entries of the dynamic section, with the (tag, value) of each,
+    /// and the string table to which string values refer.
+    #[allow(clippy::type_complexity)]
+    pub fn dynamic(
+        &self,
+    ) -> Result<Option<(Vec<(u64, u64)>, &Section)>, String> {
+        let dynamic = self.sections.iter().find(|s| s.kind == SHT_DYNAMIC);
+        let dynamic = match dynamic {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let strtab = self
+            .sections
+            .get(dynamic.link as usize)
+            .ok_or_else(|| "invalid dynamic string table".to_string())?;
+
+        let entsize = if self.is64 { 16 } else { 8 };
+        let mut entries = Vec::new();
+        for i in 0..dynamic.size / entsize {
+            let off = dynamic.offset + i * entsize;
+            let tag = self.word(off)?;
+            if tag == DT_NULL {
+                break;
+            }
+            entries.push((tag, self.word(off + entsize / 2)?));
+        }
+        Ok(Some((entries, strtab)))
+    }
+
+    fn section(&self, kind: u32) -> Option<&Section> {
+        self.sections.iter().find(|s| s.kind == kind)
+    }
+
+    fn linked(&self, section: &Section) -> Result<&Section, String> {
+        self.sections
+            .get(section.link as usize)
+            .ok_or_else(|| format!("invalid link for {}", section.name))
+    }
+
+    /// The symbols of the dynamic symbol table, including the null symbol
+    /// at index 0.
+    pub fn dynsyms(&self) -> Result<Vec<DynSym>, String> {
+        let dynsym = match self.section(SHT_DYNSYM) {
+            Some(s) => s,
+            None => return Ok(Vec::new()),
+        };
+        let strtab = self.linked(dynsym)?;
+        let versym = self.section(SHT_SUNW_VERSYM);
+
+        let entsize = if self.is64 { 24 } else { 16 };
+        let mut syms = Vec::new();
+        for i in 0..dynsym.size / entsize {
+            let off = dynsym.offset + i * entsize;
+            let (info, shndx, size) = if self.is64 {
+                (
+                    field(self.data, off + 4, 1)?[0],
+                    self.u16(off + 6)?,
+                    self.u64(off + 16)?,
+                )
+            } else {
+                (
+                    field(self.data, off + 12, 1)?[0],
+                    self.u16(off + 14)?,
+                    u64::from(self.u32(off + 8)?),
+                )
+            };
+            let version = match versym {
+                Some(vs) => Some(self.u16(vs.offset + i * 2)? & !VERSYM_HIDDEN),
+                None => None,
+            };
+            syms.push(DynSym {
+                name: self.string(strtab, self.u32(off)?)?,
+                bind: info >> 4,
+                kind: info & 0xf,
+                shndx,
+                size,
+                version,
+            });
+        }
+        Ok(syms)
+    }
+
+    /// The dynamic symbols which the object exports: those which are defined
+    /// and not local.
+    pub fn exports(&self) -> Result<Vec<DynSym>, String> {
+        let verdefs = self.verdefs()?;
+        let mut out = Vec::new();
+        for sym in self.dynsyms()? {
+            if sym.bind == STB_LOCAL || sym.shndx == SHN_UNDEF {
+                continue;
+            }
+            // The link-editor defines an absolute symbol for each version.
+            if sym.shndx == SHN_ABS && verdefs.iter()
+                .any(|d| Some(d.index) == sym.version && d.name == sym.name)
+            {
+                continue;
+            }
+            out.push(sym);
+        }
+        Ok(out)
+    }
+
+    /// The version definitions (from `.SUNW_version` or `.gnu.version_d`,
+    /// which share a section type), in the order they appear.
+    pub fn verdefs(&self) -> Result<Vec<VerDef>, String> {
+        let verdef = match self.section(SHT_SUNW_VERDEF) {
+            Some(s) => s,
+            None => return Ok(Vec::new()),
+        };
+        let strtab = self.linked(verdef)?;
+
+        let mut defs = Vec::new();
+        let mut off = verdef.offset;
+        loop {
+            let flags = self.u16(off + 2)?;
+            let index = self.u16(off + 4)?;
+            let cnt = self.u16(off + 6)?;
+            let mut aux = off + u64::from(self.u32(off + 12)?);
+            let mut names = Vec::new();
+            for _ in 0..cnt {
+                names.push(self.string(strtab, self.u32(aux)?)?);
+                aux += u64::from(self.u32(aux + 4)?);
+            }
+            if names.is_empty() {
+                return Err(format!("version definition {} has no name",
+                    index));
+            }
+            let name = names.remove(0);
+            defs.push(VerDef {
+                index,
+                flags,
+                name,
+                parents: names,
+            });
+
+            let next = self.u32(off + 16)?;
+            if next == 0 || defs.len() > verdef.size as usize {
+                break;
+            }
+            off += u64::from(next);
+        }
+        Ok(defs)
+    }
+
+    /// The strings of the dynamic entries with the given tag; e.g., the
+    /// names of the dependencies for `DT_NEEDED`.
+    pub fn dynamic_strings(&self, tag: u64) -> Result<Vec<String>, String> {
+        let (entries, strtab) = match self.dynamic()? {
+            Some(d) => d,
+            None => return Ok(Vec::new()),
+        };
+        entries
+            .iter()
+            .filter(|(t, _)| *t == tag)
+            .map(|(_, v)| self.string(strtab, *v as u32))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_stub() {
+        for &machine in &[Machine::I386, Machine::Amd64] {
+            let stub = Stub {
+                needed: vec!["libc.so.1".into(), "libm.so.2".into()],
+                ..Stub::library(machine, "libtest.so.1")
+            };
+            let bytes = stub.write().unwrap();
+            let obj = Object::parse(&bytes).unwrap();
+
+            assert_eq!(obj.is_64(), machine.is_64());
+            assert_eq!(obj.kind, ET_DYN);
+            assert_eq!(Machine::from_em(obj.machine), Some(machine));
+            assert_eq!(obj.sections[2].name, ".dynsym");
+            assert_eq!(
+                obj.dynamic_strings(DT_NEEDED).unwrap(),
+                vec!["libc.so.1", "libm.so.2"]
+            );
+            assert_eq!(
+                obj.dynamic_strings(DT_SONAME).unwrap(),
+                vec!["libtest.so.1"]
+            );
+        }
+        assert!(Object::parse(b"\x7fELF").is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        let stub = Stub::library(Machine::Amd64, "libm.so.1");
+        let bytes = stub.write().unwrap();
+        let patch = |at: usize, value: &[u8]| {
+            let mut data = bytes.clone();
+            data[at..at + value.len()].copy_from_slice(value);
+            Object::parse(&data).map(|_| ())
+        };
+        assert!(patch(58, &16u16.to_le_bytes()).unwrap_err()
+            .contains("too small"));
+        assert!(patch(54, &8u16.to_le_bytes()).is_err());
+        assert!(patch(32, &(u64::MAX - 8).to_le_bytes()).is_err());
+        assert!(patch(40, &u64::MAX.to_le_bytes()).is_err());
+
+        // The offset of the second section
+        let obj = Object::parse(&bytes).unwrap();
+        let at = obj.sections[1].header as usize + 24;
+        assert!(patch(at, &u64::MAX.to_le_bytes()).is_err());
+    }
+
+    #[test]
+    fn read_versions() {
+        let func = |name, version| {
+            StubSymbol::new(name, SymbolKind::Function, 0, Some(version))
+        };
+        let stub = Stub::library(Machine::Amd64, "libv.so.1")
+            .version("V_1", &[])
+            .version("V_2", &["V_1"])
+            .symbol(func("one", "V_1"))
+            .symbol(func("two", "V_2"))
+            .symbol(StubSymbol::new("data", SymbolKind::Data, 8, None));
+        let bytes = stub.write().unwrap();
+        let obj = Object::parse(&bytes).unwrap();
+
+        let defs = obj.verdefs().unwrap();
+        let names: Vec<_> = defs.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, vec!["libv.so.1", "V_1", "V_2"]);
+        assert_eq!(defs[0].flags, VER_FLG_BASE);
+        assert_eq!(defs[2].index, 3);
+        assert_eq!(defs[2].parents, vec!["V_1".to_string()]);
+
+        let syms = obj.dynsyms().unwrap();
+        assert_eq!(syms.len(), 6);
+        assert_eq!(syms[3].name, "one");
+        assert_eq!(syms[3].kind, STT_FUNC);
+        assert_eq!(syms[3].version, Some(2));
+        assert_eq!(syms[4].version, Some(3));
+        assert_eq!(syms[5].name, "data");
+        assert_eq!(syms[5].kind, STT_OBJECT);
+        assert_eq!(syms[5].bind, STB_GLOBAL);
+        assert_eq!(syms[5].size, 8);
+        assert_eq!(syms[5].version, Some(1));
+    }
+}
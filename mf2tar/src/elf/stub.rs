@@ -0,0 +1,610 @@
+// Copyright 2020 Oxide Computer Company
+
+use std::collections::HashMap;
+
+use super::*;
+
+/// A shared object which carries only what the link-editor consumes when
+/// linking against it: a SONAME, version definitions and dynamic symbols.
+/// Each function is a `ud2` instruction, and each data object is zero-filled;
+/// none of it is meant to be executed.
+#[derive(Debug)]
+pub struct Stub {
+    pub machine: Machine,
+    pub soname: String,
+    pub needed: Vec<String>,
+    pub runpath: Option<String>,
+    /// Version definitions beyond the base version (which takes its name
+    /// from the SONAME), in the order their indices are assigned.
+    pub versions: Vec<VersionDef>,
+    pub symbols: Vec<StubSymbol>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VersionDef {
+    pub name: String,
+    pub parents: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymbolKind {
+    Function,
+    Data,
+    /// A symbol with no type, such as `_etext`, placed with the data
+    NoType,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StubSymbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub size: u64,
+    /// The version defining the symbol, or `None` for the base version
+    pub version: Option<String>,
+    /// Whether the definition is weak, rather than global
+    pub weak: bool,
+}
+
+const PAGESIZE: u64 = 0x1000;
+const FUNC_SLOT: u64 = 16;
+const UD2: [u8; 2] = [0x0f, 0x0b];
+
+/// Section header indices, in the order the sections are laid out
+const SH_HASH: u32 = 1;
+const SH_DYNSYM: u32 = 2;
+const SH_DYNSTR: u32 = 3;
+const SH_VERDEF: u32 = 4;
+const SH_VERSYM: u32 = 5;
+const SH_TEXT: u16 = 6;
+const SH_DATA: u16 = 8;
+const SH_SHSTRTAB: u16 = 9;
+const SH_COUNT: u16 = 10;
+
+/// Bucket counts for `.hash`, chosen as primes in the manner of other
+/// link-editors.
+const BUCKETS: &[u32] = &[1, 3, 17, 37, 67, 97, 131, 197, 263, 521, 1031];
+
+fn align(v: u64, a: u64) -> u64 {
+    (v + a - 1) & !(a - 1)
+}
+
+#[derive(Default)]
+struct StrTab {
+    buf: Vec<u8>,
+    index: HashMap<String, u32>,
+}
+
+impl StrTab {
+    fn new() -> Self {
+        StrTab {
+            buf: vec![0],
+            index: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        if let Some(&off) = self.index.get(s) {
+            return off;
+        }
+        let off = self.buf.len() as u32;
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+        self.index.insert(s.to_string(), off);
+        off
+    }
+
+    fn get(&self, s: &str) -> u32 {
+        self.index[s]
+    }
+}
+
+/// Little-endian output, with address-sized fields following the class of
+/// the object.
+struct Out {
+    buf: Vec<u8>,
+    is64: bool,
+}
+
+impl Out {
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+    fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+    fn word(&mut self, v: u64) {
+        if self.is64 {
+            self.u64(v);
+        } else {
+            self.u32(v as u32);
+        }
+    }
+    fn pad_to(&mut self, off: u64) {
+        assert!(self.buf.len() as u64 <= off);
+        self.buf.resize(off as usize, 0);
+    }
+}
+
+struct Section {
+    name: &'static str,
+    kind: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+struct Sym<'a> {
+    name: &'a str,
+    value: u64,
+    size: u64,
+    bind: u8,
+    kind: u8,
+    shndx: u16,
+    version: u16,
+}
+
+impl Stub {
+    fn version_index(&self, name: &Option<String>) -> Result<u16, String> {
+        match name {
+            None => Ok(1),
+            Some(name) => self
+                .versions
+                .iter()
+                .position(|v| &v.name == name)
+                .map(|i| i as u16 + 2)
+                .ok_or_else(|| format!("undefined version {}", name)),
+        }
+    }
+
+    /// Produce the contents of the shared object.
+    pub fn write(&self) -> Result<Vec<u8>, String> {
+        let is64 = self.machine.is_64();
+        let addrsize = self.machine.addrsize();
+        let (ehsize, phentsize, shentsize, symentsize, dynentsize) = if is64 {
+            (64, 56, 64, 24, 16)
+        } else {
+            (52, 32, 40, 16, 8)
+        };
+        let phnum = 3;
+
+        let mut dynstr = StrTab::new();
+        dynstr.add(&self.soname);
+        for n in self.needed.iter().chain(self.runpath.iter()) {
+            dynstr.add(n);
+        }
+        for v in &self.versions {
+            dynstr.add(&v.name);
+            for p in &v.parents {
+                if !self.versions.iter().any(|v| &v.name == p) {
+                    return Err(format!(
+                        "version {} inherits undefined version {}",
+                        v.name, p
+                    ));
+                }
+            }
+        }
+
+        /*
+         * Lay out the symbols: the null symbol, a symbol naming each of the
+         * non-base versions (as the link-editor would define), and then the
+         * functions and data.  Values are filled in once addresses are known.
+         */
+        let mut syms = vec![Sym {
+            name: "",
+            value: 0,
+            size: 0,
+            bind: 0,
+            kind: 0,
+            shndx: 0,
+            version: 0,
+        }];
+        for (i, v) in self.versions.iter().enumerate() {
+            syms.push(Sym {
+                name: &v.name,
+                value: 0,
+                size: 0,
+                bind: STB_GLOBAL,
+                kind: STT_OBJECT,
+                shndx: SHN_ABS,
+                version: i as u16 + 2,
+            });
+        }
+        let mut text_size = 0;
+        let mut data_size = 0;
+        for s in &self.symbols {
+            dynstr.add(&s.name);
+            let (kind, shndx, value) = match s.kind {
+                SymbolKind::Function => {
+                    let off = text_size;
+                    text_size += FUNC_SLOT;
+                    (STT_FUNC, SH_TEXT, off)
+                }
+                SymbolKind::Data | SymbolKind::NoType => {
+                    let off = align(data_size, s.size.clamp(1, addrsize)
+                        .next_power_of_two());
+                    data_size = off + s.size;
+                    let kind = if s.kind == SymbolKind::Data {
+                        STT_OBJECT
+                    } else {
+                        STT_NOTYPE
+                    };
+                    (kind, SH_DATA, off)
+                }
+            };
+            syms.push(Sym {
+                name: &s.name,
+                value,
+                size: if s.kind == SymbolKind::Function { 2 } else { s.size },
+                bind: if s.weak { STB_WEAK } else { STB_GLOBAL },
+                kind,
+                shndx,
+                version: self.version_index(&s.version)?,
+            });
+        }
+        let nsyms = syms.len() as u64;
+
+        let nbucket = *BUCKETS
+            .iter()
+            .take_while(|&&b| u64::from(b) <= nsyms)
+            .last()
+            .unwrap();
+
+        let verdef_size: u64 = self
+            .versions
+            .iter()
+            .map(|v| 20 + 8 * (1 + v.parents.len() as u64))
+            .sum::<u64>()
+            + 28;
+        let ndyn = 10
+            + self.needed.len() as u64
+            + self.runpath.iter().count() as u64;
+
+        let shstrtab = b"\0.hash\0.dynsym\0.dynstr\0.SUNW_version\0\
+            .SUNW_versym\0.text\0.dynamic\0.data\0.shstrtab\0";
+        let shname = |name: &str| -> u32 {
+            let needle = format!("\0{}\0", name);
+            shstrtab
+                .windows(needle.len())
+                .position(|w| w == needle.as_bytes())
+                .unwrap() as u32
+                + 1
+        };
+
+        let mut sections = vec![Section {
+            name: "",
+            kind: 0,
+            flags: 0,
+            addr: 0,
+            offset: 0,
+            size: 0,
+            link: 0,
+            info: 0,
+            align: 0,
+            entsize: 0,
+        }];
+        let mut off = ehsize + phnum * phentsize;
+        let place = |sections: &mut Vec<Section>,
+                         off: &mut u64,
+                         base: u64,
+                         mut s: Section| {
+            *off = align(*off, s.align);
+            s.offset = *off;
+            s.addr = base + *off;
+            *off += s.size;
+            sections.push(s);
+        };
+
+        // The text segment is mapped at address zero, and the data segment at
+        // the following page.
+        let text = [
+            (".hash", SHT_HASH, 0, (2 + u64::from(nbucket) + nsyms) * 4,
+                SH_DYNSYM, 0, 4, 4),
+            (".dynsym", SHT_DYNSYM, 0, nsyms * symentsize, SH_DYNSTR, 1,
+                addrsize, symentsize),
+            (".dynstr", SHT_STRTAB, 0, dynstr.buf.len() as u64, 0, 0, 1, 0),
+            (".SUNW_version", SHT_SUNW_VERDEF, 0, verdef_size, SH_DYNSTR,
+                self.versions.len() as u32 + 1, addrsize, 0),
+            (".SUNW_versym", SHT_SUNW_VERSYM, 0, nsyms * 2, SH_DYNSYM, 0, 2,
+                2),
+            (".text", SHT_PROGBITS, SHF_EXECINSTR, text_size, 0, 0, 16, 0),
+        ];
+        for (name, kind, flags, size, link, info, align, entsize) in text {
+            place(&mut sections, &mut off, 0, Section {
+                name,
+                kind,
+                flags: SHF_ALLOC | flags,
+                addr: 0,
+                offset: 0,
+                size,
+                link,
+                info,
+                align,
+                entsize,
+            });
+        }
+        let text_end = off;
+        let data_base = align(text_end, PAGESIZE);
+        let data = [
+            (".dynamic", SHT_DYNAMIC, ndyn * dynentsize, SH_DYNSTR, addrsize,
+                dynentsize),
+            (".data", SHT_PROGBITS, data_size, 0, addrsize, 0),
+        ];
+        for (name, kind, size, link, align, entsize) in data {
+            place(&mut sections, &mut off, data_base, Section {
+                name,
+                kind,
+                flags: SHF_ALLOC | SHF_WRITE,
+                addr: 0,
+                offset: 0,
+                size,
+                link,
+                info: 0,
+                align,
+                entsize,
+            });
+        }
+        let data_start = sections[7].offset;
+        let data_end = off;
+        place(&mut sections, &mut off, 0, Section {
+            name: ".shstrtab",
+            kind: SHT_STRTAB,
+            flags: 0,
+            addr: 0,
+            offset: 0,
+            size: shstrtab.len() as u64,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        });
+        sections[SH_SHSTRTAB as usize].addr = 0;
+        let shoff = align(off, addrsize);
+
+        let mut out = Out {
+            buf: Vec::new(),
+            is64,
+        };
+
+        // ELF header
+        out.buf.extend_from_slice(b"\x7fELF");
+        out.u8(if is64 { ELFCLASS64 } else { ELFCLASS32 });
+        out.u8(ELFDATA2LSB);
+        out.u8(EV_CURRENT);
+        out.u8(ELFOSABI_SOLARIS);
+        out.pad_to(16);
+        out.u16(ET_DYN);
+        out.u16(self.machine.em());
+        out.u32(u32::from(EV_CURRENT));
+        out.word(0);
+        out.word(ehsize);
+        out.word(shoff);
+        out.u32(0);
+        out.u16(ehsize as u16);
+        out.u16(phentsize as u16);
+        out.u16(phnum as u16);
+        out.u16(shentsize as u16);
+        out.u16(SH_COUNT);
+        out.u16(SH_SHSTRTAB);
+
+        // Program headers
+        let dynamic = &sections[7];
+        let phdrs = [
+            (PT_LOAD, PF_R | PF_X, 0, 0, text_end, PAGESIZE),
+            (PT_LOAD, PF_R | PF_W, data_start, data_base + data_start,
+                data_end - data_start, PAGESIZE),
+            (PT_DYNAMIC, PF_R | PF_W, dynamic.offset, dynamic.addr,
+                dynamic.size, addrsize),
+        ];
+        for (kind, flags, offset, vaddr, size, align) in phdrs {
+            out.u32(kind);
+            if is64 {
+                out.u32(flags);
+            }
+            out.word(offset);
+            out.word(vaddr);
+            out.word(vaddr);
+            out.word(size);
+            out.word(size);
+            if !is64 {
+                out.u32(flags);
+            }
+            out.word(align);
+        }
+
+        // .hash
+        out.pad_to(sections[SH_HASH as usize].offset);
+        let mut buckets = vec![0u32; nbucket as usize];
+        let mut chains = vec![0u32; nsyms as usize];
+        for (i, s) in syms.iter().enumerate().skip(1) {
+            let b = (elf_hash(s.name.as_bytes()) % nbucket) as usize;
+            chains[i] = buckets[b];
+            buckets[b] = i as u32;
+        }
+        out.u32(nbucket);
+        out.u32(nsyms as u32);
+        for v in buckets.iter().chain(chains.iter()) {
+            out.u32(*v);
+        }
+
+        // .dynsym
+        out.pad_to(sections[SH_DYNSYM as usize].offset);
+        for s in &syms {
+            let name = if s.name.is_empty() { 0 } else { dynstr.get(s.name) };
+            let value = match s.shndx {
+                SH_TEXT | SH_DATA => s.value + sections[s.shndx as usize].addr,
+                _ => s.value,
+            };
+            let info = if s.name.is_empty() {
+                0
+            } else {
+                (s.bind << 4) | s.kind
+            };
+            out.u32(name);
+            if is64 {
+                out.u8(info);
+                out.u8(0);
+                out.u16(s.shndx);
+                out.u64(value);
+                out.u64(s.size);
+            } else {
+                out.u32(value as u32);
+                out.u32(s.size as u32);
+                out.u8(info);
+                out.u8(0);
+                out.u16(s.shndx);
+            }
+        }
+
+        // .dynstr
+        out.pad_to(sections[SH_DYNSTR as usize].offset);
+        out.buf.extend_from_slice(&dynstr.buf);
+
+        // .SUNW_version: the base version, then each of the others
+        out.pad_to(sections[SH_VERDEF as usize].offset);
+        let base = VersionDef {
+            name: self.soname.clone(),
+            parents: Vec::new(),
+        };
+        let defs: Vec<&VersionDef> =
+            std::iter::once(&base).chain(self.versions.iter()).collect();
+        for (i, v) in defs.iter().enumerate() {
+            let cnt = 1 + v.parents.len() as u32;
+            out.u16(VER_DEF_CURRENT);
+            out.u16(if i == 0 { VER_FLG_BASE } else { 0 });
+            out.u16(i as u16 + 1);
+            out.u16(cnt as u16);
+            out.u32(elf_hash(v.name.as_bytes()));
+            out.u32(20);
+            out.u32(if i + 1 < defs.len() { 20 + 8 * cnt } else { 0 });
+            let names = std::iter::once(&v.name).chain(v.parents.iter());
+            for (j, name) in names.enumerate() {
+                out.u32(dynstr.get(name));
+                out.u32(if (j as u32) + 1 < cnt { 8 } else { 0 });
+            }
+        }
+
+        // .SUNW_versym
+        out.pad_to(sections[SH_VERSYM as usize].offset);
+        for s in &syms {
+            out.u16(s.version);
+        }
+
+        // .text
+        let text = &sections[SH_TEXT as usize];
+        out.pad_to(text.offset);
+        while (out.buf.len() as u64) < text.offset + text.size {
+            out.buf.extend_from_slice(&UD2);
+        }
+
+        // .dynamic
+        out.pad_to(dynamic.offset);
+        let mut dyns: Vec<(u64, u64)> = self
+            .needed
+            .iter()
+            .map(|n| (DT_NEEDED, u64::from(dynstr.get(n))))
+            .collect();
+        if let Some(runpath) = &self.runpath {
+            dyns.push((DT_RUNPATH, u64::from(dynstr.get(runpath))));
+        }
+        dyns.extend_from_slice(&[
+            (DT_SONAME, u64::from(dynstr.get(&self.soname))),
+            (DT_HASH, sections[SH_HASH as usize].addr),
+            (DT_STRTAB, sections[SH_DYNSTR as usize].addr),
+            (DT_STRSZ, dynstr.buf.len() as u64),
+            (DT_SYMTAB, sections[SH_DYNSYM as usize].addr),
+            (DT_SYMENT, symentsize),
+            (DT_VERDEF, sections[SH_VERDEF as usize].addr),
+            (DT_VERDEFNUM, defs.len() as u64),
+            (DT_VERSYM, sections[SH_VERSYM as usize].addr),
+            (DT_NULL, 0),
+        ]);
+        assert_eq!(dyns.len() as u64, ndyn);
+        for (tag, val) in dyns {
+            out.word(tag);
+            out.word(val);
+        }
+
+        // .data (zero-filled), .shstrtab
+        out.pad_to(data_end);
+        out.pad_to(sections[SH_SHSTRTAB as usize].offset);
+        out.buf.extend_from_slice(shstrtab);
+
+        // Section headers
+        out.pad_to(shoff);
+        for s in &sections {
+            out.u32(if s.name.is_empty() { 0 } else { shname(s.name) });
+            out.u32(s.kind);
+            out.word(s.flags);
+            out.word(s.addr);
+            out.word(s.offset);
+            out.word(s.size);
+            out.u32(s.link);
+            out.u32(s.info);
+            out.word(s.align);
+            out.word(s.entsize);
+        }
+
+        Ok(out.buf)
+    }
+}
+
+/// Fixtures for the tests of whatever reads objects.
+#[cfg(test)]
+impl Stub {
+    /// A library with no dependencies, versions or symbols.
+    pub fn library(machine: Machine, soname: &str) -> Stub {
+        Stub {
+            machine,
+            soname: soname.to_string(),
+            needed: Vec::new(),
+            runpath: None,
+            versions: Vec::new(),
+            symbols: Vec::new(),
+        }
+    }
+
+    /// Add a version, inheriting from `parents`.
+    pub fn version(mut self, name: &str, parents: &[&str]) -> Stub {
+        self.versions.push(VersionDef {
+            name: name.to_string(),
+            parents: parents.iter().map(|p| p.to_string()).collect(),
+        });
+        self
+    }
+
+    /// Add a symbol.
+    pub fn symbol(mut self, symbol: StubSymbol) -> Stub {
+        self.symbols.push(symbol);
+        self
+    }
+}
+
+#[cfg(test)]
+impl StubSymbol {
+    /// A global symbol, in `version` (or the base version).
+    pub fn new(
+        name: &str,
+        kind: SymbolKind,
+        size: u64,
+        version: Option<&str>,
+    ) -> StubSymbol {
+        StubSymbol {
+            name: name.to_string(),
+            kind,
+            size,
+            version: version.map(String::from),
+            weak: false,
+        }
+    }
+}
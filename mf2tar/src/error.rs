@@ -0,0 +1,295 @@
+// Copyright 2020 Oxide Computer Company
+
+//! The errors which stop `mf2tar`, and the exit status for each class of
+//! error.  Scripts may rely on these not changing:
+//!
+//! - 0: success;
+//! - 1, `CHECK`: a check failed (e.g., with `--strict`, or an ABI break found
+//!   by `diff`);
+//! - 2, `USAGE`: the arguments or the spec are wrong;
+//! - 3, `REPOSITORY`: the repository, or a package in it, is unusable;
+//! - 4, `MANIFEST`: a manifest or mapfile could not be understood;
+//! - 5, `PAYLOAD`: a file is missing from the repository, or has the wrong
+//!   hash;
+//! - 6, `LOCKED`: the packages differ from those in the lockfile;
+//! - 7, `TAR`: an archive could not be written or read;
+//! - 8, `IO`: any other file could not be read or written.
+//!
+//! When the build keeps going after entries which could not be archived, the
+//! status is that for the first of them.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::pkgmf;
+use crate::report::Reporter;
+use crate::repo;
+
+pub const CHECK: i32 = 1;
+pub const USAGE: i32 = 2;
+pub const REPOSITORY: i32 = 3;
+pub const MANIFEST: i32 = 4;
+pub const PAYLOAD: i32 = 5;
+pub const LOCKED: i32 = 6;
+pub const TAR: i32 = 7;
+pub const IO: i32 = 8;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A check which failed; what was wrong has already been reported.
+    Check(String),
+    Usage(String),
+    Repository(repo::Error),
+    /// A manifest which could not be read or understood, with the package it
+    /// belongs to, if any; a parse error also has the file and line.
+    Manifest {
+        package: Option<String>,
+        error: pkgmf::Error,
+    },
+    /// A mapfile (for a shim) which could not be read or understood.
+    Mapfile(String),
+    /// The payload of a file which could not be had from the repository.
+    Payload {
+        package: Option<String>,
+        path: String,
+        error: Box<repo::Error>,
+    },
+    Locked(String),
+    /// The archive (or the entry at `path` in it) could not be written or
+    /// read.
+    Tar {
+        path: Option<String>,
+        error: io::Error,
+    },
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// A file in the proto area which could not be read, with the manifest
+    /// line which delivers it.
+    Proto {
+        location: pkgmf::Location,
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// The entries which could not be archived, each already reported, when
+    /// keeping going.
+    Incomplete(Vec<Error>),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// The exit status for the class of this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Check(_) => CHECK,
+            Error::Usage(_) => USAGE,
+            Error::Repository(repo::Error::Manifest(_)) => MANIFEST,
+            Error::Repository(_) => REPOSITORY,
+            Error::Manifest { .. } | Error::Mapfile(_) => MANIFEST,
+            Error::Payload { .. } => PAYLOAD,
+            Error::Locked(_) => LOCKED,
+            Error::Tar { .. } => TAR,
+            Error::Io { .. } | Error::Proto { .. } => IO,
+            Error::Incomplete(errors) => {
+                errors.first().map(Error::exit_code).unwrap_or(CHECK)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Check(msg) | Error::Usage(msg) | Error::Mapfile(msg) => {
+                write!(f, "{}", msg)
+            }
+            Error::Repository(e) => write!(f, "repository: {}", e),
+            Error::Manifest { package: Some(package), error } => {
+                write!(f, "manifest of {}: {}", package, error)
+            }
+            Error::Manifest { package: None, error } => {
+                write!(f, "manifest: {}", error)
+            }
+            Error::Payload { package, path, error } => {
+                write!(f, "payload of {}", path)?;
+                if let Some(package) = package {
+                    write!(f, " (in {})", package)?;
+                }
+                write!(f, ": {}", error)
+            }
+            Error::Locked(msg) => write!(f, "locked: {}", msg),
+            Error::Tar { path: Some(path), error } => {
+                write!(f, "tar: {}: {}", path, error)
+            }
+            Error::Tar { path: None, error } => write!(f, "tar: {}", error),
+            Error::Io { path, error } => {
+                write!(f, "{}: {}", path.display(), error)
+            }
+            Error::Proto { location, path, error } => {
+                write!(f, "{}: {}: {}", location, path.display(), error)
+            }
+            Error::Incomplete(errors) => {
+                write!(f, "{} entries could not be archived", errors.len())?;
+                if let Some(first) = errors.first() {
+                    write!(f, "; the first: {}", first)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Repository(e) => Some(e),
+            Error::Payload { error, .. } => Some(error.as_ref()),
+            Error::Manifest { error, .. } => Some(error),
+            Error::Tar { error, .. }
+            | Error::Io { error, .. }
+            | Error::Proto { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<repo::Error> for Error {
+    fn from(e: repo::Error) -> Self {
+        Error::Repository(e)
+    }
+}
+
+impl From<pkgmf::Error> for Error {
+    fn from(error: pkgmf::Error) -> Self {
+        Error::Manifest {
+            package: None,
+            error,
+        }
+    }
+}
+
+/// What to do with an entry which cannot be archived: by default, the first
+/// such error stops the build; when keeping going, each is reported and the
+/// build carries on, only to fail at the end.
+#[derive(Debug, Default)]
+pub struct Failures {
+    keep_going: bool,
+    errors: Vec<Error>,
+}
+
+impl Failures {
+    pub fn new(keep_going: bool) -> Failures {
+        Failures {
+            keep_going,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Pass on the result of archiving an entry, unless keeping going.
+    pub fn check(&mut self, report: &mut Reporter, res: Result<()>)
+        -> Result<()>
+    {
+        match res {
+            Err(e) if self.keep_going => {
+                error!(report, "{}", e);
+                self.errors.push(e);
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    /// Fail if any entry could not be archived.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Incomplete(std::mem::take(&mut self.errors)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classes() {
+        let missing = || io::Error::from(io::ErrorKind::NotFound);
+        let payload = Error::Payload {
+            package: Some("system/library".to_string()),
+            path: "usr/lib/libc.so.1".to_string(),
+            error: Box::new(repo::Error::Hash {
+                file: PathBuf::from("file/1f/1f2e"),
+                expected: "1f2e".to_string(),
+                actual: "3c4d".to_string(),
+            }),
+        };
+        assert_eq!(payload.exit_code(), PAYLOAD);
+        assert_eq!(
+            payload.to_string(),
+            "payload of usr/lib/libc.so.1 (in system/library): file/1f/1f2e: \
+                hash mismatch: 3c4d != expected 1f2e"
+        );
+
+        let repo = Error::from(repo::Error::Io(PathBuf::from("repo/pkg"),
+            missing()));
+        assert_eq!(repo.exit_code(), REPOSITORY);
+        assert!(repo.to_string().starts_with("repository: repo/pkg: "));
+        let manifest = repo::Error::Manifest(pkgmf::Error::Io(missing()));
+        assert_eq!(Error::from(manifest).exit_code(), MANIFEST);
+
+        let proto = Error::Proto {
+            location: pkgmf::Location {
+                file: PathBuf::from("/src/mf"),
+                line: 3,
+            },
+            path: PathBuf::from("/proto/usr/lib/libc.so.1"),
+            error: missing(),
+        };
+        assert_eq!(proto.exit_code(), IO);
+        assert!(proto.to_string()
+            .starts_with("/src/mf:3: /proto/usr/lib/libc.so.1: "));
+
+        let codes: Vec<_> = [
+            Error::Check(String::new()),
+            Error::Usage(String::new()),
+            Error::Mapfile(String::new()),
+            Error::Locked(String::new()),
+            Error::Tar { path: None, error: missing() },
+            Error::Io { path: PathBuf::new(), error: missing() },
+        ]
+        .iter()
+        .map(Error::exit_code)
+        .collect();
+        assert_eq!(codes, vec![CHECK, USAGE, MANIFEST, LOCKED, TAR, IO]);
+    }
+
+    #[test]
+    fn keep_going() {
+        let missing = |path: &str| Err(Error::Io {
+            path: PathBuf::from(path),
+            error: io::Error::from(io::ErrorKind::NotFound),
+        });
+
+        let mut report = Reporter::default().with_sink(|_| {});
+        let mut failures = Failures::new(false);
+        assert!(failures.check(&mut report, Ok(())).is_ok());
+        assert!(failures.check(&mut report, missing("proto/a.h")).is_err());
+        assert!(failures.finish().is_ok());
+
+        let mut failures = Failures::new(true);
+        assert!(failures.check(&mut report, missing("proto/a.h")).is_ok());
+        assert!(failures.check(&mut report, Ok(())).is_ok());
+        assert!(failures.check(&mut report, missing("proto/b.h")).is_ok());
+        let e = failures.finish().unwrap_err();
+        assert_eq!(e.exit_code(), IO);
+        assert!(e.to_string()
+            .starts_with("2 entries could not be archived; the first: \
+                proto/a.h: "));
+        assert!(failures.finish().is_ok());
+    }
+}
@@ -0,0 +1,304 @@
+// Copyright 2020 Oxide Computer Company
+
+//! Exploration of repositories and archives, without making an archive.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use tar::EntryType;
+
+use crate::audit;
+use crate::error::{self, Error};
+use crate::mapfile;
+use crate::pkgmf::{self, Entry, FsAttr};
+use crate::report::Reporter;
+use crate::repo::{self, Repository};
+use crate::shim::Shim;
+use crate::sysroot::Manifest;
+use crate::vfs;
+
+/// Each version of each package in the repository at `path`, as an FMRI.
+pub fn list_packages(path: &Path) -> repo::Result<Vec<String>> {
+    let repo = Repository::new(path)?;
+    let mut out = Vec::new();
+    for pkg in repo.scan()?.values() {
+        out.extend(pkg.versions.iter().map(|v| pkg.fmri(v)));
+    }
+    out.sort();
+    Ok(out)
+}
+
+fn describe_attr(attr: &FsAttr) -> String {
+    let mut out = String::new();
+    for (name, value) in &[
+        ("mode", &attr.mode),
+        ("owner", &attr.owner),
+        ("group", &attr.group),
+    ] {
+        if let Some(value) = value {
+            out.push_str(&format!(" {}={}", name, value));
+        }
+    }
+    out
+}
+
+/// An entry of a manifest, as `show` prints it.
+fn describe(entry: &Entry) -> String {
+    match entry {
+        Entry::Dir(dir) => {
+            format!("dir {}{}", dir.path, describe_attr(&dir.attr))
+        }
+        Entry::File(file) => {
+            let hash = match &file.cname {
+                Some(hash) => format!(" hash={}", hash),
+                None => String::new(),
+            };
+            format!("file {}{}{}", file.path, describe_attr(&file.attr), hash)
+        }
+        Entry::Link(link) => format!("link {} -> {}", link.path, link.target),
+        Entry::Set(set) => format!("set {} = {}", set.name,
+            set.values.join(", ")),
+        Entry::Unknown(text) => text.clone(),
+        other => format!("{:?}", other),
+    }
+}
+
+/// The manifest of the package `name` (or `name@version`, if the repository
+/// has more than one version of it) in the repository at `path`, leaving out
+/// actions for any other value of the given variants.
+pub fn show(
+    path: &Path,
+    name: &str,
+    variants: &BTreeMap<String, String>,
+) -> repo::Result<Vec<String>> {
+    let (name, version) = match name.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (name, None),
+    };
+    let repo = Repository::new(path)?;
+    let packages = repo.scan()?;
+    let pkg = packages
+        .get(name)
+        .ok_or_else(|| {
+            repo::Error::Package(format!("package \"{}\" not found in \
+                repository", name))
+        })?;
+    let version = match version {
+        Some(version) => pkg
+            .versions
+            .iter()
+            .find(|v| v.version == version)
+            .ok_or_else(|| {
+                repo::Error::Package(format!("package \"{}\" has no \
+                    version {}", name, version))
+            })?,
+        None if pkg.versions.len() == 1 => &pkg.versions[0],
+        None => {
+            let versions: Vec<_> =
+                pkg.versions.iter().map(|v| v.version.as_str()).collect();
+            return Err(repo::Error::Package(format!("package \"{}\" has {} \
+                versions; specify one of: {}", name, versions.len(),
+                versions.join(", "))));
+        }
+    };
+
+    let mut out = vec![format!("# {}", pkg.fmri(version))];
+    for entry in version.manifest(variants)? {
+        out.push(describe(&entry?));
+    }
+    Ok(out)
+}
+
+/// Each entry in the archive at `path` (which may be compressed with gzip).
+pub fn contents(path: &Path) -> io::Result<Vec<String>> {
+    let mut archive = audit::tar_archive(File::open(path)?)?;
+    let mut out = Vec::new();
+    for ent in archive.entries()? {
+        let ent = ent?;
+        let path = vfs::normalize(&ent.path()?.to_string_lossy());
+        let target = ent
+            .link_name()?
+            .map(|t| t.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let header = ent.header();
+        out.push(match header.entry_type() {
+            EntryType::Directory => format!(" d {}", path),
+            EntryType::Regular => {
+                format!(" f {} ({} bytes)", path, header.size()?)
+            }
+            EntryType::Symlink => format!(" l {} -> {}", path, target),
+            EntryType::Link => format!(" h {} => {}", path, target),
+            other => format!(" ? {} ({:?})", path, other),
+        });
+    }
+    Ok(out)
+}
+
+/// Each variable referenced by the manifest (and anything it includes),
+/// along with its definition, and the number of variables without a default
+/// which are left undefined.
+pub fn variables(
+    args: &Manifest,
+    report: &mut Reporter,
+) -> error::Result<(Vec<String>, usize)> {
+    let manifest_dir = args.directory()
+        .map_err(|error| Error::Io { path: args.manifest.clone(), error })?;
+    let expansion = pkgmf::Expansion::Defined(args.defines.clone());
+    let mut mogrifier = args.mogrifier(&manifest_dir, expansion);
+
+    for entry in mogrifier.by_ref() {
+        match entry {
+            Err(pkgmf::Error::Parse(e)) => warning!(report, "{}", e),
+            Err(e) => return Err(e.into()),
+            Ok(_) => {}
+        }
+    }
+
+    let variables = mogrifier.variables();
+    let mut out = Vec::new();
+    let mut undefined = 0;
+    for (name, default) in &variables {
+        out.push(match (args.defines.get(name), default) {
+            (Some(value), _) => format!("{}={}", name, value),
+            (None, Some(default)) => {
+                format!("{} (undefined, default \"{}\")", name, default)
+            }
+            (None, None) => {
+                undefined += 1;
+                format!("{} (undefined)", name)
+            }
+        });
+    }
+    for name in args.defines.keys() {
+        if !variables.contains_key(name) {
+            warning!(report, "-d {} is not referenced", name);
+        }
+    }
+    Ok((out, undefined))
+}
+
+/// The versions and symbols which the mapfile at `path` defines, as `shim`
+/// would include them.
+pub fn mapfile(path: &Path, shim: &Shim) -> Result<Vec<String>, String> {
+    let mapfile = shim.mapfile(path)?;
+
+    let mut out = Vec::new();
+    for v in &mapfile.versions {
+        out.push(match &v.name {
+            Some(name) if v.parents.is_empty() => name.clone(),
+            Some(name) => {
+                format!("{} (inherits {})", name, v.parents.join(", "))
+            }
+            None => "(no version)".to_string(),
+        });
+        for sym in &v.symbols {
+            let mut notes = Vec::new();
+            match sym.scope {
+                mapfile::Scope::Global => {}
+                mapfile::Scope::Local => notes.push("local".to_string()),
+                mapfile::Scope::Protected => {
+                    notes.push("protected".to_string())
+                }
+            }
+            match sym.kind {
+                Some(mapfile::SymbolType::Data) => notes.push("data".into()),
+                Some(mapfile::SymbolType::Common) => {
+                    notes.push("common".into())
+                }
+                _ => {}
+            }
+            if let Some(size) = sym.size {
+                notes.push(format!("{} bytes",
+                    size.bytes(shim.machine.addrsize())));
+            }
+            if notes.is_empty() {
+                out.push(format!("\t{}", sym.name));
+            } else {
+                out.push(format!("\t{} ({})", sym.name, notes.join(", ")));
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scratch::Scratch;
+    use std::fs;
+
+    #[test]
+    fn repository() {
+        let dir = Scratch::new("inspect");
+        fs::create_dir_all(dir.join("file")).unwrap();
+        let pkg = dir.join("pkg").join("system%2Fheader");
+        fs::create_dir_all(&pkg).unwrap();
+        for (version, arch) in &[("1%3A20181213T000000Z", "i386"),
+            ("2%3A20191213T000000Z", "sparc")]
+        {
+            fs::write(pkg.join(version), format!("\
+                set name=pkg.summary value=Headers\n\
+                dir path=usr/include mode=0755 owner=root\n\
+                file 1f2e chash=abcd path=usr/include/{0}.h \
+                variant.arch={0}\n\
+                file 3c4d chash=ef01 path=usr/include/sys/{0}.h\n\
+                link path=usr/include/a.h target={0}.h\n", arch)).unwrap();
+        }
+
+        assert_eq!(
+            list_packages(&dir).unwrap(),
+            vec![
+                "pkg:/system/header@1:20181213T000000Z",
+                "pkg:/system/header@2:20191213T000000Z",
+            ]
+        );
+
+        let mut variants = BTreeMap::new();
+        assert!(show(&dir, "system/header", &variants)
+            .unwrap_err()
+            .to_string()
+            .contains("has 2 versions"));
+        variants.insert("arch".to_string(), "i386".to_string());
+        let name = "system/header@1:20181213T000000Z";
+        assert_eq!(
+            show(&dir, name, &variants).unwrap(),
+            vec![
+                "# pkg:/system/header@1:20181213T000000Z",
+                "set pkg.summary = Headers",
+                "dir usr/include mode=0755 owner=root",
+                "file usr/include/i386.h hash=1f2e",
+                "file usr/include/sys/i386.h hash=3c4d",
+                "link usr/include/a.h -> i386.h",
+            ]
+        );
+        assert!(show(&dir, "system/library", &variants).is_err());
+    }
+
+    #[test]
+    fn archive() {
+        let dir = Scratch::new("contents");
+        let path = dir.join("t.tar");
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(EntryType::Directory);
+        header.set_size(0);
+        builder.append_data(&mut header, "usr/", io::empty()).unwrap();
+        let mut header = tar::Header::new_ustar();
+        header.set_size(3);
+        builder.append_data(&mut header, "usr/a", &b"abc"[..]).unwrap();
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        header.set_link_name("a").unwrap();
+        builder.append_data(&mut header, "usr/b", io::empty()).unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        assert_eq!(
+            contents(&path).unwrap(),
+            vec![" d usr", " f usr/a (3 bytes)", " l usr/b -> a"]
+        );
+    }
+}
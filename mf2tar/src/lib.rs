@@ -0,0 +1,42 @@
+// Copyright 2020 Oxide Computer Company
+
+//! Making sysroot archives for illumos from packages.
+//!
+//! - `pkgmf` reads IPS package manifests, expanding macros, following
+//!   includes and applying transforms;
+//! - `repo` reads the packages, their manifests and their files from a
+//!   file-based IPS repository;
+//! - `sysroot` assembles the archive, through a `SysrootBuilder`, which
+//!   may be made from a spec (`spec`);
+//! - `audit`, `diff` and `inspect` look into repositories and archives,
+//!   without making one.
+//!
+//! The `mf2tar` program is a command line on top of these.
+
+#[macro_use]
+pub mod report;
+
+pub mod audit;
+pub mod diff;
+pub mod elf;
+pub mod error;
+pub mod inspect;
+mod liblinks;
+mod lock;
+pub mod mapfile;
+mod needed;
+pub mod pkgmf;
+mod plan;
+pub mod release;
+pub mod repo;
+pub mod rules;
+#[cfg(test)]
+mod scratch;
+pub mod shim;
+pub mod spec;
+mod strip;
+pub mod sysroot;
+pub mod vfs;
+
+pub use error::{Error, Result};
+pub use sysroot::{Manifest, SysrootBuilder};
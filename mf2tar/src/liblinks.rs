@@ -0,0 +1,123 @@
+// Copyright 2020 Oxide Computer Company
+
+//! The compilation links (e.g., `libfoo.so -> libfoo.so.1`) through which the
+//! link-editor finds a library given `-lfoo`.  Packages deliver most of these,
+//! but not all.
+
+use std::collections::BTreeMap;
+
+use crate::vfs::{self, Node, Tree};
+
+/// Which compilation links to add, by the glob of the path of each link.
+#[derive(Default)]
+pub struct Rules {
+    /// If not empty, only links matching one of these are added
+    pub allow: Vec<String>,
+    /// Links matching any of these are not added
+    pub deny: Vec<String>,
+}
+
+impl Rules {
+    fn permits(&self, path: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|g| vfs::glob(g, path)))
+            && !self.deny.iter().any(|g| vfs::glob(g, path))
+    }
+}
+
+/// Split a versioned library name, such as "libfoo.so.1.2", into the name of
+/// its compilation link ("libfoo.so") and its version ([1, 2]).
+fn versioned(name: &str) -> Option<(&str, Vec<u64>)> {
+    if !name.starts_with("lib") {
+        return None;
+    }
+    let i = name.find(".so.")?;
+    let version = name[i + 4..]
+        .split('.')
+        .map(|n| n.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    Some((&name[..i + 3], version))
+}
+
+/// The compilation links missing from the tree, as the path of each link and
+/// its (relative) target.  Each leads to the versioned library in the same
+/// directory with the highest version.
+pub fn missing(tree: &Tree, rules: &Rules) -> Vec<(String, String)> {
+    let mut best: BTreeMap<String, (Vec<u64>, String)> = BTreeMap::new();
+    for (path, _) in tree.nodes() {
+        let (dir, name) = match path.rfind('/') {
+            Some(i) => (&path[..i + 1], &path[i + 1..]),
+            None => ("", path.as_str()),
+        };
+        let (link, version) = match versioned(name) {
+            Some(v) => v,
+            None => continue,
+        };
+        if !matches!(tree.resolve(path), Some((_, Node::File))) {
+            continue;
+        }
+        let link = format!("{}{}", dir, link);
+        match best.get(&link) {
+            Some((v, _)) if *v >= version => {}
+            _ => {
+                best.insert(link, (version, name.to_string()));
+            }
+        }
+    }
+
+    best.into_iter()
+        .filter(|(link, _)| tree.get(link).is_none() && rules.permits(link))
+        .map(|(link, (_, target))| (link, target))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn links() {
+        let mut tree = Tree::new();
+        for path in &[
+            "usr/lib/libm.so.1",
+            "usr/lib/libm.so.2",
+            "usr/lib/libssp.so.0.0.0",
+            "usr/lib/amd64/libz.so.1",
+            "usr/lib/amd64/libc.so.1",
+            "usr/lib/libfoo.so.1.bak",
+            "usr/lib/notlib.so.1",
+            "lib/libc.so.1",
+        ] {
+            tree.insert(path, Node::File);
+        }
+        let link = |t: &str| Node::Link(t.to_string());
+        tree.insert("usr/lib/libssp.so.0", link("libssp.so.0.0.0"));
+        tree.insert("usr/lib/libgone.so.1", link("libgone.so.1.0"));
+        tree.insert("usr/lib/amd64/libc.so", link("../../../lib/libc.so.1"));
+
+        let missing = |rules| {
+            missing(&tree, &rules)
+                .into_iter()
+                .map(|(l, t)| format!("{} -> {}", l, t))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            missing(Rules::default()),
+            vec![
+                "lib/libc.so -> libc.so.1",
+                "usr/lib/amd64/libz.so -> libz.so.1",
+                "usr/lib/libm.so -> libm.so.2",
+                "usr/lib/libssp.so -> libssp.so.0.0.0",
+            ]
+        );
+        assert_eq!(
+            missing(Rules {
+                allow: vec!["usr/lib/**".to_string()],
+                deny: vec!["**/libm.so".to_string()],
+            }),
+            vec![
+                "usr/lib/amd64/libz.so -> libz.so.1",
+                "usr/lib/libssp.so -> libssp.so.0.0.0",
+            ]
+        );
+    }
+}
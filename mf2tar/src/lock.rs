@@ -0,0 +1,177 @@
+// Copyright 2020 Oxide Computer Company
+
+//! A lockfile, recording the exact version of each package and the hashes of
+//! each file delivered into an archive, so that a later build from the
+//! repository (with `--locked`) can be shown to use exactly the same.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// The payload of a file action: the hash of the compressed file in the
+/// repository, and that of its contents.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Payload {
+    pub chash: String,
+    pub hash: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Package {
+    pub name: String,
+    pub fmri: String,
+    /// The payload of each file archived, by path
+    #[serde(default)]
+    pub files: BTreeMap<String, Payload>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lock {
+    #[serde(default, rename = "package")]
+    pub packages: Vec<Package>,
+}
+
+impl Lock {
+    pub fn load(path: &Path) -> io::Result<Lock> {
+        let text = fs::read_to_string(path)?;
+        toml::from_str(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let text = toml::to_string(self).map_err(io::Error::other)?;
+        let text = format!("# Written by mf2tar; check builds against it with \
+            --locked.\n\n{}", text);
+        fs::write(path, text)
+    }
+
+    fn package(&self, name: &str) -> Result<&Package, String> {
+        self.packages
+            .iter()
+            .find(|p| p.name == name)
+            .ok_or_else(|| format!("package {} is not in the lockfile", name))
+    }
+
+    /// Check that the repository offers the version of package `name` that
+    /// was locked.
+    pub fn check_package(&self, name: &str, fmri: &str) -> Result<(), String> {
+        let locked = self.package(name)?;
+        if locked.fmri != fmri {
+            return Err(format!("package {} is {}, but {} is locked", name,
+                fmri, locked.fmri));
+        }
+        Ok(())
+    }
+
+    /// Check that the file at `path` in package `name` has the payload that
+    /// was locked.
+    pub fn check_file(
+        &self,
+        name: &str,
+        path: &str,
+        payload: &Payload,
+    ) -> Result<(), String> {
+        match self.package(name)?.files.get(path) {
+            None => Err(format!("{} (in {}) is not in the lockfile", path,
+                name)),
+            Some(locked) if locked != payload => {
+                Err(format!("{} (in {}) has hash {}, but {} is locked", path,
+                    name, payload.hash, locked.hash))
+            }
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Check that the packages and files in `built` are all those that were
+    /// locked, once the files of each have been checked.
+    pub fn check_complete(&self, built: &Lock) -> Result<(), String> {
+        for locked in &self.packages {
+            let package = built
+                .packages
+                .iter()
+                .find(|p| p.name == locked.name)
+                .ok_or_else(|| format!("package {} is locked, but was not \
+                    built", locked.name))?;
+            if let Some(path) =
+                locked.files.keys().find(|p| !package.files.contains_key(*p))
+            {
+                return Err(format!("{} (in {}) is locked, but was not \
+                    archived", path, locked.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn payload(hash: &str) -> Payload {
+        Payload {
+            chash: format!("c{}", hash),
+            hash: hash.to_string(),
+        }
+    }
+
+    #[test]
+    fn locking() {
+        let mut files = BTreeMap::new();
+        files.insert("usr/include/a.h".to_string(), payload("1"));
+        files.insert("usr/include/b.h".to_string(), payload("2"));
+        let lock = Lock {
+            packages: vec![Package {
+                name: "system/header".to_string(),
+                fmri: "pkg:/system/header@0.5.11,5.11-1".to_string(),
+                files,
+            }],
+        };
+        let text = toml::to_string(&lock).unwrap();
+        assert_eq!(toml::from_str::<Lock>(&text).unwrap(), lock);
+
+        let name = "system/header";
+        assert!(lock.check_package(name, "pkg:/system/header@0.5.11,5.11-1")
+            .is_ok());
+        assert_eq!(
+            lock.check_package(name, "pkg:/system/header@0.5.11,5.11-2")
+                .unwrap_err(),
+            "package system/header is pkg:/system/header@0.5.11,5.11-2, \
+                but pkg:/system/header@0.5.11,5.11-1 is locked"
+        );
+        assert!(lock.check_package("system/library", "x").is_err());
+
+        assert!(lock.check_file(name, "usr/include/a.h", &payload("1"))
+            .is_ok());
+        assert_eq!(
+            lock.check_file(name, "usr/include/a.h", &payload("3"))
+                .unwrap_err(),
+            "usr/include/a.h (in system/header) has hash 3, but 1 is locked"
+        );
+        assert!(lock.check_file(name, "usr/include/c.h", &payload("1"))
+            .is_err());
+
+        let mut built = Lock {
+            packages: vec![Package {
+                name: name.to_string(),
+                fmri: String::new(),
+                files: BTreeMap::new(),
+            }],
+        };
+        built.packages[0]
+            .files
+            .insert("usr/include/a.h".to_string(), payload("1"));
+        assert_eq!(
+            lock.check_complete(&built).unwrap_err(),
+            "usr/include/b.h (in system/header) is locked, but was not \
+                archived"
+        );
+        assert!(lock.check_complete(&lock).is_ok());
+        assert!(lock.check_complete(&Lock::default()).is_err());
+    }
+}
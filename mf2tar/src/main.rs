@@ -1,45 +1,141 @@
 // Copyright 2020 Oxide Computer Company
 
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::collections::{BTreeMap, HashMap};
 use std::iter::Iterator;
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
 use std::process::exit;
 
 use getopts::Options;
-use tar::{Builder, EntryType, Header};
 
-mod pkgmf;
-use pkgmf::Entry;
+use mf2tar::error::Error;
+use mf2tar::report::{Event, Reporter};
+use mf2tar::rules::{Action, Rule};
+use mf2tar::shim::Shim;
+use mf2tar::sysroot::{Manifest, SysrootBuilder};
+use mf2tar::{audit, diff, elf, error, inspect, release, report, spec};
+
+enum Mode {
+    Archive(Box<SysrootBuilder>),
+    DumpVars(Manifest),
+    ShowMapfile(PathBuf, Shim),
+    Audit(Vec<PathBuf>, Option<PathBuf>),
+    Diff(PathBuf, PathBuf),
+    ListPackages(PathBuf),
+    Show(PathBuf, String, BTreeMap<String, String>),
+    Contents(PathBuf),
+}
+
+fn manifest_args(res: &getopts::Matches, usage: &dyn Fn()) -> Manifest {
+    let manifest = if let Some(m) = res.opt_str("manifest") {
+        PathBuf::from(m)
+    } else {
+        usage();
+        println!("ERROR: -p and dump-vars require -m");
+        exit(error::USAGE);
+    };
 
-mod repo;
-use repo::Repository;
+    let mut defines = HashMap::new();
+    for dv in res.opt_strs("define").iter() {
+        let t: Vec<_> = dv.splitn(2, '=').collect();
+        if t.len() != 2 {
+            usage();
+            println!("ERROR: -d requires NAME=VALUE arguments");
+            exit(error::USAGE);
+        }
+        defines.insert(t[0].to_string(), t[1].to_string());
+    }
 
-enum Extra {
-    Link(Entry),
-    File(Entry, PathBuf),
+    let transforms = res.opt_strs("transform").iter()
+        .map(PathBuf::from)
+        .collect();
+    let include_dirs = res.opt_strs("include-dir").iter()
+        .map(PathBuf::from)
+        .collect();
+
+    Manifest {
+        manifest,
+        defines,
+        transforms,
+        include_dirs,
+    }
 }
 
-enum Source {
-    ManifestProto(PathBuf, PathBuf, HashMap<String, String>),
-    RepositoryPackages(PathBuf, Vec<String>),
+/// Parse a `--shim` argument: `PATH=MAPFILE` followed by comma-separated
+/// `soname=`, `mach=`, `define=` and `data=` options.
+fn parse_shim(arg: &str) -> Result<(String, PathBuf, Shim), String> {
+    let mut opts = arg.split(',');
+    let t: Vec<_> = opts.next().unwrap().splitn(2, '=').collect();
+    if t.len() != 2 {
+        return Err("--shim requires PATH=MAPFILE arguments".to_string());
+    }
+    let shim = shim_options(t[0], opts)?;
+
+    Ok((t[0].to_string(), PathBuf::from(t[1]), shim))
 }
 
-struct Params {
-    source: Source,
-    tar: PathBuf,
-    append: bool,
-    excludes: Vec<String>,
-    extra: Vec<Extra>,
+fn shim_options<'a>(
+    path: &str,
+    opts: impl Iterator<Item = &'a str>,
+) -> Result<Shim, String> {
+    let mut soname = None;
+    let mut machine = None;
+    let mut defines = Vec::new();
+    let mut data = Vec::new();
+    for opt in opts {
+        match opt.split_once('=') {
+            Some(("soname", v)) => soname = Some(v.to_string()),
+            Some(("mach", v)) => {
+                machine = Some(elf::Machine::from_name(v).ok_or_else(|| {
+                    format!("--shim: unknown machine \"{}\"", v)
+                })?);
+            }
+            Some(("define", v)) => defines.push(v.to_string()),
+            Some(("data", v)) => data.push(v.to_string()),
+            _ => return Err(format!("--shim: unknown option \"{}\"", opt)),
+        }
+    }
+
+    Ok(Shim::for_path(path, soname, machine, defines, data))
 }
 
-fn parse_args() -> Params {
+fn parse_audit_args(args: &[String]) -> Mode {
     let mut opts = Options::new();
+    opts.optopt("o", "output", "write the report to a file, rather than \
+        stdout", "REPORT_FILE");
+    opts.optflag("", "help", "print usage information");
 
-    opts.optopt("r", "repository", "IPS repository directory (repo.redist)",
-        "REPOSITORY_DIR");
+    let usage = || {
+        println!("{}", opts.usage("Usage: mf2tar audit [-o REPORT_FILE] \
+            TARFILE|DIRECTORY..."));
+    };
+
+    let res = match opts.parse(args) {
+        Ok(r) => r,
+        Err(e) => {
+            usage();
+            println!("ERROR: {}", e);
+            exit(error::USAGE);
+        }
+    };
+    if res.opt_present("help") {
+        usage();
+        exit(0);
+    }
+    if res.free.is_empty() {
+        usage();
+        println!("ERROR: must specify an archive or directory to audit");
+        exit(error::USAGE);
+    }
+
+    Mode::Audit(res.free.iter().map(PathBuf::from).collect(),
+        res.opt_str("output").map(PathBuf::from))
+}
+
+fn parse_build_args(args: &[String]) -> Mode {
+    let mut opts = Options::new();
+
+    opts.optopt("r", "repository", "IPS repository directory (repo.redist), \
+        or that instead of the one in the spec", "REPOSITORY_DIR");
     opts.optmulti("P", "package", "IPS package name (e.g., \"system/header\")",
         "PACKAGE_NAME");
 
@@ -48,566 +144,669 @@ fn parse_args() -> Params {
     opts.optopt("m", "manifest", "IPS manifest file", "MANIFEST_FILE");
     opts.optmulti("d", "define", "variable replacement \"macros\"",
         "NAME=VALUE");
+    opts.optmulti("T", "transform", "pkgmogrify transform file, applied \
+        ahead of the manifest", "TRANSFORM_FILE");
+    opts.optmulti("I", "include-dir", "directory to search, in order, for \
+        <include> and transform files (default: the manifest directory)",
+        "INCLUDE_DIR");
 
     opts.optflag("a", "append", "append to tar file (instead of \
         overwriting)");
-    opts.optmulti("E", "exclude-path", "exclude manifest object path",
-        "EXCLUDE_PATH");
+    opts.optmulti("E", "exclude", "exclude the manifest object paths which \
+        match: a path (and everything beneath it), a glob, or re:REGEX",
+        "PATTERN");
+    opts.optmulti("", "include", "keep the manifest object paths which \
+        match, even if excluded by an earlier -E; the last -E or --include \
+        which matches a path decides", "PATTERN");
+    opts.optmulti("", "variant", "leave out manifest actions tagged for any \
+        other value of variant.NAME", "NAME=VALUE");
+    opts.optflag("z", "gzip", "also write a copy of the tar file compressed \
+        with gzip, as TARFILE.gz");
+    opts.optopt("", "lockfile", "record the package versions and file hashes \
+        archived (with -r) in the lockfile, or use it instead of that in the \
+        spec", "LOCKFILE");
+    opts.optflag("", "locked", "refuse to archive any package version or \
+        file other than those recorded in the lockfile");
+    opts.optopt("", "release", "name the tar file (in the same directory) \
+        as this version of a release, from the machine, date and commit in \
+        the package metadata", "VERSION");
+    opts.optopt("", "release-commit", "the commit of the release, if not \
+        that in the package metadata", "COMMIT");
 
     opts.optmulti("F", "file", "add extra file in archive", "PATH=LOCALFILE");
     opts.optmulti("L", "link", "add extra symlink in archive",
         "PATH=LINKTARGET");
+    opts.optmulti("", "shim", "add shim library in archive, generated from \
+        a mapfile (machine from an \"amd64\" path component, SONAME from \
+        the file name, unless given); each data=SYMBOL is data the size of \
+        an address",
+        "PATH=MAPFILE[,soname=NAME][,mach=i386|amd64][,define=NAME...]\
+        [,data=SYMBOL...]");
+
+    opts.optflag("", "strict", "fail on any unknown or malformed manifest \
+        action (instead of warning), undefined -d variable, link leading out \
+        of the sysroot or to nothing in the archive, or library dependency \
+        not found in the archive");
+    opts.optflag("", "stub-libraries", "replace each shared object with a \
+        stub holding only its SONAME, dependencies, versions and dynamic \
+        symbols, enough to link against");
+    opts.optflag("", "dev-links", "add each missing compilation link \
+        (e.g., libfoo.so -> libfoo.so.1) for the libraries in the archive");
+    opts.optmulti("", "dev-links-allow", "only add compilation links whose \
+        paths match the glob (implies --dev-links)", "GLOB");
+    opts.optmulti("", "dev-links-deny", "do not add compilation links whose \
+        paths match the glob (implies --dev-links)", "GLOB");
+    opts.optmulti("", "strip-sections", "remove the named sections (which \
+        are not loaded) from executables and shared objects whose path \
+        matches the glob; names may be globs", "GLOB=SECTION[,SECTION...]");
+    opts.optopt("o", "output", "tar file to write, instead of that in the \
+        spec", "TARFILE");
+    opts.optflagopt("", "dry-run", "list each entry which would be \
+        archived, with its source, and each path excluded, without writing \
+        the tar file", "text|json");
+    opts.optopt("", "log-format", "report each entry archived, path \
+        excluded, warning and error as a line of JSON on standard output, \
+        or (by default) only warnings and errors, and a summary, as text",
+        "text|json");
+    opts.optflag("v", "verbose", "list each entry as it is archived (with \
+        --log-format text)");
+    opts.optflag("k", "keep-going", "carry on past each entry which cannot \
+        be archived (e.g., a file missing from the proto area), reporting \
+        it, and fail at the end; by default, the first such error stops the \
+        build");
 
     opts.optflag("", "help", "print usage information");
 
     let usage = || {
-        let mut out = String::new();
-        out.push_str("Usage: mf2tar -r REPOSITORY_DIR -P PACKAGE_NAME... \
-            TARFILE\n");
-        out.push_str("       mf2tar -m MANIFEST_FILE -p PROTO_DIR \
-            TARFILE");
-        println!("{}", opts.usage(&out));
+        println!("{}", opts.usage("Usage: mf2tar build [OPTIONS] SPEC\n       \
+            mf2tar build [OPTIONS] -r REPOSITORY_DIR -P PACKAGE_NAME... \
+            TARFILE\n       \
+            mf2tar build [OPTIONS] -m MANIFEST_FILE -p PROTO_DIR TARFILE\n\n\
+            Make a sysroot archive, as a spec file (in TOML) declares, or \
+            from the packages\nin a repository or a manifest and proto area \
+            given by options.  With a spec,\n-r, -o and --lockfile override \
+            it, and only they, --locked, --release,\n--release-commit, \
+            --dry-run, --log-format, -v and -k may be given."));
     };
 
-    let args: Vec<String> = std::env::args().skip(1).collect();
     let res = match opts.parse(args) {
         Ok(r) => r,
         Err(e) => {
             usage();
             println!("ERROR: {}", e);
-            exit(1);
+            exit(error::USAGE);
         }
     };
+    if res.opt_present("help") {
+        usage();
+        exit(0);
+    }
 
+    let mut builder = if ["P", "p", "m"].iter().any(|o| res.opt_present(o)) {
+        if res.opt_present("output") {
+            usage();
+            println!("ERROR: -o is only used with a spec");
+            exit(error::USAGE);
+        }
+        option_params(&res, &usage)
+    } else {
+        spec_args(&res, &usage)
+    };
+    if res.opt_present("dry-run") {
+        let format = res.opt_str("dry-run");
+        let format = format.as_deref().map(report::Format::from_name);
+        builder = builder.dry_run(match format {
+            None => report::Format::Text,
+            Some(Some(format)) => format,
+            Some(None) => {
+                usage();
+                println!("ERROR: --dry-run takes \"text\" or \"json\"");
+                exit(error::USAGE);
+            }
+        });
+    }
+    if let Some(format) = res.opt_str("log-format") {
+        builder = builder.log_format(match report::Format::from_name(&format)
+        {
+            Some(format) => format,
+            None => {
+                usage();
+                println!("ERROR: --log-format takes \"text\" or \"json\"");
+                exit(error::USAGE);
+            }
+        });
+    }
+    let builder = builder
+        .verbose(res.opt_present("verbose"))
+        .keep_going(res.opt_present("keep-going"));
+    if let Err(e) = builder.check() {
+        usage();
+        println!("ERROR: {}", e);
+        exit(error::USAGE);
+    }
+    Mode::Archive(Box::new(builder))
+}
+
+/// Options which are given by a spec, rather than on the command line.
+const SPEC_OPTIONS: &[&str] = &[
+    "a", "E", "include", "variant", "z", "F", "L", "shim", "strict",
+    "stub-libraries", "dev-links", "dev-links-allow", "dev-links-deny",
+    "strip-sections", "d", "T", "I",
+];
+
+/// The parameters for making the archive declared by the spec given as the
+/// argument, with any overrides.
+fn spec_args(res: &getopts::Matches, usage: &dyn Fn()) -> SysrootBuilder {
+    if let Some(opt) = SPEC_OPTIONS.iter().find(|o| res.opt_present(o)) {
+        let dashes = if opt.len() == 1 { "-" } else { "--" };
+        usage();
+        println!("ERROR: {}{} cannot be used with a spec", dashes, opt);
+        exit(error::USAGE);
+    }
+    if res.free.len() != 1 {
+        usage();
+        println!("ERROR: must specify a single spec file");
+        exit(error::USAGE);
+    }
+
+    let mut spec = match spec::Spec::load(Path::new(&res.free[0])) {
+        Ok(spec) => spec,
+        Err(e) => {
+            println!("ERROR: {}", e);
+            exit(error::USAGE);
+        }
+    };
+    if let Some(repo) = res.opt_str("repository") {
+        spec.repository.path = PathBuf::from(repo);
+    }
+    if let Some(output) = res.opt_str("output") {
+        spec.output = PathBuf::from(output);
+    }
+    if let Some(lockfile) = res.opt_str("lockfile") {
+        spec.lockfile = Some(PathBuf::from(lockfile));
+    }
+
+    let mut builder = SysrootBuilder::from_spec(spec)
+        .locked(res.opt_present("locked"));
+    if let Some(release) = release_args(res) {
+        builder = builder.release(release);
+    }
+    builder
+}
+
+/// The parameters for making an archive from the packages or the manifest and
+/// proto area given by options.
+fn option_params(
+    res: &getopts::Matches,
+    usage: &dyn Fn(),
+) -> SysrootBuilder {
     let have = |n: &str| -> bool {
         res.opt_present(n)
     };
 
-    if have("help") {
+    if res.free.len() != 1 {
         usage();
-        exit(0);
+        println!("ERROR: must specify a single tar file for output");
+        exit(error::USAGE);
     }
+    let tar = PathBuf::from(&res.free[0]);
 
-    let source = if let Some(proto) = res.opt_str("proto") {
+    let mut builder = if let Some(proto) = res.opt_str("proto") {
         if have("r") || have("P") {
             usage();
-            println!("ERROR: -p, -m, & -d are exclusive with -r & -P");
-            exit(1);
+            println!("ERROR: -p, -m, -d, -T & -I are exclusive with -r & -P");
+            exit(error::USAGE);
         }
 
-        let proto = PathBuf::from(proto);
-        let manifest = if let Some(m) = res.opt_str("manifest") {
-            PathBuf::from(m)
-        } else {
-            usage();
-            println!("ERROR: -p and -m must be specified together");
-            exit(1);
-        };
-
-        let mut defines = HashMap::new();
-        for dv in res.opt_strs("define").iter() {
-            let t: Vec<_> = dv.splitn(2, '=').collect();
-            if t.len() != 2 {
-                usage();
-                println!("ERROR: -d requires NAME=VALUE arguments");
-                exit(1);
-            }
-            defines.insert(t[0].to_string(), t[1].to_string());
-        }
-
-        Source::ManifestProto(manifest, proto, defines)
+        SysrootBuilder::from_manifest(manifest_args(res, usage), proto, tar)
 
     } else if let Some(repo) = res.opt_str("repository") {
-        if have("p") || have("m") || have("d") {
+        if have("p") || have("m") || have("d") || have("T") || have("I")
+        {
             usage();
-            println!("ERROR: -p, -m, & -d are exclusive with -r & -P");
-            exit(1);
+            println!("ERROR: -p, -m, -d, -T & -I are exclusive with -r & -P");
+            exit(error::USAGE);
         }
 
-        let repository = PathBuf::from(repo);
-        let packages = res.opt_strs("package");
-
-        Source::RepositoryPackages(repository, packages)
+        SysrootBuilder::from_repository(repo, res.opt_strs("package"), tar)
 
     } else {
         usage();
         println!("ERROR: must specify either -r & -P, or -p & -m");
-        exit(1);
+        exit(error::USAGE);
     };
 
-    let mut extra = Vec::new();
     for f in res.opt_strs("file") {
         let t: Vec<_> = f.splitn(2, '=').collect();
         if t.len() != 2 {
             usage();
             println!("ERROR: -F requires NAME=VALUE arguments");
-            exit(1);
+            exit(error::USAGE);
         }
-        extra.push(Extra::File(Entry::File(pkgmf::File {
-            path: t[0].to_string(),
-            attr: pkgmf::FsAttr::default(),
-            chash: None,
-            cname: None,
-        }), PathBuf::from(t[1])));
+        builder = builder.file(t[0], t[1]);
     }
     for l in res.opt_strs("link") {
         let t: Vec<_> = l.splitn(2, '=').collect();
         if t.len() != 2 {
             usage();
             println!("ERROR: -L requires NAME=VALUE arguments");
-            exit(1);
+            exit(error::USAGE);
         }
-        extra.push(Extra::Link(Entry::Link(pkgmf::Link {
-            path: t[0].to_string(),
-            attr: pkgmf::FsAttr::default(),
-            target: t[1].to_string(),
-        })));
+        builder = builder.link(t[0], t[1]);
+    }
+    for a in res.opt_strs("shim") {
+        let (path, mapfile, shim) = match parse_shim(&a) {
+            Ok(v) => v,
+            Err(e) => {
+                usage();
+                println!("ERROR: {}", e);
+                exit(error::USAGE);
+            }
+        };
+        builder = builder.shim(&path, mapfile, shim);
     }
 
-    if res.free.len() != 1 {
-        usage();
-        println!("ERROR: must specify a single tar file for output");
-        exit(1);
+    if have("dev-links") || have("dev-links-allow") || have("dev-links-deny")
+    {
+        builder = builder.dev_links(res.opt_strs("dev-links-allow"),
+            res.opt_strs("dev-links-deny"));
     }
-    let tar = PathBuf::from(&res.free[0]);
 
-    let mut excludes = res.opt_strs("exclude-path");
-    excludes.sort();
+    for arg in res.opt_strs("strip-sections") {
+        let t: Vec<_> = arg.splitn(2, '=').collect();
+        if t.len() != 2 || t[1].is_empty() {
+            usage();
+            println!("ERROR: --strip-sections requires GLOB=SECTION \
+                arguments");
+            exit(error::USAGE);
+        }
+        let names = t[1].split(',').map(String::from).collect();
+        builder = builder.strip_sections(t[0], names);
+    }
 
-    Params {
-        source,
-        tar,
-        append: res.opt_present("append"),
-        excludes,
-        extra,
+    let mut given: Vec<_> = res
+        .opt_strs_pos("exclude")
+        .into_iter()
+        .map(|(pos, p)| (pos, Action::Exclude, p))
+        .chain(res.opt_strs_pos("include").into_iter()
+            .map(|(pos, p)| (pos, Action::Include, p)))
+        .collect();
+    given.sort_by_key(|(pos, _, _)| *pos);
+    for (_, action, pattern) in given {
+        match Rule::new(action, &pattern) {
+            Ok(rule) => builder = builder.rule(rule),
+            Err(e) => {
+                usage();
+                println!("ERROR: {}", e);
+                exit(error::USAGE);
+            }
+        }
     }
-}
 
-fn prepare_manifest(manifest: &Path) -> io::Result<(PathBuf, File)> {
-    let parent = manifest.parent().ok_or_else(|| io::Error::new(
-        io::ErrorKind::InvalidInput,
-        "invalid manifest directory",
-    ))?;
-    let manifest_file = File::open(&manifest)?;
+    for v in res.opt_strs("variant") {
+        match v.split_once('=') {
+            Some((name, value)) => builder = builder.variant(name, value),
+            None => {
+                usage();
+                println!("ERROR: --variant requires NAME=VALUE arguments");
+                exit(error::USAGE);
+            }
+        }
+    }
 
-    Ok((parent.to_path_buf(), manifest_file))
+    if let Some(lockfile) = res.opt_str("lockfile") {
+        builder = builder.lockfile(lockfile);
+    }
+    if let Some(release) = release_args(res) {
+        builder = builder.release(release);
+    }
+    builder
+        .append(have("append"))
+        .gzip(have("gzip"))
+        .strict(have("strict"))
+        .stub_libraries(have("stub-libraries"))
+        .locked(have("locked"))
 }
 
-fn prepare_proto(proto_dir: &Path) -> io::Result<PathBuf> {
-    let cpath = proto_dir.canonicalize()?;
+fn parse_diff_args(args: &[String]) -> Mode {
+    let mut opts = Options::new();
+    opts.optflag("", "help", "print usage information");
 
-    if !cpath.is_dir() {
-        Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            format!("not a directory: {}", proto_dir.to_str().unwrap_or("")),
-        ))
-    } else {
-        Ok(cpath.to_path_buf())
+    let usage = || {
+        println!("{}", opts.usage("Usage: mf2tar diff OLD NEW\n\n\
+            Compare two sysroots (archives or directories), and exit \
+            non-zero if\nanything was removed from OLD, breaking the ABI."));
+    };
+
+    let res = match opts.parse(args) {
+        Ok(r) => r,
+        Err(e) => {
+            usage();
+            println!("ERROR: {}", e);
+            exit(error::USAGE);
+        }
+    };
+    if res.opt_present("help") {
+        usage();
+        exit(0);
+    }
+    if res.free.len() != 2 {
+        usage();
+        println!("ERROR: must specify two archives or directories");
+        exit(error::USAGE);
     }
+
+    Mode::Diff(PathBuf::from(&res.free[0]), PathBuf::from(&res.free[1]))
 }
 
-fn prepare_tar(tar_path: &Path, append: bool) -> io::Result<Builder<File>> {
-    let mut tar_file = OpenOptions::new()
-        .write(true)
-        .read(append)
-        .create(true)
-        .truncate(!append)
-        .open(tar_path)?;
-
-    if append {
-        let mut parser = tar::Archive::new(tar_file);
-        let entries = parser.entries()?;
-        let pos = entries.last().map_or(Ok(0u64), |last| -> io::Result<u64> {
-            match last {
-                Err(e) => Err(e),
-                Ok(ent) => {
-                    let size = ent.header().entry_size()?;
-                    let next = ent.raw_file_position() + size;
-                    let next_align = (next + (512 - 1)) & !(512 - 1);
-                    Ok(next_align)
-                }
-            }
-        })?;
-        tar_file = parser.into_inner();
-        tar_file.seek(SeekFrom::Start(pos))?;
+fn parse_dump_vars_args(args: &[String]) -> Mode {
+    let mut opts = Options::new();
+    opts.optopt("m", "manifest", "IPS manifest file", "MANIFEST_FILE");
+    opts.optmulti("d", "define", "variable replacement \"macros\"",
+        "NAME=VALUE");
+    opts.optmulti("T", "transform", "pkgmogrify transform file, applied \
+        ahead of the manifest", "TRANSFORM_FILE");
+    opts.optmulti("I", "include-dir", "directory to search, in order, for \
+        <include> and transform files (default: the manifest directory)",
+        "INCLUDE_DIR");
+    opts.optflag("", "help", "print usage information");
+
+    let usage = || {
+        println!("{}", opts.usage("Usage: mf2tar dump-vars -m MANIFEST_FILE\n\n\
+            List the variables referenced by a manifest, and whether -d \
+            defines each;\nexits non-zero if any without a default is \
+            undefined."));
+    };
+
+    let res = match opts.parse(args) {
+        Ok(r) => r,
+        Err(e) => {
+            usage();
+            println!("ERROR: {}", e);
+            exit(error::USAGE);
+        }
+    };
+    if res.opt_present("help") {
+        usage();
+        exit(0);
+    }
+    if !res.free.is_empty() {
+        usage();
+        println!("ERROR: dump-vars only accepts -m, -d, -T & -I");
+        exit(error::USAGE);
     }
 
-    // TODO: handle seeking to end (and indexing existing records) for append mode
-    Ok(Builder::new(tar_file))
+    Mode::DumpVars(manifest_args(&res, &usage))
 }
 
-fn iterate_items<F>(
-    manifest_path: &Path,
-    manifest_dir: &PathBuf,
-    manifest_file: File,
-    defines: &HashMap<String, String>,
-    mut process_func: F,
-) -> io::Result<()>
-where
-    F: FnMut(&Entry) -> io::Result<()>,
-{
-    println!(
-        "processing {} in {}",
-        manifest_path.to_str().unwrap_or(""),
-        manifest_dir.to_str().unwrap_or("")
-    );
-
-    // Buffer a file input (and strictly convert to line-separated utf8)
-    let file_to_strings = |f: File| BufReader::new(f).lines().filter_map(|x| x.ok());
-    // Handle $(variable) replacement with provided defines
-    let replace = |name: &str| {
-        let val = defines.get(name)?;
-        Some(val.to_string())
+fn parse_show_mapfile_args(args: &[String]) -> Mode {
+    let mut opts = Options::new();
+    opts.optflag("", "help", "print usage information");
+
+    let usage = || {
+        println!("{}", opts.usage("Usage: mf2tar show-mapfile \
+            MAPFILE[,mach=i386|amd64][,define=NAME...][,data=SYMBOL...]\n\n\
+            List the versions and symbols which a mapfile defines, as a shim \
+            would include them."));
     };
 
-    // To avoid malicious manifests creating an infinite loop of includes, track them in a stack.
-    let mut include_stack: Vec<(PathBuf, pkgmf::Reader<_, _>)> = Vec::new();
+    let res = match opts.parse(args) {
+        Ok(r) => r,
+        Err(e) => {
+            usage();
+            println!("ERROR: {}", e);
+            exit(error::USAGE);
+        }
+    };
+    if res.opt_present("help") {
+        usage();
+        exit(0);
+    }
+    if res.free.len() != 1 {
+        usage();
+        println!("ERROR: must specify a single mapfile");
+        exit(error::USAGE);
+    }
 
-    let full_manifest_path = manifest_path.canonicalize()?;
-    include_stack.push((
-        full_manifest_path,
-        pkgmf::Reader::new(file_to_strings(manifest_file), replace),
-    ));
+    let mut opts = res.free[0].split(',');
+    let mapfile = opts.next().unwrap();
+    match shim_options(mapfile, opts) {
+        Ok(shim) => Mode::ShowMapfile(PathBuf::from(mapfile), shim),
+        Err(e) => {
+            usage();
+            println!("ERROR: {}", e);
+            exit(error::USAGE);
+        }
+    }
+}
 
-    while let Some((path, mut reader)) = include_stack.pop() {
-        let include = loop {
-            let next = reader.next();
-            if next.is_none() {
-                break None;
-            }
-            match next.unwrap() {
-                Entry::Include(name) => {
-                    break Some(name);
-                }
-                Entry::Unknown(_) => {}
-                entry => {
-                    if let Err(e) = process_func(&entry) {
-                        eprintln!("{}", e);
-                    }
-                    //if let Err(e) = process_func(&entry)?;
-                }
-            }
-        };
+fn parse_list_packages_args(args: &[String]) -> Mode {
+    let mut opts = Options::new();
+    opts.optflag("", "help", "print usage information");
 
-        if let Some(name) = include {
-            include_stack.push((path, reader));
-
-            let mut inc_path: PathBuf = manifest_dir.clone();
-            inc_path.push(name);
-            inc_path = inc_path.canonicalize()?;
-
-            // Search for a match in the include stack to avoid infinite include loops
-            if include_stack
-                .iter()
-                .any(|(path, _)| path.to_str().unwrap() == inc_path.to_str().unwrap())
-            {
-                let msg = format!(
-                    "infinite include loop through {}",
-                    &inc_path.to_str().unwrap_or("")
-                );
-                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
-            }
+    let usage = || {
+        println!("{}", opts.usage("Usage: mf2tar list-packages \
+            REPOSITORY_DIR\n\n\
+            List the packages in a repository, with their versions."));
+    };
 
-            let file = File::open(&inc_path)?;
-            let new_reader = pkgmf::Reader::new(file_to_strings(file), replace);
-            include_stack.push((inc_path, new_reader));
+    let res = match opts.parse(args) {
+        Ok(r) => r,
+        Err(e) => {
+            usage();
+            println!("ERROR: {}", e);
+            exit(error::USAGE);
         }
+    };
+    if res.opt_present("help") {
+        usage();
+        exit(0);
+    }
+    if res.free.len() != 1 {
+        usage();
+        println!("ERROR: must specify a single repository");
+        exit(error::USAGE);
     }
-    Ok(())
-}
 
-enum TarFileSource<'a> {
-    Proto(&'a PathBuf),
-    Repository(&'a Repository),
-    SingleFile(&'a PathBuf),
-    None,
+    Mode::ListPackages(PathBuf::from(&res.free[0]))
 }
 
-fn append_tar<W: io::Write>(
-    builder: &mut Builder<W>,
-    source: &TarFileSource,
-    entry: &Entry,
-    mtime: u64,
-) -> io::Result<()> {
-    match entry {
-        Entry::Dir(dir) => {
-            let mut header = Header::new_ustar();
-            header.set_entry_type(EntryType::Directory);
-            header.set_path(&dir.path)?;
-            header.set_mode(0o755);
-            header.set_mtime(mtime);
-            header.set_cksum();
-
-            builder.append(&header, &[] as &[u8])?;
-            println!(" d {}", &dir.path);
-            Ok(())
+fn parse_show_args(args: &[String]) -> Mode {
+    let mut opts = Options::new();
+    opts.optmulti("", "variant", "leave out manifest actions tagged for any \
+        other value of variant.NAME", "NAME=VALUE");
+    opts.optflag("", "help", "print usage information");
+
+    let usage = || {
+        println!("{}", opts.usage("Usage: mf2tar show REPOSITORY_DIR \
+            PACKAGE_NAME[@VERSION]\n\n\
+            Print the manifest of a package in a repository, as mf2tar reads \
+            it."));
+    };
+
+    let res = match opts.parse(args) {
+        Ok(r) => r,
+        Err(e) => {
+            usage();
+            println!("ERROR: {}", e);
+            exit(error::USAGE);
         }
-        Entry::File(file) => {
-            let mut header = Header::new_ustar();
-            header.set_entry_type(EntryType::Regular);
-            header.set_path(&file.path)?;
-            header.set_mode(0o644);
-            header.set_mtime(mtime);
-
-            match source {
-                TarFileSource::None =>
-                    panic!("should not be None for Entry::File"),
-                TarFileSource::SingleFile(path) => {
-                    let source = match File::open(&path) {
-                        Err(e) => {
-                            return Err(io::Error::new(
-                                e.kind(),
-                                format!("{}: {}", e, &path.to_str().unwrap()),
-                            ));
-                        }
-                        Ok(f) => f,
-                    };
-                    let meta = source.metadata()?;
-
-                    if !meta.file_type().is_file() {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            format!("{} is not a file", &path.to_str().unwrap()),
-                        ));
-                    }
-                    let source_len = meta.len();
-
-                    header.set_size(source_len);
-                    header.set_cksum();
-                    builder.append(&header, source.take(source_len))?;
-                }
-                TarFileSource::Proto(proto_dir) => {
-                    let mut source_path = proto_dir.to_path_buf();
-                    source_path.push(&file.path);
-
-                    let source = match File::open(&source_path) {
-                        Err(e) => {
-                            return Err(io::Error::new(
-                                e.kind(),
-                                format!("{}: {}", e, &source_path.to_str().unwrap()),
-                            ));
-                        }
-                        Ok(f) => f,
-                    };
-                    let meta = source.metadata()?;
-
-                    if !meta.file_type().is_file() {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            format!("{} is not a file", &source_path.to_str().unwrap()),
-                        ));
-                    }
-                    let source_len = meta.len();
-
-                    header.set_size(source_len);
-                    header.set_cksum();
-                    builder.append(&header, source.take(source_len))?;
-                }
-                TarFileSource::Repository(repo) => {
-                    let buf = match repo.file(file.cname.as_ref().unwrap(),
-                        file.chash.as_ref().unwrap())
-                    {
-                        Ok(v) => v,
-                        Err(e) => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::Other,
-                                format!("file {}: {}", &file.path, e)));
-                        }
-                    };
-
-                    header.set_size(buf.len() as u64);
-                    header.set_cksum();
-                    builder.append(&header, buf.as_slice())?;
-                }
-            };
+    };
+    if res.opt_present("help") {
+        usage();
+        exit(0);
+    }
+    if res.free.len() != 2 {
+        usage();
+        println!("ERROR: must specify a repository and a package");
+        exit(error::USAGE);
+    }
 
-            println!(" f {}", &file.path);
-            Ok(())
+    let mut variants = BTreeMap::new();
+    for v in res.opt_strs("variant") {
+        match v.split_once('=') {
+            Some((name, value)) => {
+                variants.insert(name.to_string(), value.to_string());
+            }
+            None => {
+                usage();
+                println!("ERROR: --variant requires NAME=VALUE arguments");
+                exit(error::USAGE);
+            }
         }
-        pkgmf::Entry::Link(link) => {
-            let mut header = Header::new_ustar();
-            header.set_entry_type(EntryType::Symlink);
-            header.set_size(0);
-            header.set_path(&link.path)?;
-            header.set_mtime(mtime);
-            header.set_mode(0o777);
-            header.set_link_name(&link.target)?;
-            header.set_cksum();
-
-            // TODO: handle symlinks which are too long
-            builder.append(&header, io::empty())?;
-            println!(" l {} -> {}", &link.path, &link.target);
-            Ok(())
+    }
+
+    Mode::Show(PathBuf::from(&res.free[0]), res.free[1].clone(), variants)
+}
+
+fn parse_contents_args(args: &[String]) -> Mode {
+    let mut opts = Options::new();
+    opts.optflag("", "help", "print usage information");
+
+    let usage = || {
+        println!("{}", opts.usage("Usage: mf2tar contents TARFILE\n\n\
+            List the contents of an archive (which may be compressed with \
+            gzip)."));
+    };
+
+    let res = match opts.parse(args) {
+        Ok(r) => r,
+        Err(e) => {
+            usage();
+            println!("ERROR: {}", e);
+            exit(error::USAGE);
         }
-        _ => Ok(()),
+    };
+    if res.opt_present("help") {
+        usage();
+        exit(0);
+    }
+    if res.free.len() != 1 {
+        usage();
+        println!("ERROR: must specify a single archive");
+        exit(error::USAGE);
     }
+
+    Mode::Contents(PathBuf::from(&res.free[0]))
 }
 
-fn main() {
-    let params = parse_args();
-
-    /*
-     * Use a single mtime for all files in the archive.
-     */
-    let mtime = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-
-    let mut tar_builder = match &params.source {
-        Source::ManifestProto(manifest, proto_area, defines) => {
-            let (manifest_dir, manifest_file) = match prepare_manifest(&manifest) {
-                Err(err) => {
-                    eprintln!("Error preparing: {}", err);
-                    exit(118);
-                }
-                Ok(state) => state,
-            };
+const USAGE: &str = "Usage: mf2tar COMMAND [OPTIONS]
 
-            let proto_dir = match prepare_proto(&proto_area) {
-                Err(err) => {
-                    eprintln!("Invalid proto area: {}", err);
-                    exit(119);
-                }
-                Ok(t) => t,
-            };
-            let source = TarFileSource::Proto(&proto_dir);
+Commands:
+    build           make a sysroot archive
+    list-packages   list the packages in a repository
+    show            print the manifest of a package in a repository
+    contents        list the contents of an archive
+    audit           report the versions and symbols the libraries export
+    diff            compare two sysroots
+    dump-vars       list the variables referenced by a manifest
+    show-mapfile    list the versions and symbols a mapfile defines
 
-            for (key, value) in defines.iter() {
-                println!("'{}' => '{}'", key, value);
-            }
+Run \"mf2tar COMMAND --help\" for the options of each.
 
-            let mut tar_builder = match prepare_tar(&params.tar, params.append)
-            {
-                Err(err) => {
-                    eprintln!("Error preparing tar: {}", err);
-                    exit(85);
-                }
-                Ok(t) => t,
-            };
-
-            let proc_func = |entry: &Entry| {
-                if let Some(path) = entry.get_path() {
-                    if params
-                        .excludes
-                        .iter()
-                        .find(|&comp| path.starts_with(comp))
-                        .is_none()
-                    {
-                        append_tar(&mut tar_builder, &source, entry, mtime)?;
-                    }
-                }
-                Ok(())
-            };
-
-            let res = iterate_items(
-                &manifest,
-                &manifest_dir,
-                manifest_file,
-                &defines,
-                proc_func,
-            );
-            if let Err(e) = res {
-                eprintln!("{}", e);
-                exit(117);
-            }
+Exit status: 0 success; 1 a check failed; 2 usage; 3 repository; 4 manifest
+or mapfile; 5 payload missing or with the wrong hash; 6 lockfile differs;
+7 tar; 8 other I/O.";
 
-            tar_builder
+fn parse_args() -> Mode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let rest = args.get(1..).unwrap_or(&[]);
+    match args.first().map(String::as_str) {
+        Some("build") => parse_build_args(rest),
+        Some("list-packages") => parse_list_packages_args(rest),
+        Some("show") => parse_show_args(rest),
+        Some("contents") => parse_contents_args(rest),
+        Some("audit") => parse_audit_args(rest),
+        Some("diff") => parse_diff_args(rest),
+        Some("dump-vars") => parse_dump_vars_args(rest),
+        Some("show-mapfile") => parse_show_mapfile_args(rest),
+        Some("help") | Some("--help") => {
+            println!("{}", USAGE);
+            exit(0);
         }
-        Source::RepositoryPackages(repo_dir, package_names) => {
-            let repo = match Repository::new(&repo_dir) {
-                Ok(v) => v,
-                Err(e) => {
-                    eprintln!("ERROR: repository open: {}", e);
-                    exit(103);
-                }
-            };
-            let source = TarFileSource::Repository(&repo);
-
-            let packages = match repo.scan() {
-                Ok(v) => v,
-                Err(e) => {
-                    eprintln!("ERROR: repository scan: {}", e);
-                    exit(104);
-                }
-            };
+        Some(command) => {
+            println!("{}", USAGE);
+            println!("ERROR: unknown command \"{}\"", command);
+            exit(error::USAGE);
+        }
+        None => {
+            println!("{}", USAGE);
+            println!("ERROR: must specify a command");
+            exit(error::USAGE);
+        }
+    }
+}
 
-            let mut tar_builder = match prepare_tar(&params.tar, params.append)
-            {
-                Err(err) => {
-                    eprintln!("Error preparing tar: {}", err);
-                    exit(85);
-                }
-                Ok(t) => t,
-            };
-
-            for pn in package_names {
-                let pkg = if let Some(pkg) = packages.get(pn) {
-                    pkg
-                } else {
-                    eprintln!("ERROR: package \"{}\" not found in repository",
-                        pn);
-                    exit(100);
-                };
-
-                if pkg.versions.len() != 1 {
-                    eprintln!("ERROR: package \"{}\" has {} versions, not 1",
-                        pn, pkg.versions.len());
-                    exit(101);
-                }
+fn release_args(res: &getopts::Matches) -> Option<release::Release> {
+    res.opt_str("release").map(|version| release::Release {
+        version,
+        commit: res.opt_str("release-commit"),
+    })
+}
 
-                let mfest = match pkg.versions.first().unwrap().manifest() {
-                    Ok(v) => v,
-                    Err(e) => {
-                        eprintln!("ERROR: manifest load: {}", e);
-                        exit(105);
-                    }
-                };
-
-                for ent in mfest {
-                    if let Some(path) = ent.get_path() {
-                        if params.excludes
-                            .iter()
-                            .find(|&comp| path.starts_with(comp))
-                            .is_none()
-                        {
-                            if let Err(e) = append_tar(&mut tar_builder,
-                                &source, &ent, mtime)
-                            {
-                                eprintln!("ERROR: tar: {}", e);
-                                exit(110);
-                            }
-                        }
-                    }
+fn main() {
+    let mut report = Reporter::default();
+    let res = match parse_args() {
+        // The build reports its own errors.
+        Mode::Archive(builder) => match builder.build() {
+            Ok(()) => Ok(()),
+            Err(e) => exit(e.exit_code()),
+        },
+        Mode::ShowMapfile(path, shim) => inspect::mapfile(&path, &shim)
+            .map(|lines| print_lines(&lines))
+            .map_err(Error::Mapfile),
+        Mode::Audit(paths, output) => {
+            audit::write(&paths, output.as_deref(), &mut report)
+        }
+        Mode::Diff(old, new) => match diff::Diff::new(&old, &new) {
+            Ok(diff) => {
+                print!("{}", diff);
+                match diff.breaks() {
+                    0 => Ok(()),
+                    n => Err(Error::Check(format!("{} change(s) break the \
+                        ABI", n))),
                 }
             }
-
-            tar_builder
+            Err(error) => Err(Error::Tar { path: None, error }),
+        },
+        Mode::ListPackages(repo) => inspect::list_packages(&repo)
+            .map(|packages| print_lines(&packages))
+            .map_err(Error::from),
+        Mode::Show(repo, package, variants) => {
+            inspect::show(&repo, &package, &variants)
+                .map(|lines| print_lines(&lines))
+                .map_err(Error::from)
         }
-    };
-
-    if !params.extra.is_empty() {
-        println!("EXTRA FILES AND LINKS:");
-    }
-    for extra in &params.extra {
-        let res = match extra {
-            Extra::File(entry, file) => {
-                append_tar(&mut tar_builder, &TarFileSource::SingleFile(file),
-                    &entry, mtime)
-            }
-            Extra::Link(entry) => {
-                append_tar(&mut tar_builder, &TarFileSource::None,
-                    &entry, mtime)
+        Mode::Contents(path) => match inspect::contents(&path) {
+            Ok(lines) => {
+                print_lines(&lines);
+                Ok(())
             }
-        };
-        if let Err(e) = res {
-            eprintln!("ERROR: tar: {}", e);
-            exit(111);
+            Err(error) => Err(Error::Tar {
+                path: Some(path.display().to_string()),
+                error,
+            }),
+        },
+        Mode::DumpVars(args) => {
+            inspect::variables(&args, &mut report).and_then(|(lines, n)| {
+                print_lines(&lines);
+                match n {
+                    0 => Ok(()),
+                    n => Err(Error::Check(format!("{} variable(s) \
+                        undefined, without a default", n))),
+                }
+            })
         }
+    };
+    if let Err(e) = res {
+        report.emit(Event::Error { message: &e.to_string() });
+        exit(e.exit_code());
     }
+}
 
-    if let Err(e) = tar_builder.finish() {
-        eprintln!("ERROR: tar: {}", e);
-        exit(97);
+fn print_lines(lines: &[String]) {
+    for l in lines {
+        println!("{}", l);
     }
 }
+
@@ -0,0 +1,660 @@
+// Copyright 2020 Oxide Computer Company
+
+//! A reader for version 2 link-editor mapfiles, as consumed by the illumos
+//! `ld -M`.  Only the symbol-related directives (`SYMBOL_VERSION` and
+//! `SYMBOL_SCOPE`) are understood, along with the control directives (`$if`,
+//! `$elif`, `$else`, `$endif`, `$add`, `$clear` and `$error`) used to select
+//! versions.
+
+use std::collections::HashSet;
+
+#[derive(Debug, PartialEq)]
+pub struct Mapfile {
+    pub versions: Vec<SymbolVersion>,
+}
+
+/// A `SYMBOL_VERSION` block, or the unnamed block of a `SYMBOL_SCOPE`.
+#[derive(Debug, PartialEq)]
+pub struct SymbolVersion {
+    pub name: Option<String>,
+    pub parents: Vec<String>,
+    pub symbols: Vec<Symbol>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    Global,
+    Local,
+    Protected,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymbolType {
+    Function,
+    Data,
+    Common,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Size {
+    Bytes(u64),
+    /// A multiple of the address size of the target (`SIZE = addrsize[n]`)
+    AddrSize(u64),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub scope: Scope,
+    pub kind: Option<SymbolType>,
+    pub size: Option<Size>,
+}
+
+impl Size {
+    pub fn bytes(&self, addrsize: u64) -> u64 {
+        match self {
+            Size::Bytes(n) => *n,
+            Size::AddrSize(n) => n * addrsize,
+        }
+    }
+}
+
+/// The names which the link-editor defines before reading any mapfile, for
+/// a target of the given class (32- or 64-bit) on x86.
+pub fn predefined(is_64: bool) -> Vec<&'static str> {
+    vec![if is_64 { "_ELF64" } else { "_ELF32" }, "_ELF_LSB", "_x86"]
+}
+
+/// Evaluate the expression of an `$if` or `$elif`: names, which are true if
+/// defined, combined with `!`, `&&`, `||` and parentheses.  As with the C
+/// preprocessor, `&&` binds more tightly than `||`.
+fn evaluate(expr: &str, defined: &HashSet<String>) -> Result<bool, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '!' | '(' | ')' => tokens.push(c.to_string()),
+            '&' | '|' => {
+                if chars.next() != Some(c) {
+                    return Err(format!("expected \"{}{}\"", c, c));
+                }
+                tokens.push(format!("{}{}", c, c));
+            }
+            c if c.is_alphanumeric() || c == '_' || c == '.' => {
+                let mut name = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_' || c == '.') {
+                        break;
+                    }
+                    name.push(c);
+                    chars.next();
+                }
+                tokens.push(name);
+            }
+            c => return Err(format!("unexpected \"{}\" in expression", c)),
+        }
+    }
+
+    struct Eval<'a> {
+        tokens: std::iter::Peekable<std::slice::Iter<'a, String>>,
+        defined: &'a HashSet<String>,
+    }
+
+    impl Eval<'_> {
+        fn or(&mut self) -> Result<bool, String> {
+            let mut v = self.and()?;
+            while self.tokens.peek().is_some_and(|t| *t == "||") {
+                self.tokens.next();
+                v |= self.and()?;
+            }
+            Ok(v)
+        }
+
+        fn and(&mut self) -> Result<bool, String> {
+            let mut v = self.unary()?;
+            while self.tokens.peek().is_some_and(|t| *t == "&&") {
+                self.tokens.next();
+                v &= self.unary()?;
+            }
+            Ok(v)
+        }
+
+        fn unary(&mut self) -> Result<bool, String> {
+            match self.tokens.next().map(String::as_str) {
+                Some("!") => Ok(!self.unary()?),
+                Some("(") => {
+                    let v = self.or()?;
+                    match self.tokens.next().map(String::as_str) {
+                        Some(")") => Ok(v),
+                        _ => Err("expected \")\"".to_string()),
+                    }
+                }
+                Some("1") => Ok(true),
+                Some("0") => Ok(false),
+                Some(t) if t != ")" && t != "&&" && t != "||" => {
+                    Ok(self.defined.contains(t))
+                }
+                Some(t) => Err(format!("unexpected \"{}\" in expression", t)),
+                None => Err("incomplete expression".to_string()),
+            }
+        }
+    }
+
+    let mut eval = Eval {
+        tokens: tokens.iter().peekable(),
+        defined,
+    };
+    let v = eval.or()?;
+    if let Some(t) = eval.tokens.next() {
+        return Err(format!("unexpected \"{}\" in expression", t));
+    }
+    Ok(v)
+}
+
+/// The state of one `$if` ... `$endif` block.
+struct Cond {
+    /// Whether the lines around the block are selected
+    outer: bool,
+    /// Whether any branch so far has been selected
+    taken: bool,
+    /// Whether the current branch is selected
+    active: bool,
+    /// Whether `$else` has been seen
+    seen_else: bool,
+}
+
+/// Apply the control directives, returning the text of the lines which are
+/// selected given the names already defined.  Directive lines (and those not
+/// selected) are left empty, so that line numbers are preserved.
+fn preprocess(text: &str, defines: &[&str]) -> Result<String, String> {
+    let mut defined: HashSet<String> =
+        defines.iter().map(|d| d.to_string()).collect();
+    let mut stack: Vec<Cond> = Vec::new();
+    let mut out = String::with_capacity(text.len());
+
+    for (n, raw) in text.lines().enumerate() {
+        let line = raw.trim();
+        let active = stack.last().is_none_or(|c| c.active);
+        let err = |msg: &str| format!("line {}: {}: {}", n + 1, msg, line);
+
+        if !line.starts_with('$') {
+            if active {
+                out.push_str(raw);
+            }
+            out.push('\n');
+            continue;
+        }
+        out.push('\n');
+
+        let line = line.split('#').next().unwrap().trim();
+        let (directive, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        let eval = |expr: &str, defined: &HashSet<String>| {
+            if expr.is_empty() {
+                return Err(err("missing expression"));
+            }
+            evaluate(expr, defined).map_err(|e| err(&e))
+        };
+        match directive {
+            "$mapfile_version" => {
+                if rest != "2" {
+                    return Err(err("only mapfile version 2 is supported"));
+                }
+            }
+            "$if" => {
+                let v = active && eval(rest, &defined)?;
+                stack.push(Cond {
+                    outer: active,
+                    taken: v,
+                    active: v,
+                    seen_else: false,
+                });
+            }
+            "$elif" => {
+                let cond = match stack.last_mut() {
+                    Some(c) if !c.seen_else => c,
+                    _ => return Err(err("$elif without $if")),
+                };
+                let v = cond.outer && !cond.taken && eval(rest, &defined)?;
+                cond.taken |= v;
+                cond.active = v;
+            }
+            "$else" => {
+                let cond = match stack.last_mut() {
+                    Some(c) if !c.seen_else && rest.is_empty() => c,
+                    _ => return Err(err("$else without $if")),
+                };
+                cond.active = cond.outer && !cond.taken;
+                cond.taken = true;
+                cond.seen_else = true;
+            }
+            "$endif" => {
+                if stack.pop().is_none() || !rest.is_empty() {
+                    return Err(err("$endif without $if"));
+                }
+            }
+            "$add" | "$clear" => {
+                let mut names = rest.split_whitespace().peekable();
+                if names.peek().is_none() {
+                    return Err(err("missing name"));
+                }
+                if active {
+                    for name in names {
+                        if directive == "$add" {
+                            defined.insert(name.to_string());
+                        } else {
+                            defined.remove(name);
+                        }
+                    }
+                }
+            }
+            "$error" => {
+                if active {
+                    return Err(format!("line {}: $error: {}", n + 1, rest));
+                }
+            }
+            _ => return Err(err("unsupported control directive")),
+        }
+    }
+
+    if !stack.is_empty() {
+        return Err("missing $endif".to_string());
+    }
+    Ok(out)
+}
+
+/// Split the text into tokens, each with the number of its line.
+fn tokenize(text: &str) -> Vec<(usize, String)> {
+    let mut tokens = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap();
+        let mut word = String::new();
+        for c in line.chars() {
+            if c.is_whitespace() || "{};:=[]".contains(c) {
+                if !word.is_empty() {
+                    tokens.push((n + 1, std::mem::take(&mut word)));
+                }
+                if !c.is_whitespace() {
+                    tokens.push((n + 1, c.to_string()));
+                }
+            } else {
+                word.push(c);
+            }
+        }
+        if !word.is_empty() {
+            tokens.push((n + 1, word));
+        }
+    }
+    tokens
+}
+
+struct Parser {
+    tokens: std::vec::IntoIter<(usize, String)>,
+    /// The line of the most recent token
+    line: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Result<String, String> {
+        self.next_opt()
+            .ok_or_else(|| "unexpected end of mapfile".to_string())
+    }
+
+    fn next_opt(&mut self) -> Option<String> {
+        let (line, tok) = self.tokens.next()?;
+        self.line = line;
+        Some(tok)
+    }
+
+    fn expect(&mut self, want: &str) -> Result<(), String> {
+        let tok = self.next()?;
+        if tok != want {
+            return Err(format!("expected \"{}\", found \"{}\"", want, tok));
+        }
+        Ok(())
+    }
+
+    fn size(value: &[String]) -> Result<Size, String> {
+        let err = || format!("invalid SIZE: {}", value.join(" "));
+        let count = match value {
+            [_] => 1,
+            [_, open, n, close] if open == "[" && close == "]" => {
+                parse_number(n).ok_or_else(err)?
+            }
+            _ => return Err(err()),
+        };
+        if value[0] == "addrsize" {
+            Ok(Size::AddrSize(count))
+        } else {
+            Ok(Size::Bytes(parse_number(&value[0]).ok_or_else(err)? * count))
+        }
+    }
+
+    /// Parse the `{ ATTR = value; ... }` following a symbol name.
+    fn attributes(&mut self, sym: &mut Symbol) -> Result<(), String> {
+        loop {
+            let name = self.next()?;
+            if name == "}" {
+                return Ok(());
+            }
+            self.expect("=")?;
+            if name == "ASSERT" {
+                // The asserted TYPE and SIZE describe the symbol as well as
+                // a direct definition would.
+                self.expect("{")?;
+                self.attributes(sym)?;
+                match self.next()?.as_str() {
+                    ";" => continue,
+                    "}" => return Ok(()),
+                    other => {
+                        return Err(format!(
+                            "unexpected \"{}\" after ASSERT for {}",
+                            other, sym.name
+                        ))
+                    }
+                }
+            }
+            let mut value: Vec<String> = Vec::new();
+            loop {
+                let tok = self.next()?;
+                if tok == ";" || tok == "}" {
+                    match name.as_str() {
+                        "TYPE" => {
+                            sym.kind = Some(match value.as_slice() {
+                                [t] if t.eq_ignore_ascii_case("FUNCTION") => {
+                                    SymbolType::Function
+                                }
+                                [t] if t.eq_ignore_ascii_case("DATA") => {
+                                    SymbolType::Data
+                                }
+                                [t] if t.eq_ignore_ascii_case("COMMON") => {
+                                    SymbolType::Common
+                                }
+                                _ => {
+                                    return Err(format!(
+                                        "invalid TYPE for {}",
+                                        sym.name
+                                    ))
+                                }
+                            });
+                        }
+                        "SIZE" => sym.size = Some(Self::size(&value)?),
+                        "VALUE" | "FLAGS" | "FILTER" | "AUXILIARY" | "BINDING"
+                        | "ALIAS" => {}
+                        other => {
+                            return Err(format!(
+                                "unknown symbol attribute {}",
+                                other
+                            ))
+                        }
+                    }
+                    if tok == "}" {
+                        return Ok(());
+                    }
+                    break;
+                }
+                value.push(tok);
+            }
+        }
+    }
+
+    /// Parse the body of a `SYMBOL_VERSION` or `SYMBOL_SCOPE` block, after
+    /// the opening brace.
+    fn symbols(&mut self) -> Result<Vec<Symbol>, String> {
+        let mut symbols = Vec::new();
+        let mut scope = Scope::Global;
+        loop {
+            let name = self.next()?;
+            if name == "}" {
+                return Ok(symbols);
+            }
+            let mut sym = Symbol {
+                name,
+                scope,
+                kind: None,
+                size: None,
+            };
+            match self.next()?.as_str() {
+                ":" => {
+                    scope = match sym.name.as_str() {
+                        "default" | "global" | "exported" | "singleton" => {
+                            Scope::Global
+                        }
+                        "hidden" | "local" | "eliminate" => Scope::Local,
+                        "protected" | "symbolic" => Scope::Protected,
+                        other => return Err(format!("unknown scope {}", other)),
+                    };
+                    continue;
+                }
+                ";" => {}
+                "{" => {
+                    self.attributes(&mut sym)?;
+                    self.expect(";")?;
+                }
+                other => {
+                    return Err(format!(
+                        "unexpected \"{}\" after symbol {}",
+                        other, sym.name
+                    ))
+                }
+            }
+            symbols.push(sym);
+        }
+    }
+
+    fn mapfile(&mut self) -> Result<Mapfile, String> {
+        let mut versions = Vec::new();
+        while let Some(directive) = self.next_opt() {
+            match directive.as_str() {
+                "SYMBOL_VERSION" => {
+                    let name = self.next()?;
+                    self.expect("{")?;
+                    let symbols = self.symbols()?;
+                    let mut parents = Vec::new();
+                    loop {
+                        let tok = self.next()?;
+                        if tok == ";" {
+                            break;
+                        }
+                        parents.push(tok);
+                    }
+                    for p in &parents {
+                        if !versions.iter().any(|v: &SymbolVersion| {
+                            v.name.as_ref() == Some(p)
+                        }) {
+                            return Err(format!(
+                                "version {} inherits undefined version {}",
+                                name, p
+                            ));
+                        }
+                    }
+                    versions.push(SymbolVersion {
+                        name: Some(name),
+                        parents,
+                        symbols,
+                    });
+                }
+                "SYMBOL_SCOPE" => {
+                    self.expect("{")?;
+                    let symbols = self.symbols()?;
+                    self.expect(";")?;
+                    versions.push(SymbolVersion {
+                        name: None,
+                        parents: Vec::new(),
+                        symbols,
+                    });
+                }
+                other => {
+                    return Err(format!("unsupported directive {}", other));
+                }
+            }
+        }
+        Ok(Mapfile { versions })
+    }
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+impl Mapfile {
+    /// Parse a mapfile, with the names in `defines` treated as already
+    /// defined (as if by `$add`) for the purpose of `$if` evaluation.
+    pub fn parse(text: &str, defines: &[&str]) -> Result<Mapfile, String> {
+        let text = preprocess(text, defines)?;
+        let mut parser = Parser {
+            tokens: tokenize(&text).into_iter(),
+            line: 0,
+        };
+        parser
+            .mapfile()
+            .map_err(|e| format!("line {}: {}", parser.line, e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAPFILE: &str = "$mapfile_version 2
+
+$if VER_2
+$add VER_1
+$endif
+
+SYMBOL_VERSION TEST_1 {
+    global:
+\tfunc_one;
+\tdata_one { TYPE = DATA; SIZE = addrsize };
+    local:
+\t*;
+};
+
+$if VER_2
+SYMBOL_VERSION TEST_2 {
+    global:
+\tfunc_two;    # a comment
+\tdata_two { TYPE = DATA; SIZE = 4[3]; };
+\tdata_three { ASSERT = { TYPE = data; SIZE = 8 } };
+} TEST_1;
+$endif
+";
+
+    #[test]
+    fn versions_selected() {
+        let mf = Mapfile::parse(MAPFILE, &[]).unwrap();
+        assert_eq!(mf.versions.len(), 1);
+        let mf = Mapfile::parse(MAPFILE, &["VER_2"]).unwrap();
+        assert_eq!(mf.versions.len(), 2);
+
+        let v = &mf.versions[1];
+        assert_eq!(v.name.as_deref(), Some("TEST_2"));
+        assert_eq!(v.parents, vec!["TEST_1".to_string()]);
+        assert_eq!(
+            v.symbols[1],
+            Symbol {
+                name: "data_two".to_string(),
+                scope: Scope::Global,
+                kind: Some(SymbolType::Data),
+                size: Some(Size::Bytes(12)),
+            }
+        );
+        assert_eq!(v.symbols[2].kind, Some(SymbolType::Data));
+        assert_eq!(v.symbols[2].size, Some(Size::Bytes(8)));
+
+        let v = &mf.versions[0];
+        assert_eq!(v.symbols.len(), 3);
+        assert_eq!(v.symbols[1].size.unwrap().bytes(8), 8);
+        assert_eq!(v.symbols[2].name, "*");
+        assert_eq!(v.symbols[2].scope, Scope::Local);
+    }
+
+    #[test]
+    fn expressions() {
+        let defined: HashSet<String> =
+            ["A", "B"].iter().map(|s| s.to_string()).collect();
+        let eval = |e| evaluate(e, &defined);
+        assert_eq!(eval("A"), Ok(true));
+        assert_eq!(eval("!A"), Ok(false));
+        assert_eq!(eval("C || A && B"), Ok(true));
+        assert_eq!(eval("(C || A) && !B"), Ok(false));
+        assert_eq!(eval("!(C)&&1"), Ok(true));
+        assert!(eval("A &").is_err());
+        assert!(eval("(A").is_err());
+        assert!(eval("A B").is_err());
+    }
+
+    fn selected(text: &str, defines: &[&str]) -> Vec<String> {
+        let mf = Mapfile::parse(text, defines).unwrap();
+        mf.versions
+            .iter()
+            .flat_map(|v| v.symbols.iter().map(|s| s.name.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn conditionals() {
+        let text = "$if _ELF64
+            $add WIDE
+            $elif _ELF32 && !OLD
+            $add NARROW
+            $else
+            $error no target
+            $endif
+            $clear _ELF64
+            SYMBOL_SCOPE {
+            $if WIDE
+                wide;
+            $elif NARROW
+                narrow;
+            $endif
+            $if _ELF64
+                never;
+            $endif
+            };";
+        assert_eq!(selected(text, &["_ELF64"]), vec!["wide"]);
+        assert_eq!(selected(text, &["_ELF32"]), vec!["narrow"]);
+        let err = Mapfile::parse(text, &["_ELF32", "OLD"]).unwrap_err();
+        assert_eq!(err, "line 6: $error: no target");
+
+        // Nested blocks within an unselected branch are never selected.
+        let text = "$if A
+            $else
+            $if 1
+            SYMBOL_SCOPE { a; };
+            $endif
+            $endif";
+        assert!(selected(text, &["A"]).is_empty());
+        assert_eq!(selected(text, &[]), vec!["a"]);
+    }
+
+    #[test]
+    fn errors() {
+        let err = |text| Mapfile::parse(text, &[]).unwrap_err();
+        assert!(err("$mapfile_version 1\n").contains("version 2"));
+        assert_eq!(err("$if A\n"), "missing $endif");
+        assert!(err("$endif\n").starts_with("line 1: $endif without $if"));
+        assert!(err("$if A\n$else\n$else\n$endif")
+            .starts_with("line 3: $else without $if"));
+        assert!(err("$if\n$endif").starts_with("line 1: missing expression"));
+        assert_eq!(
+            err("SYMBOL_VERSION A {\n    a\n};"),
+            "line 3: unexpected \"}\" after symbol a"
+        );
+        assert_eq!(
+            err("\nLOAD_SEGMENT text {};"),
+            "line 2: unsupported directive LOAD_SEGMENT"
+        );
+        assert_eq!(
+            err("SYMBOL_VERSION A { a; } B;"),
+            "line 1: version A inherits undefined version B"
+        );
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright 2020 Oxide Computer Company
+
+//! Checks that the dependencies (`DT_NEEDED` entries) of each shared object
+//! in an archive can be found within the archive, as the link-editor would
+//! search for them when linking against the sysroot.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+
+use tar::EntryType;
+
+use crate::elf::{self, Object};
+use crate::vfs::{self, Node, Tree};
+
+/// Directories searched for the dependencies of 32- and 64-bit objects,
+/// after any run path of the object itself.
+const DEFAULT_32: &[&str] = &["lib", "usr/lib"];
+const DEFAULT_64: &[&str] = &["lib/amd64", "usr/lib/amd64"];
+
+struct Library {
+    path: String,
+    is64: bool,
+    needed: Vec<String>,
+    runpath: Vec<String>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    /// Each dependency which could not be found, with the paths of the
+    /// objects which need it.
+    pub unresolved: BTreeMap<String, Vec<String>>,
+    /// Objects which could not be read, with the reason.
+    pub malformed: Vec<(String, String)>,
+}
+
+impl Library {
+    fn search_dirs(&self) -> Vec<String> {
+        let origin = match self.path.rfind('/') {
+            Some(i) => &self.path[..i],
+            None => "",
+        };
+        let mut dirs: Vec<String> = self
+            .runpath
+            .iter()
+            .flat_map(|rp| rp.split(':'))
+            .filter_map(|dir| {
+                if let Some(rest) = dir.strip_prefix("$ORIGIN") {
+                    Some(format!("/{}{}", origin, rest))
+                } else if dir.starts_with('/') {
+                    Some(dir.to_string())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let defaults = if self.is64 { DEFAULT_64 } else { DEFAULT_32 };
+        dirs.extend(defaults.iter().map(|d| format!("/{}", d)));
+        dirs
+    }
+
+    fn resolves(&self, tree: &Tree, name: &str) -> bool {
+        let found = |path: &str| {
+            matches!(tree.resolve(path), Some((_, Node::File)))
+        };
+        if name.contains('/') {
+            return found(name);
+        }
+        self.search_dirs()
+            .iter()
+            .any(|dir| found(&format!("{}/{}", dir, name)))
+    }
+}
+
+/// Read the archive, and report on the dependencies of its shared objects.
+pub fn check<R: Read>(archive: R) -> io::Result<Report> {
+    let mut tree = Tree::new();
+    let mut libraries = Vec::new();
+    let mut report = Report::default();
+
+    let mut archive = tar::Archive::new(archive);
+    for ent in archive.entries()? {
+        let mut ent = ent?;
+        let path = vfs::normalize(&ent.path()?.to_string_lossy());
+        match ent.header().entry_type() {
+            EntryType::Directory => tree.insert(&path, Node::Dir),
+            EntryType::Symlink => {
+                let target = ent
+                    .link_name()?
+                    .map(|t| t.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                tree.insert(&path, Node::Link(target));
+            }
+            EntryType::Regular => {
+                tree.insert(&path, Node::File);
+
+                let mut data = Vec::new();
+                ent.read_to_end(&mut data)?;
+                if !Object::is_elf(&data) {
+                    continue;
+                }
+                let lib = Object::parse(&data).and_then(|obj| {
+                    if obj.kind != elf::ET_DYN {
+                        return Ok(None);
+                    }
+                    let mut runpath = obj.dynamic_strings(elf::DT_RUNPATH)?;
+                    runpath.extend(obj.dynamic_strings(elf::DT_RPATH)?);
+                    Ok(Some(Library {
+                        path: path.clone(),
+                        is64: obj.is_64(),
+                        needed: obj.dynamic_strings(elf::DT_NEEDED)?,
+                        runpath,
+                    }))
+                });
+                match lib {
+                    Ok(Some(lib)) => libraries.push(lib),
+                    Ok(None) => {}
+                    Err(e) => report.malformed.push((path, e)),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for lib in &libraries {
+        for name in &lib.needed {
+            if !lib.resolves(&tree, name) {
+                report
+                    .unresolved
+                    .entry(name.clone())
+                    .or_default()
+                    .push(lib.path.clone());
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::elf::{Machine, Stub};
+    use tar::{Builder, Header};
+
+    fn add(
+        b: &mut Builder<Vec<u8>>,
+        path: &str,
+        kind: EntryType,
+        data: &[u8],
+    ) {
+        let mut header = Header::new_ustar();
+        header.set_entry_type(kind);
+        header.set_path(path).unwrap();
+        if kind == EntryType::Symlink {
+            header.set_link_name(std::str::from_utf8(data).unwrap()).unwrap();
+        }
+        header.set_size(if kind == EntryType::Symlink {
+            0
+        } else {
+            data.len() as u64
+        });
+        header.set_mode(0o644);
+        header.set_cksum();
+        if kind == EntryType::Symlink {
+            b.append(&header, io::empty()).unwrap();
+        } else {
+            b.append(&header, data).unwrap();
+        }
+    }
+
+    fn lib(machine: Machine, soname: &str, needed: &[&str]) -> Vec<u8> {
+        Stub {
+            needed: needed.iter().map(|n| n.to_string()).collect(),
+            ..Stub::library(machine, soname)
+        }
+        .write()
+        .unwrap()
+    }
+
+    #[test]
+    fn closure() {
+        let mut b = Builder::new(Vec::new());
+        let i386 = |s, n: &[&str]| lib(Machine::I386, s, n);
+        let amd64 = |s, n: &[&str]| lib(Machine::Amd64, s, n);
+        let regular = EntryType::Regular;
+
+        add(&mut b, "lib/libc.so.1", regular, &i386("libc.so.1", &[]));
+        add(&mut b, "lib/amd64/libc.so.1", regular,
+            &amd64("libc.so.1", &[]));
+        add(&mut b, "usr/lib/libm.so.2", regular,
+            &i386("libm.so.2", &["libc.so.1"]));
+        add(&mut b, "usr/lib/amd64/libm.so.2", regular,
+            &amd64("libm.so.2", &["libc.so.1", "libgone.so.1", "libz.so"]));
+        add(&mut b, "usr/lib/libx.so.1", regular,
+            &i386("libx.so.1", &["libgone.so.1", "libm.so.2"]));
+        add(&mut b, "usr/lib/amd64/libgone.so.1", EntryType::Symlink,
+            b"../../../lib/amd64/libc.so.1");
+        add(&mut b, "usr/lib/README", regular, b"not an object");
+        add(&mut b, "usr/lib/libbad.so.1", regular, b"\x7fELF\x02");
+
+        let archive = b.into_inner().unwrap();
+        let report = check(archive.as_slice()).unwrap();
+
+        // The 64-bit libgone.so.1 is a link to libc.so.1, but there is no
+        // 32-bit libgone.so.1.
+        let mut unresolved = BTreeMap::new();
+        unresolved.insert(
+            "libgone.so.1".to_string(),
+            vec!["usr/lib/libx.so.1".to_string()],
+        );
+        unresolved.insert(
+            "libz.so".to_string(),
+            vec!["usr/lib/amd64/libm.so.2".to_string()],
+        );
+        assert_eq!(report.unresolved, unresolved);
+        assert_eq!(report.malformed.len(), 1);
+        assert_eq!(report.malformed[0].0, "usr/lib/libbad.so.1");
+    }
+}
@@ -0,0 +1,305 @@
+// Copyright 2020 Oxide Computer Company
+
+use std::fmt;
+
+use super::{Dir, Entry, File, FsAttr, Link, ParseErrorKind, Set};
+
+/// The action types defined by IPS, with the attributes each requires.
+const ACTIONS: &[(&str, &[&str])] = &[
+    ("depend", &["fmri", "type"]),
+    ("dir", &["path"]),
+    ("driver", &["name"]),
+    ("file", &["path"]),
+    ("group", &["groupname"]),
+    ("hardlink", &["path", "target"]),
+    ("legacy", &["pkg"]),
+    ("license", &["license"]),
+    ("link", &["path", "target"]),
+    ("set", &["name"]),
+    ("signature", &[]),
+    ("unknown", &["path"]),
+    ("user", &["username"]),
+];
+
+/// A manifest action in its generic form: the action name, an optional
+/// payload (e.g., the content hash of a `file` action in a repository
+/// manifest) and the ordered list of attributes.  Attributes may repeat, as
+/// with multi-valued attributes such as `facet` or `alias`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Action {
+    pub kind: String,
+    pub payload: Option<String>,
+    pub attrs: Vec<(String, String)>,
+}
+
+/// Split an action line into whitespace separated fields, honouring single
+/// and double quoted values (e.g., `name="a value"`) and backslash escapes
+/// within them.  Returns `None` if a quote is left unterminated.
+pub(crate) fn split_fields(input: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut chars = input.chars();
+    let mut cur: Option<String> = None;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                let field = cur.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '\\' => field.push(chars.next()?),
+                        q if q == c => break,
+                        o => field.push(o),
+                    }
+                }
+            }
+            c if c.is_whitespace() => {
+                if let Some(field) = cur.take() {
+                    fields.push(field);
+                }
+            }
+            c => cur.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(field) = cur.take() {
+        fields.push(field);
+    }
+
+    Some(fields)
+}
+
+fn quote(value: &str) -> String {
+    if !value.is_empty()
+        && !value.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'')
+    {
+        return value.to_string();
+    }
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+    out
+}
+
+impl Action {
+    pub fn parse(input: &str) -> Option<Action> {
+        let mut fields = split_fields(input)?.into_iter();
+        let kind = fields.next()?;
+        if kind.starts_with('<') || kind.contains('=') {
+            return None;
+        }
+
+        let mut payload = None;
+        let mut attrs = Vec::new();
+        for field in fields {
+            if let Some(idx) = field.find('=') {
+                let (name, value) = field.split_at(idx);
+                attrs.push((name.to_string(), value[1..].to_string()));
+            } else {
+                payload = Some(field);
+            }
+        }
+
+        Some(Action { kind, payload, attrs })
+    }
+
+    /// The first value of the named attribute, if present.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of the named attribute, in order.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.attrs
+            .iter()
+            .filter(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.attrs.iter().any(|(n, _)| n == name)
+    }
+
+    /// Replace all values of the named attribute with a single value.
+    pub fn set(&mut self, name: &str, value: &str) {
+        match self.attrs.iter().position(|(n, _)| n == name) {
+            Some(idx) => {
+                self.attrs.retain(|(n, _)| n != name);
+                self.attrs.insert(idx, (name.to_string(), value.to_string()));
+            }
+            None => self.add(name, value),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, value: &str) {
+        self.attrs.push((name.to_string(), value.to_string()));
+    }
+
+    /// Check that this is a known type of action, with all of the attributes
+    /// required for that type.
+    pub fn check(&self) -> Result<(), ParseErrorKind> {
+        let required = ACTIONS
+            .iter()
+            .find(|(kind, _)| *kind == self.kind)
+            .map(|(_, required)| *required)
+            .ok_or_else(|| ParseErrorKind::UnknownAction(self.kind.clone()))?;
+
+        match required.iter().find(|attr| !self.has(attr)) {
+            Some(attr) => Err(ParseErrorKind::MissingAttribute(
+                self.kind.clone(),
+                attr,
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Convert into the typed `Entry` used for archive population.  Actions
+    /// which are not understood, or which lack required attributes, become
+    /// `Entry::Unknown`.
+    pub fn into_entry(self) -> Entry {
+        let attr = FsAttr {
+            owner: self.get("owner").map(str::to_string),
+            group: self.get("group").map(str::to_string),
+            mode: self.get("mode").map(str::to_string),
+        };
+        let path = self.get("path").map(str::to_string);
+
+        match (self.kind.as_str(), path) {
+            ("dir", Some(path)) => Entry::Dir(Dir { path, attr }),
+            ("file", Some(path)) => Entry::File(File {
+                path,
+                attr,
+                chash: self.get("chash").map(str::to_string),
+                cname: self.payload.clone(),
+            }),
+            ("link", Some(path)) => match self.get("target") {
+                Some(target) => Entry::Link(Link {
+                    path,
+                    attr,
+                    target: target.to_string(),
+                }),
+                None => Entry::Unknown(self.to_string()),
+            },
+            ("set", _) => match self.get("name") {
+                Some(name) => Entry::Set(Set {
+                    name: name.to_string(),
+                    values: self
+                        .get_all("value")
+                        .into_iter()
+                        .map(str::to_string)
+                        .collect(),
+                }),
+                None => Entry::Unknown(self.to_string()),
+            },
+            _ => Entry::Unknown(self.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(payload) = &self.payload {
+            write!(f, " {}", quote(payload))?;
+        }
+        for (name, value) in &self.attrs {
+            write!(f, " {}={}", name, quote(value))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quoted_fields() {
+        assert_eq!(
+            split_fields(r#"set name=pkg.summary value="a \"quoted\" value""#),
+            Some(vec![
+                "set".to_string(),
+                "name=pkg.summary".to_string(),
+                r#"value=a "quoted" value"#.to_string(),
+            ])
+        );
+        assert_eq!(
+            split_fields("a  b='c d'  "),
+            Some(vec!["a".to_string(), "b=c d".to_string()])
+        );
+        assert_eq!(split_fields("unterminated \"quote"), None);
+    }
+
+    #[test]
+    fn action_roundtrip() {
+        let line = "file 1f2e chash=abcd path=usr/lib/libc.so.1 \
+            facet.devel=true facet.devel=all";
+        let action = Action::parse(line).unwrap();
+        assert_eq!(action.kind, "file");
+        assert_eq!(action.payload.as_deref(), Some("1f2e"));
+        assert_eq!(action.get_all("facet.devel"), vec!["true", "all"]);
+        assert_eq!(action.to_string(), line);
+
+        let mut action = action;
+        action.set("facet.devel", "false");
+        assert_eq!(action.get_all("facet.devel"), vec!["false"]);
+
+        let action = Action::parse("set name=pkg.description \
+            value=\"two words\"").unwrap();
+        assert_eq!(
+            action.to_string(),
+            "set name=pkg.description value=\"two words\""
+        );
+    }
+
+    #[test]
+    fn entry_conversion() {
+        let action = Action::parse("file 1f2e chash=abcd path=lib/libc.so.1")
+            .unwrap();
+        assert_eq!(
+            action.into_entry(),
+            Entry::File(File {
+                path: "lib/libc.so.1".to_string(),
+                attr: Default::default(),
+                chash: Some("abcd".to_string()),
+                cname: Some("1f2e".to_string()),
+            })
+        );
+
+        let action = Action::parse("link path=lib/libc.so").unwrap();
+        assert_eq!(
+            action.check(),
+            Err(ParseErrorKind::MissingAttribute("link".to_string(), "target"))
+        );
+        assert_eq!(
+            action.into_entry(),
+            Entry::Unknown("link path=lib/libc.so".to_string())
+        );
+
+        let action =
+            Action::parse("set name=variant.arch value=i386 value=sparc")
+                .unwrap();
+        assert_eq!(action.check(), Ok(()));
+        assert_eq!(
+            action.into_entry(),
+            Entry::Set(Set {
+                name: "variant.arch".to_string(),
+                values: vec!["i386".to_string(), "sparc".to_string()],
+            })
+        );
+
+        let action = Action::parse("flie path=lib/libc.so.1").unwrap();
+        assert_eq!(
+            action.check(),
+            Err(ParseErrorKind::UnknownAction("flie".to_string()))
+        );
+    }
+}
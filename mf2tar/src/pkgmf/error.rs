@@ -0,0 +1,122 @@
+// Copyright 2020 Oxide Computer Company
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// The position of a logical manifest line: the file it was read from and
+/// the line number (starting at 1) of its first physical line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    pub file: PathBuf,
+    pub line: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseErrorKind {
+    /// The action name is not one which IPS defines.
+    UnknownAction(String),
+    /// The action lacks an attribute which is required for its type.
+    MissingAttribute(String, &'static str),
+    /// A quoted value was not terminated before the end of the line.
+    UnterminatedQuote,
+    /// A `<...>` directive other than `<include>` or `<transform>`, or one
+    /// of those which could not be parsed.
+    MalformedDirective,
+    /// A `<transform>` which could not be parsed or applied.
+    Transform(String),
+    /// A `$(NAME)` macro with no definition, when expansion is strict.
+    UndefinedVariable(String),
+}
+
+/// A manifest line which could not be understood.
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub location: Location,
+    pub kind: ParseErrorKind,
+    pub text: String,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse(ParseError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseErrorKind::UnknownAction(kind) => {
+                write!(f, "unknown action \"{}\"", kind)
+            }
+            ParseErrorKind::MissingAttribute(kind, attr) => {
+                write!(f, "{} action missing \"{}\" attribute", kind, attr)
+            }
+            ParseErrorKind::UnterminatedQuote => {
+                write!(f, "unterminated quote")
+            }
+            ParseErrorKind::MalformedDirective => {
+                write!(f, "malformed directive")
+            }
+            ParseErrorKind::Transform(msg) => write!(f, "{}", msg),
+            ParseErrorKind::UndefinedVariable(name) => {
+                write!(f, "undefined variable \"$({})\"", name)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.location, self.kind, self.text)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Parse(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Parse(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            Error::Parse(e) => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
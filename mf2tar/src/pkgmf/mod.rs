@@ -1,19 +1,71 @@
 // Copyright 2020 Oxide Computer Company
 
-pub struct Reader<I, F> {
+//! Reading IPS package manifests.  A `Reader` splits a manifest into logical
+//! lines, expanding `$(NAME)` macros; a `Mogrifier` goes on to follow
+//! `<include>` directives and apply `<transform>`s, as `pkgmogrify(1)` does,
+//! yielding each action as an `Entry`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::PathBuf;
+
+mod action;
+mod error;
+mod mogrify;
+mod transform;
+
+pub use action::Action;
+pub use error::{Error, Location, ParseError, ParseErrorKind, Result};
+pub use mogrify::Mogrifier;
+pub use transform::Transform;
+
+/// The logical lines of a manifest: continuations are joined, comments and
+/// blank lines skipped, and macros expanded.
+pub struct Reader<I> {
     input: I,
-    lookup: F,
+    expansion: Expansion,
+    file: PathBuf,
+    consumed: usize,
+    line: usize,
+    variables: BTreeMap<String, Option<String>>,
 }
 
+/// A `$(NAME)` or `$(NAME:-default)` macro reference in a manifest line.
+#[derive(Debug, PartialEq)]
+pub struct MacroRef<'a> {
+    pub name: &'a str,
+    pub default: Option<&'a str>,
+}
+
+/// How `$(NAME)` macros in manifest lines are expanded.
+#[derive(Clone, Debug)]
+pub enum Expansion {
+    /// Leave `$(...)` text verbatim, as is appropriate for the published
+    /// manifests in a repository.
+    Disabled,
+    /// Replace defined names with their values; undefined names expand to
+    /// nothing.
+    Defined(HashMap<String, String>),
+    /// Replace defined names with their values; an undefined name is an
+    /// error.
+    Strict(HashMap<String, String>),
+}
+
+/// An action, or a directive, of a manifest.  Only the actions which put
+/// something in the file system, and `set` actions, are understood; anything
+/// else is `Unknown`, with the text of the line.
 #[derive(Debug, PartialEq)]
 pub enum Entry {
     Include(String),
+    Transform(Transform),
     Dir(Dir),
     File(File),
     Link(Link),
+    Set(Set),
     Unknown(String),
 }
 
+/// The ownership and mode of something in the file system.
 #[derive(Default, Debug, PartialEq)]
 pub struct FsAttr {
     pub owner: Option<String>,
@@ -27,6 +79,9 @@ pub struct Dir {
     pub attr: FsAttr,
 }
 
+/// A `file` action.  A file in a repository has the hash of its compressed
+/// contents (`chash`) and the name under which they are stored (`cname`, the
+/// hash of the uncompressed contents); one in a proto area has neither.
 #[derive(Debug, PartialEq)]
 pub struct File {
     pub path: String,
@@ -42,65 +97,202 @@ pub struct Link {
     pub target: String,
 }
 
+/// A package attribute (e.g., `pkg.fmri`), which may have several values.
+#[derive(Debug, PartialEq)]
+pub struct Set {
+    pub name: String,
+    pub values: Vec<String>,
+}
+
 impl Entry {
+    /// The path of a directory, file or link.
     pub fn get_path(&self) -> Option<&str> {
         match self {
             Entry::Dir(dir) => Some(&dir.path),
             Entry::File(file) => Some(&file.path),
             Entry::Link(link) => Some(&link.path),
-            _ => None
+            _ => None,
         }
     }
 }
 
-impl<I, F> Reader<I, F>
-where
-    I: Iterator<Item = String>,
-    F: Fn(&str) -> Option<String>,
-{
-    pub fn new(input: I, lookup: F) -> Self {
-        Self { input, lookup }
+impl Expansion {
+    /// Expand the macros in a line, or return the first undefined name if
+    /// operating in `Strict` mode.
+    pub fn expand(&self, line: &str) -> std::result::Result<String, String> {
+        match self {
+            Expansion::Disabled => Ok(line.to_string()),
+            Expansion::Defined(defines) => {
+                Ok(replace_vars(line, |name| defines.get(name).cloned()))
+            }
+            Expansion::Strict(defines) => {
+                // A reference with a default is never undefined
+                if let Some(m) = macro_refs(line).iter().find(|m| {
+                    m.default.is_none() && !defines.contains_key(m.name)
+                }) {
+                    return Err(m.name.to_string());
+                }
+                Ok(replace_vars(line, |name| defines.get(name).cloned()))
+            }
+        }
     }
 }
 
-impl<I, F> Iterator for Reader<I, F>
+impl<I> Reader<I>
 where
-    I: Iterator<Item = String>,
-    F: Fn(&str) -> Option<String>,
+    I: Iterator<Item = io::Result<String>>,
 {
-    type Item = Entry;
-    fn next(&mut self) -> Option<Self::Item> {
-        while let Some(line) = get_full_line(&mut self.input) {
-            let replaced = replace_vars(line.as_str(), &self.lookup);
-            // Replacement of variables may have emptied or commented out the line
-            match replaced.chars().nth(0) {
+    /// Read the lines of `input`, expanding macros per `expansion`.  A line
+    /// which cannot be read (e.g., as it is not UTF-8) is an error.
+    pub fn new(input: I, expansion: Expansion) -> Self {
+        Self {
+            input,
+            expansion,
+            file: PathBuf::new(),
+            consumed: 0,
+            line: 0,
+            variables: BTreeMap::new(),
+        }
+    }
+
+    /// Name the file being read, for use in error locations.
+    pub fn with_file<P: Into<PathBuf>>(mut self, file: P) -> Self {
+        self.file = file.into();
+        self
+    }
+
+    /// The location of the logical line most recently returned by the
+    /// reader.
+    pub fn location(&self) -> Location {
+        Location {
+            file: self.file.clone(),
+            line: self.line,
+        }
+    }
+
+    /// Every macro referenced by the lines read so far, with its default
+    /// value (if one was given in any of the references).
+    pub fn variables(&self) -> &BTreeMap<String, Option<String>> {
+        &self.variables
+    }
+
+    /// Fetch the next logical line, with continuations joined, variables
+    /// replaced and comments skipped, but without parsing it into an `Entry`.
+    pub fn next_line(&mut self) -> Option<Result<String>> {
+        loop {
+            let (start, line) =
+                match get_full_line(&mut self.input, &mut self.consumed) {
+                    Ok(Some(line)) => line,
+                    Ok(None) => return None,
+                    Err(e) => {
+                        // The line which could not be read was not counted.
+                        self.line = self.consumed + 1;
+                        return Some(Err(io::Error::new(e.kind(),
+                            format!("{}: {}", self.location(), e)).into()));
+                    }
+                };
+            self.line = start;
+            for m in macro_refs(&line) {
+                let default = self
+                    .variables
+                    .entry(m.name.to_string())
+                    .or_insert(None);
+                if default.is_none() {
+                    *default = m.default.map(str::to_string);
+                }
+            }
+            let replaced = match self.expansion.expand(&line) {
+                Ok(replaced) => replaced,
+                Err(name) => {
+                    return Some(Err(ParseError {
+                        location: self.location(),
+                        kind: ParseErrorKind::UndefinedVariable(name),
+                        text: line,
+                    }
+                    .into()));
+                }
+            };
+            // Replacement of variables may have emptied or commented out
+            // the line, and a comment may be indented
+            let replaced = replaced.trim();
+            match replaced.chars().next() {
                 Some('#') | None => {
                     continue;
                 }
                 Some(_) => {}
             }
-            return Some(parse_entry(replaced.trim()));
+            return Some(Ok(replaced.to_string()));
         }
-        None
     }
 }
 
-fn get_full_line<I: Iterator<Item = String>>(iter: &mut I) -> Option<String> {
-    let mut line = iter.next()?;
+impl<I> Iterator for Reader<I>
+where
+    I: Iterator<Item = io::Result<String>>,
+{
+    type Item = Result<Entry>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_line().map(|line| line.map(|line| parse_entry(&line)))
+    }
+}
+
+/// Fetch the next logical line, along with the number of the physical line on
+/// which it started.  `consumed` tracks the count of physical lines read so
+/// far, including comments and continuations.
+fn get_full_line<I: Iterator<Item = io::Result<String>>>(
+    iter: &mut I,
+    consumed: &mut usize,
+) -> io::Result<Option<(usize, String)>> {
+    let mut line = match iter.next().transpose()? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    *consumed += 1;
 
     // ignore any commented lines
     while line.starts_with('#') || line.is_empty() {
-        line = iter.next()?;
+        line = match iter.next().transpose()? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        *consumed += 1;
     }
+    let start = *consumed;
 
     // Handle backslash continuation
     while line.ends_with(" \\") {
         line.pop();
-        if let Some(next_line) = iter.next() {
+        if let Some(next_line) = iter.next().transpose()? {
+            *consumed += 1;
             line.push_str(&next_line)
         }
     }
-    Some(line)
+    Ok(Some((start, line)))
+}
+
+/// Find each `$(NAME)` or `$(NAME:-default)` reference in a line.  Defaults
+/// may not themselves contain `$` or `)`.
+pub fn macro_refs(line: &str) -> Vec<MacroRef<'_>> {
+    line.split('$')
+        .skip(1)
+        .filter_map(|sub| match (sub.find('('), sub.find(')')) {
+            (Some(0), Some(x)) => Some(split_macro(&sub[1..x])),
+            _ => None,
+        })
+        .collect()
+}
+
+fn split_macro(content: &str) -> MacroRef<'_> {
+    match content.find(":-") {
+        Some(idx) => MacroRef {
+            name: &content[..idx],
+            default: Some(&content[idx + 2..]),
+        },
+        None => MacroRef {
+            name: content,
+            default: None,
+        },
+    }
 }
 
 fn replace_vars<F>(line: &str, lookup: F) -> String
@@ -116,9 +308,16 @@ where
     for sub in tokens {
         match (sub.find('('), sub.find(')')) {
             (Some(0), Some(x)) => {
-                // Follows $(VAR_NAME) form
-                if let Some(replace) = lookup(&sub[1..x]) {
-                    result.push_str(&replace);
+                // Follows $(VAR_NAME) or $(VAR_NAME:-default) form, where the
+                // default is used if the variable is undefined or empty
+                let m = split_macro(&sub[1..x]);
+                match (lookup(m.name), m.default) {
+                    (Some(replace), Some(default)) if replace.is_empty() => {
+                        result.push_str(default);
+                    }
+                    (Some(replace), _) => result.push_str(&replace),
+                    (None, Some(default)) => result.push_str(default),
+                    (None, None) => {}
                 }
                 if x < sub.len() - 1 {
                     result.push_str(&sub[(x + 1)..]);
@@ -136,72 +335,25 @@ where
 }
 
 // <include system-library.man3ldap.inc>
+// <transform file path=usr/lib/.*\.so$ -> set mode 0555>
 // dir path=lib
 // file path=lib/$(ARCH64)/c_synonyms.so.1
 // link path=lib/$(ARCH64)/libadm.so target=libadm.so.1
 
-fn parse_file_fields(input: &str) -> (Option<String>, Option<String>, FsAttr, Option<String>, Option<String>) {
-    let mut chash: Option<String> = None;
-    let mut cname: Option<String> = None;
-    let mut path: Option<String> = None;
-    let mut target: Option<String> = None;
-    let mut attrs: FsAttr = Default::default();
-
-    // Find all name=value pairs (TODO: handle name="quoted value")
-    let items = input.split_whitespace().filter_map(|x| {
-        if let Some(idx) = x.find('=') {
-            let (name, raw_value) = x.split_at(idx);
-            Some((Some(name), &raw_value[1..]))
-        } else {
-            Some((None, x))
-        }
-    });
-
-    for (name, value) in items {
-        if let Some(field) = match name {
-            Some("owner") => Some(&mut attrs.owner),
-            Some("group") => Some(&mut attrs.group),
-            Some("mode") => Some(&mut attrs.mode),
-            Some("path") => Some(&mut path),
-            Some("target") => Some(&mut target),
-            Some("chash") => Some(&mut chash),
-            None => Some(&mut cname),
-            _ => None,
-        } {
-            *field = Some(value.to_string());
-        }
-    }
-
-    (path, target, attrs, chash, cname)
-}
-
 fn parse_entry(input: &str) -> Entry {
-    let (kind, rest) = input.split_at(input.find(' ').unwrap_or(0));
-    let rest = rest.trim_start();
-
-    match kind {
-        "<include" => {
-            let len = rest.len();
-            if len != 0 && rest.bytes().last().unwrap() == b'>' {
-                return Entry::Include(rest[..len - 1].to_string());
+    if input.starts_with("<include ") {
+        let rest = input["<include".len()..].trim_start();
+        if let Some(name) = rest.strip_suffix('>') {
+            if !name.is_empty() {
+                return Entry::Include(name.to_string());
             }
         }
-        "dir" => {
-            if let (Some(path), _, attr, _, _) = parse_file_fields(rest) {
-                return Entry::Dir(Dir { path, attr });
-            }
-        }
-        "file" => {
-            if let (Some(path), _, attr, chash, cname) = parse_file_fields(rest) {
-                return Entry::File(File { path, attr, chash, cname, });
-            }
+    } else if input.starts_with("<transform ") {
+        if let Ok(t) = Transform::parse(input) {
+            return Entry::Transform(t);
         }
-        "link" => {
-            if let (Some(path), Some(target), attr, _, _) = parse_file_fields(rest) {
-                return Entry::Link(Link { path, attr, target });
-            }
-        }
-        _ => {}
+    } else if let Some(action) = Action::parse(input) {
+        return action.into_entry();
     }
     Entry::Unknown(input.to_string())
 }
@@ -224,13 +376,38 @@ long \
 cont \
 line";
         let br = Cursor::new(input);
-        // Assume (for now) that valid input is utf8 clean
-        let mut iter = br.lines().filter_map(|x| x.ok());
+        let mut iter = br.lines();
+
+        let mut n = 0;
 
-        assert_eq!(get_full_line(&mut iter), Some("normal line".to_string()));
-        assert_eq!(get_full_line(&mut iter), Some("cont line".to_string()));
-        assert_eq!(get_full_line(&mut iter), Some("long cont line".to_string()));
-        assert_eq!(get_full_line(&mut iter), None);
+        assert_eq!(
+            get_full_line(&mut iter, &mut n).unwrap(),
+            Some((3, "normal line".to_string()))
+        );
+        assert_eq!(
+            get_full_line(&mut iter, &mut n).unwrap(),
+            Some((4, "cont line".to_string()))
+        );
+        assert_eq!(
+            get_full_line(&mut iter, &mut n).unwrap(),
+            Some((8, "long cont line".to_string()))
+        );
+        assert_eq!(get_full_line(&mut iter, &mut n).unwrap(), None);
+        assert_eq!(n, 10);
+    }
+
+    #[test]
+    fn blank_and_indented_comments() {
+        let input = "dir path=usr\n   \n\t\n    # note\n\t# note\n\
+            dir path=usr/lib\n";
+        let mut reader = Reader::new(
+            input.lines().map(|l| Ok(l.to_string())),
+            Expansion::Disabled,
+        );
+        let lines: Vec<_> = std::iter::from_fn(|| reader.next_line())
+            .map(|l| l.unwrap())
+            .collect();
+        assert_eq!(lines, vec!["dir path=usr", "dir path=usr/lib"]);
     }
 
     #[test]
@@ -268,6 +445,79 @@ line";
             })
         );
     }
+    #[test]
+    fn var_defaults() {
+        let lookup = |x: &str| match x {
+            "set" => Some("value".to_string()),
+            "empty" => Some(String::new()),
+            _ => None,
+        };
+        let cases = &[
+            ("$(set:-dflt)", "value"),
+            ("$(empty:-dflt)", "dflt"),
+            ("$(unset:-dflt)", "dflt"),
+            ("$(unset:-)x", "x"),
+            ("a/$(unset:-b/c)/d", "a/b/c/d"),
+        ];
+        for (inp, outp) in cases.iter() {
+            assert_eq!(*outp, replace_vars(inp, lookup));
+        }
+
+        assert_eq!(
+            macro_refs("file path=$(A)/$(B:-x) $ (C) $(D"),
+            vec![
+                MacroRef { name: "A", default: None },
+                MacroRef { name: "B", default: Some("x") },
+            ]
+        );
+    }
+
+    #[test]
+    fn expansion_modes() {
+        let mut defines = HashMap::new();
+        defines.insert("ARCH64".to_string(), "amd64".to_string());
+        let line = "file path=lib/$(ARCH64)/$(LIB)";
+
+        assert_eq!(Expansion::Disabled.expand(line), Ok(line.to_string()));
+        assert_eq!(
+            Expansion::Defined(defines.clone()).expand(line),
+            Ok("file path=lib/amd64/".to_string())
+        );
+        assert_eq!(
+            Expansion::Strict(defines.clone()).expand(line),
+            Err("LIB".to_string())
+        );
+        assert_eq!(
+            Expansion::Strict(defines.clone())
+                .expand("file path=lib/$(ARCH64)/$(LIB:-libc.so.1)"),
+            Ok("file path=lib/amd64/libc.so.1".to_string())
+        );
+
+        let input = vec![
+            "# $(LIB) in a comment".to_string(),
+            "dir path=$(ARCH64)".to_string(),
+            "file path=lib/$(ARCH64)/$(LIB)".to_string(),
+        ];
+        let mut reader =
+            Reader::new(input.into_iter().map(Ok), Expansion::Strict(defines))
+                .with_file("test.mf");
+        assert_eq!(reader.next_line().unwrap().unwrap(), "dir path=amd64");
+        match reader.next_line().unwrap() {
+            Err(Error::Parse(e)) => assert_eq!(
+                e.to_string(),
+                "test.mf:3: undefined variable \"$(LIB)\": \
+                file path=lib/$(ARCH64)/$(LIB)"
+            ),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(reader.next_line().is_none());
+
+        let mut expect = BTreeMap::new();
+        expect.insert("ARCH64".to_string(), None);
+        expect.insert("LIB".to_string(), None);
+        assert_eq!(reader.variables(), &expect);
+    }
+
     #[test]
     fn entry_parsing() {
         assert_eq!(
@@ -285,7 +535,9 @@ line";
             parse_entry("file path=bin/ls"),
             Entry::File(File {
                 path: "bin/ls".to_string(),
-                attr: Default::default()
+                attr: Default::default(),
+                chash: None,
+                cname: None,
             })
         );
         assert_eq!(
@@ -296,7 +548,9 @@ line";
                     owner: Some("special".to_string()),
                     group: Some("selective".to_string()),
                     mode: Some("0540".to_string()),
-                }
+                },
+                chash: None,
+                cname: None,
             })
         );
         assert_eq!(
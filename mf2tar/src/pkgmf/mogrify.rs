@@ -0,0 +1,530 @@
+// Copyright 2020 Oxide Computer Company
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use super::action::{split_fields, Action};
+use super::transform::{apply_all, Outcome, Transform};
+use super::{
+    parse_entry, Entry, Expansion, Location, ParseError, ParseErrorKind,
+    Reader, Result,
+};
+
+type Lines = Box<dyn Iterator<Item = io::Result<String>>>;
+
+/// Process manifests in the manner of pkgmogrify(1): `<include>` directives
+/// are expanded in place, and `<transform>` directives are accumulated and
+/// applied, in order, to every action which follows them.  Files are
+/// processed in the order they were added, so transform files should be
+/// added ahead of the manifests they are meant to modify.
+///
+/// Lines which cannot be parsed, and actions which are unknown or lack
+/// required attributes, are returned as `Error::Parse` with the location of
+/// the offending line.  Iteration may continue past such errors.
+pub struct Mogrifier {
+    expansion: Expansion,
+    include_path: Vec<PathBuf>,
+    pending: VecDeque<PathBuf>,
+    // To avoid malicious manifests creating an infinite loop of includes,
+    // track them in a stack.
+    stack: Vec<(PathBuf, Reader<Lines>)>,
+    transforms: Vec<Transform>,
+    emitted: VecDeque<(Location, String)>,
+    variables: BTreeMap<String, Option<String>>,
+    variants: BTreeMap<String, String>,
+    /// Where the entry last returned came from
+    location: Option<Location>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn merge_variables(
+    into: &mut BTreeMap<String, Option<String>>,
+    from: &BTreeMap<String, Option<String>>,
+) {
+    for (name, default) in from {
+        let d = into.entry(name.clone()).or_insert(None);
+        if d.is_none() {
+            *d = default.clone();
+        }
+    }
+}
+
+fn parse_error(
+    location: &Location,
+    kind: ParseErrorKind,
+    text: &str,
+) -> ParseError {
+    ParseError {
+        location: location.clone(),
+        kind,
+        text: text.to_string(),
+    }
+}
+
+impl Mogrifier {
+    /// Create a processor which expands `$(VAR)` macros per `expansion`.
+    pub fn new(expansion: Expansion) -> Self {
+        Mogrifier {
+            expansion,
+            include_path: Vec::new(),
+            pending: VecDeque::new(),
+            stack: Vec::new(),
+            transforms: Vec::new(),
+            emitted: VecDeque::new(),
+            variables: BTreeMap::new(),
+            variants: BTreeMap::new(),
+            location: None,
+        }
+    }
+
+    /// Select the value of a variant (e.g., "arch", for `variant.arch`), so
+    /// that actions tagged for any other value are dropped.  Actions for
+    /// variants which are not selected are all kept.
+    pub fn set_variant(&mut self, name: &str, value: &str) {
+        self.variants.insert(name.to_string(), value.to_string());
+    }
+
+    fn selected(&self, action: &Action) -> bool {
+        self.variants.iter().all(|(name, value)| {
+            let tag = format!("variant.{}", name);
+            let mut values = action
+                .attrs
+                .iter()
+                .filter(|(n, _)| *n == tag)
+                .map(|(_, v)| v)
+                .peekable();
+            values.peek().is_none() || values.any(|v| v == value)
+        })
+    }
+
+    /// Queue a file for processing after those already added.
+    pub fn add_file<P: AsRef<Path>>(&mut self, path: P) {
+        self.pending.push_back(path.as_ref().to_path_buf());
+    }
+
+    /// Append a directory to the search path, as with the `-I` option to
+    /// pkgmogrify.  Directories are searched in the order they were added,
+    /// both for `<include>` directives and for relative paths passed to
+    /// `add_file()` which do not exist as given.
+    pub fn add_include_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        self.include_path.push(dir.as_ref().to_path_buf());
+    }
+
+    /// Every macro referenced by the files processed so far, along with its
+    /// default value if one was given.
+    pub fn variables(&self) -> BTreeMap<String, Option<String>> {
+        let mut vars = self.variables.clone();
+        for (_, reader) in &self.stack {
+            merge_variables(&mut vars, reader.variables());
+        }
+        vars
+    }
+
+    /// The file and line of the entry last returned (for an entry emitted
+    /// by a transform, those of the line transformed).
+    pub fn location(&self) -> Option<&Location> {
+        self.location.as_ref()
+    }
+
+    fn resolve(&self, name: &Path) -> io::Result<PathBuf> {
+        if name.is_absolute() {
+            return Ok(name.to_path_buf());
+        }
+        if let Some(found) = self.include_path.iter()
+            .map(|dir| dir.join(name))
+            .find(|p| p.is_file())
+        {
+            return Ok(found);
+        }
+
+        let tried: Vec<_> = self.include_path.iter()
+            .map(|dir| dir.display().to_string())
+            .collect();
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "{} not found in include path (tried: {})",
+                name.display(),
+                if tried.is_empty() { "none".to_string() } else {
+                    tried.join(", ")
+                }
+            ),
+        ))
+    }
+
+    fn open(&mut self, path: &Path) -> io::Result<()> {
+        let path = path.canonicalize().map_err(|e| {
+            io::Error::new(e.kind(), format!("{}: {}", e, path.display()))
+        })?;
+
+        // Search for a match in the include stack to avoid infinite include
+        // loops
+        if self.stack.iter().any(|(p, _)| p == &path) {
+            return Err(invalid(format!(
+                "infinite include loop through {}",
+                path.display()
+            )));
+        }
+
+        let file = File::open(&path)?;
+        let lines: Lines = Box::new(BufReader::new(file).lines());
+        let reader =
+            Reader::new(lines, self.expansion.clone()).with_file(&path);
+        self.stack.push((path, reader));
+        Ok(())
+    }
+
+    fn parse_action(loc: &Location, line: &str) -> Result<Action> {
+        Action::parse(line).ok_or_else(|| {
+            let kind = match split_fields(line) {
+                None => ParseErrorKind::UnterminatedQuote,
+                Some(fields) => ParseErrorKind::UnknownAction(
+                    fields.first().cloned().unwrap_or_default(),
+                ),
+            };
+            parse_error(loc, kind, line).into()
+        })
+    }
+
+    /// The entry for an action, once transformed, unless it is for another
+    /// value of a selected variant.
+    fn accept(&self, loc: &Location, action: Action) -> Result<Option<Entry>> {
+        if !self.selected(&action) {
+            return Ok(None);
+        }
+        action
+            .check()
+            .map_err(|kind| parse_error(loc, kind, &action.to_string()))?;
+        Ok(Some(action.into_entry()))
+    }
+
+    fn action(&mut self, loc: &Location, line: &str) -> Result<Option<Entry>> {
+        let mut action = Mogrifier::parse_action(loc, line)?;
+
+        let mut emitted = Vec::new();
+        let outcome = apply_all(&self.transforms, &mut action, &mut emitted)
+            .map_err(|e| parse_error(loc, ParseErrorKind::Transform(e), line))?;
+        self.emitted
+            .extend(emitted.into_iter().map(|e| (loc.clone(), e)));
+
+        if outcome == Outcome::Drop {
+            return Ok(None);
+        }
+        self.accept(loc, action)
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        loop {
+            // Lines emitted by transforms are not themselves transformed,
+            // but are otherwise taken as if they were read from the file,
+            // blank lines and comments included.
+            if let Some((loc, line)) = self.emitted.pop_front() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let action = Mogrifier::parse_action(&loc, line)?;
+                match self.accept(&loc, action)? {
+                    Some(entry) => {
+                        self.location = Some(loc);
+                        return Ok(Some(entry));
+                    }
+                    None => continue,
+                }
+            }
+
+            let (loc, line) = match self.stack.last_mut() {
+                Some((_, reader)) => match reader.next_line() {
+                    Some(line) => (reader.location(), line?),
+                    None => {
+                        if let Some((_, reader)) = self.stack.pop() {
+                            merge_variables(
+                                &mut self.variables,
+                                reader.variables(),
+                            );
+                        }
+                        continue;
+                    }
+                },
+                None => match self.pending.pop_front() {
+                    Some(path) => {
+                        let path = if path.exists() {
+                            path
+                        } else {
+                            self.resolve(&path)?
+                        };
+                        self.open(&path)?;
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+            };
+
+            if line.starts_with("<transform") {
+                let t = Transform::parse(&line).map_err(|e| {
+                    parse_error(&loc, ParseErrorKind::Transform(e), &line)
+                })?;
+                self.transforms.push(t);
+            } else if line.starts_with('<') {
+                match parse_entry(&line) {
+                    Entry::Include(name) => {
+                        self.resolve(Path::new(&name))
+                            .and_then(|path| self.open(&path))
+                            .map_err(|e| {
+                                io::Error::new(
+                                    e.kind(),
+                                    format!("{}: {}", loc, e),
+                                )
+                            })?;
+                    }
+                    _ => {
+                        return Err(parse_error(
+                            &loc,
+                            ParseErrorKind::MalformedDirective,
+                            &line,
+                        )
+                        .into());
+                    }
+                }
+            } else if let Some(entry) = self.action(&loc, &line)? {
+                self.location = Some(loc);
+                return Ok(Some(entry));
+            }
+        }
+    }
+}
+
+impl Iterator for Mogrifier {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pkgmf::Error;
+    use crate::scratch::Scratch;
+    use std::fs;
+
+    fn paths(m: Mogrifier) -> Vec<String> {
+        m.map(|e| e.unwrap())
+            .filter_map(|e| e.get_path().map(str::to_string))
+            .collect()
+    }
+
+    #[test]
+    fn transforms_and_includes() {
+        let dir = Scratch::new("mogrify");
+        fs::write(
+            dir.join("defaults"),
+            "<transform file path=usr/share/.* -> drop>\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("lib.inc"),
+            "file path=usr/lib/libc.so.1\nfile path=usr/share/doc\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("test.mf"),
+            "dir path=usr/share\n<include lib.inc>\n\
+            <transform file -> edit path ^usr/ opt/>\n\
+            file path=usr/bin/ls\n",
+        )
+        .unwrap();
+
+        let mut m = Mogrifier::new(Expansion::Disabled);
+        m.add_include_dir(&dir);
+        m.add_file(dir.join("defaults"));
+        m.add_file(dir.join("test.mf"));
+        assert_eq!(
+            paths(m),
+            vec!["usr/share", "usr/lib/libc.so.1", "opt/bin/ls"]
+        );
+    }
+
+    #[test]
+    fn variants() {
+        let dir = Scratch::new("variants");
+        fs::write(
+            dir.join("test.mf"),
+            "file path=usr/lib/libc.so.1\n\
+            file path=usr/lib/i386 variant.arch=i386\n\
+            file path=usr/lib/sparc variant.arch=sparc\n\
+            file path=usr/lib/debug variant.debug.illumos=true\n",
+        )
+        .unwrap();
+
+        let mut m = Mogrifier::new(Expansion::Disabled);
+        m.add_file(dir.join("test.mf"));
+        m.set_variant("arch", "i386");
+        assert_eq!(
+            paths(m),
+            vec!["usr/lib/libc.so.1", "usr/lib/i386", "usr/lib/debug"]
+        );
+    }
+
+    #[test]
+    fn emitted() {
+        let dir = Scratch::new("emitted");
+        fs::write(
+            dir.join("test.mf"),
+            "<transform file path=usr/lib/libc.so.1 -> \
+                emit depend fmri=pkg:/system/library type=require>\n\
+            <transform file path=usr/lib/libc.so.1 -> \
+                emit file path=usr/lib/sparc variant.arch=sparc>\n\
+            <transform file path=usr/lib/libc.so.1 -> \
+                emit link path=usr/lib/libc.so target=libc.so.1>\n\
+            <transform file path=usr/lib/libc.so.1 -> emit>\n\
+            <transform file path=usr/lib/libc.so.1 -> \
+                emit # a comment>\n\
+            file path=usr/lib/libc.so.1\n",
+        )
+        .unwrap();
+
+        let mut m = Mogrifier::new(Expansion::Disabled);
+        m.add_file(dir.join("test.mf"));
+        m.set_variant("arch", "i386");
+        let entries: Vec<_> = m.map(|e| e.unwrap()).collect();
+        assert_eq!(entries.len(), 3);
+        assert!(matches!(&entries[1],
+            Entry::Unknown(action) if action.starts_with("depend ")));
+        assert_eq!(entries[2].get_path(), Some("usr/lib/libc.so"));
+    }
+
+    #[test]
+    fn include_loop() {
+        let dir = Scratch::new("loop");
+        fs::write(dir.join("a.mf"), "<include b.mf>\n").unwrap();
+        fs::write(dir.join("b.mf"), "<include a.mf>\n").unwrap();
+
+        let mut m = Mogrifier::new(Expansion::Disabled);
+        m.add_include_dir(&dir);
+        m.add_file(dir.join("a.mf"));
+        let err = m.find_map(|e| e.err()).unwrap();
+        assert!(err.to_string().contains("infinite include loop"));
+    }
+
+    #[test]
+    fn unreadable_line() {
+        let dir = Scratch::new("unreadable");
+        let mf = dir.join("test.mf");
+        fs::write(&mf, b"dir path=usr\nfile path=usr/\xe9\n\
+            file path=usr/lib/libc.so.1\n").unwrap();
+
+        let mut m = Mogrifier::new(Expansion::Disabled);
+        m.add_file(&mf);
+        assert_eq!(m.next().unwrap().unwrap().get_path(), Some("usr"));
+        let err = match m.next().unwrap() {
+            Err(Error::Io(e)) => e,
+            r => panic!("unexpected {:?}", r),
+        };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with(&format!("{}:2: ",
+            mf.canonicalize().unwrap().display())));
+    }
+
+    #[test]
+    fn include_path() {
+        let dir = Scratch::new("incpath");
+        for sub in &["mf", "first", "second"] {
+            fs::create_dir(dir.join(sub)).unwrap();
+        }
+        fs::write(
+            dir.join("mf/test.mf"),
+            "<include both.inc>\n<include second.inc>\n",
+        )
+        .unwrap();
+        fs::write(dir.join("first/both.inc"), "dir path=first\n").unwrap();
+        fs::write(dir.join("second/both.inc"), "dir path=shadowed\n").unwrap();
+        fs::write(dir.join("second/second.inc"), "dir path=second\n").unwrap();
+
+        let mut m = Mogrifier::new(Expansion::Disabled);
+        m.add_include_dir(dir.join("first"));
+        m.add_include_dir(dir.join("second"));
+        m.add_file(dir.join("mf/test.mf"));
+        assert_eq!(paths(m), vec!["first", "second"]);
+
+        let mut m = Mogrifier::new(Expansion::Disabled);
+        m.add_include_dir(dir.join("mf"));
+        m.add_include_dir(dir.join("first"));
+        m.add_file(dir.join("mf/test.mf"));
+        let err = match m.find_map(|e| e.err()).unwrap() {
+            Error::Io(e) => e,
+            e => panic!("unexpected error: {}", e),
+        };
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{}:2: second.inc not found in include path (tried: {}, {})",
+                dir.join("mf/test.mf").canonicalize().unwrap().display(),
+                dir.join("mf").display(),
+                dir.join("first").display()
+            )
+        );
+    }
+
+    #[test]
+    fn locations() {
+        let dir = Scratch::new("locations");
+        let mf = dir.join("test.mf");
+        fs::write(
+            &mf,
+            "# comment\n\
+            dir path=usr \\\n    owner=root\n\
+            file mode=0555 \\\n    owner=root\n\
+            \n\
+            \x20\x20\x20\n\
+            \x20\x20\x20\x20# indented\n\
+            flie path=usr/lib/libc.so.1\n\
+            link path=\"usr/lib/libc.so\n\
+            <bogus>\n\
+            file path=usr/lib/libc.so.1\n",
+        )
+        .unwrap();
+
+        let mut m = Mogrifier::new(Expansion::Disabled);
+        m.add_file(&mf);
+        let mut results = Vec::new();
+        while let Some(r) = m.next() {
+            results.push(match r {
+                Ok(e) => Ok((m.location().unwrap().line,
+                    e.get_path().unwrap().to_string())),
+                Err(Error::Parse(e)) => Err((e.location.line, e.kind)),
+                Err(Error::Io(e)) => panic!("{}", e),
+            });
+        }
+
+        let mf = mf.canonicalize().unwrap();
+        assert_eq!(
+            results,
+            vec![
+                Ok((2, "usr".to_string())),
+                Err((4, ParseErrorKind::MissingAttribute(
+                    "file".to_string(), "path"))),
+                Err((9, ParseErrorKind::UnknownAction("flie".to_string()))),
+                Err((10, ParseErrorKind::UnterminatedQuote)),
+                Err((11, ParseErrorKind::MalformedDirective)),
+                Ok((12, "usr/lib/libc.so.1".to_string())),
+            ]
+        );
+
+        let mut m = Mogrifier::new(Expansion::Disabled);
+        m.add_file(&mf);
+        let err = m.find_map(|r| r.err()).unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!("{}:4: file action missing \"path\" attribute: \
+                file mode=0555 owner=root", mf.display())
+        );
+    }
+}
@@ -0,0 +1,384 @@
+// Copyright 2020 Oxide Computer Company
+
+use std::fmt;
+
+use regex::{Captures, Regex};
+
+use super::action::{split_fields, Action};
+
+/// A pkgmogrify(1) transform directive, e.g.:
+///
+/// ```text
+/// <transform file path=usr/lib/.*\.so$ -> set mode 0555>
+/// <transform dir path=usr/share/man -> drop>
+/// ```
+///
+/// The (optional) action names and `attribute=regex` matches appear to the
+/// left of the `->`, and the operation to apply to matching actions to the
+/// right.  As with pkgmogrify, each regex is anchored at the start of the
+/// attribute value, but not at the end.
+#[derive(Debug)]
+pub struct Transform {
+    text: String,
+    types: Vec<String>,
+    matches: Vec<(String, Regex)>,
+    op: Operation,
+}
+
+#[derive(Debug)]
+enum Operation {
+    Drop,
+    Set(String, String),
+    Add(String, String),
+    Default(String, String),
+    Delete(String, Regex),
+    Edit(String, Regex, String),
+    Emit(String),
+}
+
+/// The result of applying transforms to an action.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Keep,
+    Drop,
+}
+
+impl PartialEq for Transform {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+    }
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn anchored(re: &str) -> Result<Regex, String> {
+    Regex::new(&format!("^(?:{})", re))
+        .map_err(|e| format!("invalid regex \"{}\": {}", re, e))
+}
+
+/// Convert a Python-style replacement string, as used by pkgmogrify's `edit`
+/// operation (`\1` for the first group), into the `${1}` form used by the
+/// regex crate.
+fn replacement(python: &str) -> String {
+    let mut out = String::with_capacity(python.len());
+    let mut chars = python.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek().is_some_and(char::is_ascii_digit) => {
+                out.push_str("${");
+                while let Some(d) = chars.next_if(char::is_ascii_digit) {
+                    out.push(d);
+                }
+                out.push('}');
+            }
+            '\\' if chars.peek().is_some() => out.push(chars.next().unwrap()),
+            '$' => out.push_str("$$"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+impl Operation {
+    fn parse(input: &str) -> Result<Operation, String> {
+        let input = input.trim();
+        let (name, rest) =
+            input.split_at(input.find(' ').unwrap_or(input.len()));
+        let rest = rest.trim_start();
+
+        if name == "emit" {
+            return Ok(Operation::Emit(rest.to_string()));
+        }
+
+        let args = split_fields(rest)
+            .ok_or_else(|| "unterminated quote in operation".to_string())?;
+        let arg = |n: usize| -> Result<String, String> {
+            args.get(n).cloned().ok_or_else(|| {
+                format!("operation \"{}\" requires {} arguments", name, n + 1)
+            })
+        };
+
+        Ok(match name {
+            "drop" => Operation::Drop,
+            "set" => Operation::Set(arg(0)?, arg(1)?),
+            "add" => Operation::Add(arg(0)?, arg(1)?),
+            "default" => Operation::Default(arg(0)?, arg(1)?),
+            "delete" => Operation::Delete(arg(0)?, anchored(&arg(1)?)?),
+            "edit" => {
+                let re = Regex::new(&arg(1)?)
+                    .map_err(|e| format!("invalid regex: {}", e))?;
+                let repl =
+                    args.get(2).map_or(String::new(), |r| replacement(r));
+                Operation::Edit(arg(0)?, re, repl)
+            }
+            "" => return Err("missing operation".to_string()),
+            other => {
+                return Err(format!("unsupported operation \"{}\"", other));
+            }
+        })
+    }
+}
+
+impl Transform {
+    /// Parse a complete `<transform ... -> ...>` directive.
+    pub fn parse(input: &str) -> Result<Transform, String> {
+        let body = input
+            .trim()
+            .strip_prefix("<transform")
+            .and_then(|b| b.strip_suffix('>'))
+            .ok_or_else(|| "malformed transform directive".to_string())?;
+        let arrow = body
+            .find("->")
+            .ok_or_else(|| "missing -> in transform".to_string())?;
+        let (lhs, rhs) = (&body[..arrow], &body[arrow + 2..]);
+
+        let mut types = Vec::new();
+        let mut matches = Vec::new();
+        for field in split_fields(lhs)
+            .ok_or_else(|| "unterminated quote in transform".to_string())?
+        {
+            if let Some(idx) = field.find('=') {
+                let (name, re) = field.split_at(idx);
+                matches.push((name.to_string(), anchored(&re[1..])?));
+            } else {
+                types.push(field);
+            }
+        }
+
+        Ok(Transform {
+            text: input.trim().to_string(),
+            types,
+            matches,
+            op: Operation::parse(rhs)?,
+        })
+    }
+
+    /// If the action matches, return the captures from each of the attribute
+    /// matches, in order, for use as `%<n>` backreferences.
+    fn matching<'a>(&self, action: &'a Action) -> Option<Vec<Captures<'a>>> {
+        if !self.types.is_empty() && !self.types.contains(&action.kind) {
+            return None;
+        }
+        self.matches
+            .iter()
+            .map(|(name, re)| {
+                action
+                    .attrs
+                    .iter()
+                    .filter(|(n, _)| n == name)
+                    .find_map(|(_, v)| re.captures(v))
+            })
+            .collect()
+    }
+
+    /// Expand `%{attribute}` and `%<n>` references in an operation argument.
+    fn expand(
+        &self,
+        input: &str,
+        action: &Action,
+        caps: &[Captures],
+    ) -> Result<String, String> {
+        let groups: Vec<&str> = caps
+            .iter()
+            .flat_map(|c| {
+                c.iter().skip(1).map(|m| m.map_or("", |m| m.as_str()))
+            })
+            .collect();
+
+        let mut out = String::with_capacity(input.len());
+        let mut rest = input;
+        while let Some(idx) = rest.find('%') {
+            out.push_str(&rest[..idx]);
+            rest = &rest[idx..];
+            let (close, lookup) = match rest.chars().nth(1) {
+                Some('{') => ('}', true),
+                Some('<') => ('>', false),
+                _ => {
+                    out.push('%');
+                    rest = &rest[1..];
+                    continue;
+                }
+            };
+            let end = rest.find(close).ok_or_else(|| {
+                format!("unterminated reference in \"{}\"", input)
+            })?;
+            let name = &rest[2..end];
+            if lookup {
+                let value = action.get(name).ok_or_else(|| {
+                    format!("{}: attribute \"{}\" not found", self, name)
+                })?;
+                out.push_str(value);
+            } else {
+                let n: usize = name.parse().ok().filter(|&n| n > 0)
+                    .ok_or_else(|| {
+                        format!("{}: invalid backreference %<{}>", self, name)
+                    })?;
+                let value = groups.get(n - 1).ok_or_else(|| {
+                    format!("{}: no backreference %<{}>", self, n)
+                })?;
+                out.push_str(value);
+            }
+            rest = &rest[end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    /// Apply this transform to an action, if it matches.  Lines produced by
+    /// an `emit` operation are appended to `emitted`.
+    pub fn apply(
+        &self,
+        action: &mut Action,
+        emitted: &mut Vec<String>,
+    ) -> Result<Outcome, String> {
+        let snapshot = action.clone();
+        let caps = match self.matching(&snapshot) {
+            Some(caps) => caps,
+            None => return Ok(Outcome::Keep),
+        };
+        let expand = |s: &str| self.expand(s, &snapshot, &caps);
+
+        match &self.op {
+            Operation::Drop => return Ok(Outcome::Drop),
+            Operation::Set(name, value) => action.set(name, &expand(value)?),
+            Operation::Add(name, value) => action.add(name, &expand(value)?),
+            Operation::Default(name, value) => {
+                if !action.has(name) {
+                    action.add(name, &expand(value)?);
+                }
+            }
+            Operation::Delete(name, re) => {
+                action.attrs.retain(|(n, v)| n != name || !re.is_match(v));
+            }
+            Operation::Edit(name, re, repl) => {
+                let repl = expand(repl)?;
+                for (n, v) in action.attrs.iter_mut() {
+                    if n == name {
+                        *v = re.replace_all(v, repl.as_str()).into_owned();
+                    }
+                }
+            }
+            Operation::Emit(line) => emitted.push(expand(line)?),
+        }
+        Ok(Outcome::Keep)
+    }
+}
+
+/// Apply each transform, in order, to an action.  Processing stops at the
+/// first transform which drops the action.
+pub fn apply_all(
+    transforms: &[Transform],
+    action: &mut Action,
+    emitted: &mut Vec<String>,
+) -> Result<Outcome, String> {
+    for t in transforms {
+        if t.apply(action, emitted)? == Outcome::Drop {
+            return Ok(Outcome::Drop);
+        }
+    }
+    Ok(Outcome::Keep)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(transforms: &[&str], line: &str) -> (Option<String>, Vec<String>) {
+        let transforms: Vec<_> = transforms
+            .iter()
+            .map(|t| Transform::parse(t).unwrap())
+            .collect();
+        let mut action = Action::parse(line).unwrap();
+        let mut emitted = Vec::new();
+        match apply_all(&transforms, &mut action, &mut emitted).unwrap() {
+            Outcome::Keep => (Some(action.to_string()), emitted),
+            Outcome::Drop => (None, emitted),
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        assert!(Transform::parse("<transform file -> drop>").is_ok());
+        assert!(Transform::parse("<transform file path=a -> set>").is_err());
+        assert!(Transform::parse("<transform path=a set mode 1>").is_err());
+        assert!(Transform::parse("<transform -> frobnicate x>").is_err());
+        assert!(Transform::parse("<transform file path=( -> drop>").is_err());
+    }
+
+    #[test]
+    fn matching() {
+        let t = &["<transform file path=usr/lib/.*\\.so$ -> set mode 0555>"];
+        assert_eq!(
+            run(t, "file path=usr/lib/libc.so mode=0444").0.unwrap(),
+            "file path=usr/lib/libc.so mode=0555"
+        );
+        // Anchored at the start of the value
+        assert_eq!(
+            run(t, "file path=opt/usr/lib/libc.so").0.unwrap(),
+            "file path=opt/usr/lib/libc.so"
+        );
+        // Restricted to the listed action types
+        assert_eq!(
+            run(t, "link path=usr/lib/libc.so target=libc.so.1").0.unwrap(),
+            "link path=usr/lib/libc.so target=libc.so.1"
+        );
+        // No type list means every action
+        assert_eq!(run(&["<transform path=usr/share -> drop>"],
+            "dir path=usr/share/man").0, None);
+    }
+
+    #[test]
+    fn operations() {
+        assert_eq!(
+            run(&["<transform dir -> default mode 0755>"],
+                "dir path=usr").0.unwrap(),
+            "dir path=usr mode=0755"
+        );
+        assert_eq!(
+            run(&["<transform dir -> default mode 0755>"],
+                "dir path=usr mode=0700").0.unwrap(),
+            "dir path=usr mode=0700"
+        );
+        assert_eq!(
+            run(&["<transform file -> add facet.devel true>"],
+                "file path=a").0.unwrap(),
+            "file path=a facet.devel=true"
+        );
+        assert_eq!(
+            run(&["<transform file -> delete facet.devel a.*>"],
+                "file path=a facet.devel=all facet.devel=true").0.unwrap(),
+            "file path=a facet.devel=true"
+        );
+        assert_eq!(
+            run(&["<transform file -> edit path ^usr/(.*)$ \\1/usr>"],
+                "file path=usr/bin").0.unwrap(),
+            "file path=bin/usr"
+        );
+        assert_eq!(
+            run(&["<transform file path=(.*)\\.so\\.1$ -> \
+                emit link path=%<1>.so target=%{path}>"],
+                "file path=lib/libc.so.1"),
+            (
+                Some("file path=lib/libc.so.1".to_string()),
+                vec!["link path=lib/libc.so target=lib/libc.so.1".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn ordering() {
+        let t = &[
+            "<transform file path=usr/lib/.* -> set mode 0555>",
+            "<transform file mode=0555 -> drop>",
+        ];
+        assert_eq!(run(t, "file path=usr/lib/libc.so.1").0, None);
+        assert_eq!(
+            run(t, "file path=usr/bin/ls").0.unwrap(),
+            "file path=usr/bin/ls"
+        );
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright 2020 Oxide Computer Company
+
+//! The plan of a dry run: each entry that would be written to the archive,
+//! where its contents would come from, and each path left out by a rule.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+/// The type of an entry in the archive.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Dir,
+    File,
+    Link,
+}
+
+/// An entry which would be written to the archive.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Item {
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    pub path: String,
+    /// Where the contents of a file would come from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// The target of a link, once made relative
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Plan {
+    /// The tar file which would be written
+    pub output: String,
+    pub entries: Vec<Item>,
+    /// The paths left out by each rule
+    pub excluded: BTreeMap<String, Vec<String>>,
+    /// The rules which decided on no path
+    pub unused: Vec<String>,
+}
+
+impl Plan {
+    pub fn dir(&mut self, path: &str) {
+        self.entries.push(Item {
+            kind: Kind::Dir,
+            path: path.to_string(),
+            source: None,
+            target: None,
+        });
+    }
+
+    pub fn file(&mut self, path: &str, source: String) {
+        self.entries.push(Item {
+            kind: Kind::File,
+            path: path.to_string(),
+            source: Some(source),
+            target: None,
+        });
+    }
+
+    pub fn link(&mut self, path: &str, target: &str) {
+        self.entries.push(Item {
+            kind: Kind::Link,
+            path: path.to_string(),
+            source: None,
+            target: Some(target.to_string()),
+        });
+    }
+
+    /// Note that `rule` left out `path`.
+    pub fn exclude(&mut self, path: &str, rule: &str) {
+        self.excluded
+            .entry(rule.to_string())
+            .or_default()
+            .push(path.to_string());
+    }
+
+    /// The plan as text, in the form in which entries are listed as they are
+    /// archived.
+    pub fn lines(&self) -> Vec<String> {
+        let mut out = Vec::new();
+        for item in &self.entries {
+            out.push(match item.kind {
+                Kind::Dir => format!(" d {}", item.path),
+                Kind::File => format!(" f {} <- {}", item.path,
+                    item.source.as_deref().unwrap_or("")),
+                Kind::Link => format!(" l {} -> {}", item.path,
+                    item.target.as_deref().unwrap_or("")),
+            });
+        }
+        for (rule, paths) in &self.excluded {
+            out.push(format!("EXCLUDED BY {}:", rule));
+            out.extend(paths.iter().map(|p| format!(" {}", p)));
+        }
+        for rule in &self.unused {
+            out.push(format!("UNUSED {}", rule));
+        }
+        out.push(format!("would write {}", self.output));
+        out
+    }
+
+    pub fn json(&self) -> String {
+        let mut out = serde_json::to_string_pretty(self).unwrap();
+        out.push('\n');
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn listing() {
+        let mut plan = Plan {
+            output: "out/sysroot.tar".to_string(),
+            ..Default::default()
+        };
+        plan.dir("usr/include");
+        plan.file("usr/include/a.h", "repository file 1f2e".to_string());
+        plan.link("usr/lib/libz.so", "libz.so.1");
+        plan.exclude("usr/share/man", "--exclude usr/share");
+        plan.exclude("usr/share/doc", "--exclude usr/share");
+        plan.unused.push("--include usr/share/pkgconfig".to_string());
+
+        assert_eq!(
+            plan.lines(),
+            vec![
+                " d usr/include",
+                " f usr/include/a.h <- repository file 1f2e",
+                " l usr/lib/libz.so -> libz.so.1",
+                "EXCLUDED BY --exclude usr/share:",
+                " usr/share/man",
+                " usr/share/doc",
+                "UNUSED --include usr/share/pkgconfig",
+                "would write out/sysroot.tar",
+            ]
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&plan.json()).unwrap();
+        assert_eq!(json["output"], "out/sysroot.tar");
+        assert_eq!(json["entries"][0]["type"], "dir");
+        assert!(json["entries"][0].get("source").is_none());
+        assert_eq!(json["entries"][1]["source"], "repository file 1f2e");
+        assert_eq!(json["entries"][2]["target"], "libz.so.1");
+        assert_eq!(json["excluded"]["--exclude usr/share"][1],
+            "usr/share/doc");
+        assert_eq!(json["unused"][0], "--include usr/share/pkgconfig");
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright 2020 Oxide Computer Company
+
+//! The naming of release archives, `illumos-sysroot-MACH-DATE-COMMIT-VERSION`,
+//! and the `SYSROOT-INFO` file within each archive, which records what it
+//! was made from.  The date, commit and machine are taken from the metadata
+//! of the packages.
+
+use std::collections::BTreeSet;
+
+use serde::Serialize;
+
+use crate::pkgmf::Set;
+
+/// The name of the file in the archive which describes it.
+pub const INFO: &str = "SYSROOT-INFO";
+
+/// The number of digits of the commit in the name of an archive.
+const COMMIT_DIGITS: usize = 12;
+
+/// A release of the sysroot.
+pub struct Release {
+    /// The revision of the contents (e.g., "v1")
+    pub version: String,
+    /// The commit of the base, if not that in the package metadata
+    pub commit: Option<String>,
+}
+
+/// What the packages archived say about the base of the sysroot.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    /// The latest publication timestamp of any package
+    timestamp: Option<String>,
+    commits: BTreeSet<String>,
+    machines: BTreeSet<String>,
+    packages: Vec<String>,
+}
+
+/// The commit in a source URL (e.g., ".../commit/de6af22ae73b..."), if any.
+fn url_commit(url: &str) -> Option<&str> {
+    let last = url
+        .trim_end_matches('/')
+        .rsplit(['/', '#', '=', '@'])
+        .next()?;
+    if (7..=40).contains(&last.len())
+        && last.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        Some(last)
+    } else {
+        None
+    }
+}
+
+/// The single value among those the packages gave for `what`.
+fn single(what: &str, values: &BTreeSet<String>) -> Result<String, String> {
+    match values.len() {
+        0 => Err(format!("no package records the {}", what)),
+        1 => Ok(values.iter().next().unwrap().clone()),
+        _ => Err(format!(
+            "packages record different values for the {}: {}",
+            what,
+            values.iter().cloned().collect::<Vec<_>>().join(", ")
+        )),
+    }
+}
+
+impl Metadata {
+    /// Note a package archived, by its FMRI; the version of a published
+    /// package ends with its timestamp (e.g., ":20181213T232526Z").
+    pub fn package(&mut self, fmri: &str) {
+        let version = fmri.rsplit('@').next().unwrap_or("");
+        if let Some((_, ts)) = version.rsplit_once(':') {
+            if ts.len() >= 8
+                && ts[..8].chars().all(|c| c.is_ascii_digit())
+                && self.timestamp.as_deref().is_none_or(|t| t < ts)
+            {
+                self.timestamp = Some(ts.to_string());
+            }
+        }
+        self.packages.push(fmri.to_string());
+    }
+
+    /// Note a package attribute of an archived package.
+    pub fn set(&mut self, set: &Set) {
+        match set.name.as_str() {
+            "info.source-url" => {
+                self.commits.extend(set.values.iter()
+                    .filter_map(|v| url_commit(v))
+                    .map(String::from));
+            }
+            "variant.arch" if set.values.len() == 1 => {
+                self.machines.insert(set.values[0].clone());
+            }
+            _ => {}
+        }
+    }
+
+    /// The description of the archive, for the `SYSROOT-INFO` file.  The
+    /// machine is that of the `arch` variant selected, if any.
+    pub fn info(&self, arch: Option<&str>, release: Option<&Release>) -> Info {
+        let mut machines = self.machines.clone();
+        if let Some(arch) = arch {
+            machines = std::iter::once(arch.to_string()).collect();
+        }
+        let commit = match release.and_then(|r| r.commit.as_ref()) {
+            Some(commit) => Ok(commit.clone()),
+            None => single("commit", &self.commits),
+        };
+        Info {
+            machine: single("machine", &machines),
+            date: self
+                .timestamp
+                .as_ref()
+                .map(|t| t[..8].to_string())
+                .ok_or_else(|| "no package records a timestamp".to_string()),
+            commit,
+            version: release.map(|r| r.version.clone()),
+            packages: self.packages.clone(),
+            tool: format!("mf2tar {}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
+/// The description of an archive.  Each item of metadata which could not be
+/// determined is the reason why.
+#[derive(Debug)]
+pub struct Info {
+    pub machine: Result<String, String>,
+    pub date: Result<String, String>,
+    pub commit: Result<String, String>,
+    pub version: Option<String>,
+    pub packages: Vec<String>,
+    pub tool: String,
+}
+
+#[derive(Serialize)]
+struct InfoJson<'a> {
+    machine: Option<&'a str>,
+    date: Option<&'a str>,
+    commit: Option<&'a str>,
+    version: Option<&'a str>,
+    packages: &'a [String],
+    tool: &'a str,
+}
+
+impl Info {
+    /// The file name of the release archive (without any ".gz").
+    pub fn file_name(&self) -> Result<String, String> {
+        let version = self
+            .version
+            .as_ref()
+            .ok_or_else(|| "not a release".to_string())?;
+        let commit = self.commit.as_ref()?;
+        let short = commit.get(..COMMIT_DIGITS).unwrap_or(commit);
+        Ok(format!(
+            "illumos-sysroot-{}-{}-{}-{}.tar",
+            self.machine.as_ref()?,
+            self.date.as_ref()?,
+            short,
+            version
+        ))
+    }
+
+    /// The contents of the `SYSROOT-INFO` file.
+    pub fn json(&self) -> String {
+        let info = InfoJson {
+            machine: self.machine.as_deref().ok(),
+            date: self.date.as_deref().ok(),
+            commit: self.commit.as_deref().ok(),
+            version: self.version.as_deref(),
+            packages: &self.packages,
+            tool: &self.tool,
+        };
+        let mut out = serde_json::to_string_pretty(&info).unwrap();
+        out.push('\n');
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn naming() {
+        let set = |name: &str, values: &[&str]| Set {
+            name: name.to_string(),
+            values: values.iter().map(|v| v.to_string()).collect(),
+        };
+        let commit = "de6af22ae73ba8d72672288621ff50b88f2cf5fd";
+        let url = format!("https://github.com/illumos/illumos-gate/commit/{}",
+            commit);
+
+        let mut meta = Metadata::default();
+        meta.package("pkg:/system/header@0.5.11,5.11-2018.0.0.1:\
+            20181213T232526Z");
+        meta.package("pkg:/system/library@0.5.11,5.11-2018.0.0.1:\
+            20181214T000107Z");
+        meta.set(&set("info.source-url", &[&url]));
+        meta.set(&set("info.source-url", &["http://src.illumos.org"]));
+        meta.set(&set("variant.arch", &["i386"]));
+        meta.set(&set("variant.arch", &["i386", "sparc"]));
+
+        let release = Release {
+            version: "v1".to_string(),
+            commit: None,
+        };
+        let info = meta.info(None, Some(&release));
+        assert_eq!(
+            info.file_name().unwrap(),
+            "illumos-sysroot-i386-20181214-de6af22ae73b-v1.tar"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&info.json()).unwrap();
+        assert_eq!(json["commit"], commit);
+        assert_eq!(json["packages"].as_array().unwrap().len(), 2);
+
+        // Without a release, there is no name, but there is still the info.
+        let info = meta.info(None, None);
+        assert_eq!(info.file_name().unwrap_err(), "not a release");
+        assert_eq!(info.version, None);
+
+        meta.set(&set("info.source-url", &["https://x/tree/0123456789ab"]));
+        let info = meta.info(Some("amd64"), Some(&release));
+        assert_eq!(
+            info.file_name().unwrap_err(),
+            format!("packages record different values for the commit: \
+                0123456789ab, {}", commit)
+        );
+        assert_eq!(info.machine.as_deref(), Ok("amd64"));
+        let json: serde_json::Value =
+            serde_json::from_str(&info.json()).unwrap();
+        assert!(json["commit"].is_null());
+
+        let release = Release {
+            version: "v2".to_string(),
+            commit: Some(commit.to_string()),
+        };
+        assert_eq!(
+            meta.info(None, Some(&release)).file_name().unwrap(),
+            "illumos-sysroot-i386-20181214-de6af22ae73b-v2.tar"
+        );
+    }
+}
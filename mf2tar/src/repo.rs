@@ -1,25 +1,99 @@
+//! Reading a file-based IPS repository: the packages it offers, the
+//! manifest of each version, and the files they deliver.
+
 use digest::Digest;
 use flate2::read::GzDecoder;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs::{metadata, read_dir, File};
-use std::io::{BufRead, BufReader, Read};
+use std::io::{self, BufReader, Read};
 use std::path::{Path, PathBuf};
 
 use super::pkgmf;
 
 use pkgmf::Entry;
 
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+#[derive(Debug)]
+pub enum Error {
+    /// A file or directory of the repository could not be read.
+    Io(PathBuf, io::Error),
+    /// The directory lacks the `file` or `pkg` directory of a repository.
+    NotRepository(PathBuf),
+    /// A package or version whose name is not UTF-8, once decoded.
+    Name(PathBuf),
+    /// A package which the repository does not offer as asked.
+    Package(String),
+    /// A file whose contents do not have the hash by which they are named.
+    Hash {
+        file: PathBuf,
+        expected: String,
+        actual: String,
+    },
+    /// A manifest which could not be read or understood.
+    Manifest(pkgmf::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
 
-fn read_file<P: AsRef<Path>>(p: P) -> Result<Vec<u8>> {
-    let f = File::open(p.as_ref())?;
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::NotRepository(path) => {
+                write!(f, "{} is not a directory", path.display())
+            }
+            Error::Name(path) => {
+                write!(f, "{}: name is not valid UTF-8", path.display())
+            }
+            Error::Package(msg) => write!(f, "{}", msg),
+            Error::Hash { file, expected, actual } => {
+                write!(f, "{}: hash mismatch: {} != expected {}",
+                    file.display(), actual, expected)
+            }
+            Error::Manifest(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(_, e) => Some(e),
+            Error::Manifest(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<pkgmf::Error> for Error {
+    fn from(e: pkgmf::Error) -> Self {
+        Error::Manifest(e)
+    }
+}
+
+/// Attach the path to an I/O error.
+fn at<T>(path: &Path, res: io::Result<T>) -> Result<T> {
+    res.map_err(|e| Error::Io(path.to_path_buf(), e))
+}
+
+fn read_file(p: &Path) -> Result<Vec<u8>> {
+    let f = at(p, File::open(p))?;
     let mut r = BufReader::new(f);
     let mut buf = Vec::new();
-    r.read_to_end(&mut buf)?;
+    at(p, r.read_to_end(&mut buf))?;
     Ok(buf)
 }
 
-fn hash_buf(buf: &[u8]) -> String {
+/// A name in the repository, decoded.
+fn decode(path: &Path, name: &str) -> Result<String> {
+    percent_encoding::percent_decode_str(name)
+        .decode_utf8()
+        .map(|n| n.to_string())
+        .map_err(|_| Error::Name(path.to_path_buf()))
+}
+
+/// The SHA-1 hash of the data, in hex, as IPS uses to name content.
+pub fn hash_buf(buf: &[u8]) -> String {
     let mut digest = sha1::Sha1::new();
     digest.input(buf);
     let mut out = String::new();
@@ -29,118 +103,141 @@ fn hash_buf(buf: &[u8]) -> String {
     out
 }
 
+/// A file-based IPS repository, as `pkgrecv(1)` or a build writes.
 #[derive(Debug)]
 pub struct Repository {
     file: PathBuf,
     pkg: PathBuf,
 }
 
+/// A published version of a package.
 #[derive(Debug)]
-pub struct Version<'a> {
-    version: String,
+pub struct Version {
+    pub version: String,
     file: PathBuf,
-    repo: &'a Repository,
 }
 
-impl Version<'_> {
-    pub fn manifest(&self) -> Result<Box<dyn Iterator<Item = Entry>>> {
-        let f = File::open(&self.file)?;
-        let data = BufReader::new(f).lines().filter_map(|x| x.ok());
-        fn replace(name: &str) -> Option<String> {
-            panic!("unexpected expansion in repository manifest: {}", name);
+impl Version {
+    /// The actions of the manifest, leaving out those for any other value of
+    /// the given variants.
+    pub fn manifest(
+        &self,
+        variants: &BTreeMap<String, String>,
+    ) -> Result<Box<dyn Iterator<Item = pkgmf::Result<Entry>>>> {
+        // Check that the manifest can be opened before handing it off
+        at(&self.file, File::open(&self.file))?;
+        // Published manifests have already had any macros expanded, so
+        // anything which looks like one is literal text.
+        let mut mogrifier = pkgmf::Mogrifier::new(pkgmf::Expansion::Disabled);
+        for (name, value) in variants {
+            mogrifier.set_variant(name, value);
         }
-        Ok(Box::new(pkgmf::Reader::new(data, replace)))
+        mogrifier.add_file(&self.file);
+        Ok(Box::new(mogrifier))
     }
 }
 
+/// A package, with each version of it in the repository.
 #[derive(Debug)]
-pub struct Package<'a> {
+pub struct Package {
     pub name: String,
-    pub versions: Vec<Version<'a>>,
+    pub versions: Vec<Version>,
+}
+
+impl Package {
+    /// The FMRI of a version of the package.
+    pub fn fmri(&self, version: &Version) -> String {
+        format!("pkg:/{}@{}", self.name, version.version)
+    }
 }
 
 impl Repository {
+    /// The contents of a file, by its name (the hash of its contents) and the
+    /// hash of the compressed file in which they are stored; both hashes are
+    /// checked.
     pub fn file(&self, cname: &str, chash: &str) -> Result<Vec<u8>> {
         let mut p = self.file.clone();
         p.push(&cname[0..=1]);
-        p.push(&cname);
+        p.push(cname);
 
         let buf = read_file(&p)?;
 
         let outer_hash = hash_buf(&buf);
-        if &outer_hash != chash {
-            return Err(format!("hash mismatch: {} != expected {}", outer_hash,
-                chash).into());
+        if outer_hash != chash {
+            return Err(Error::Hash {
+                file: p,
+                expected: chash.to_string(),
+                actual: outer_hash,
+            });
         }
 
         let mut gunzip = GzDecoder::new(buf.as_slice());
         let mut rawbuf: Vec<u8> = Vec::new();
-        gunzip.read_to_end(&mut rawbuf)?;
+        at(&p, gunzip.read_to_end(&mut rawbuf))?;
         let inner_hash = hash_buf(&rawbuf);
-        if &inner_hash != cname {
-            return Err(format!("hash mismatch: {} != expected {}", inner_hash,
-                cname).into());
+        if inner_hash != cname {
+            return Err(Error::Hash {
+                file: p,
+                expected: cname.to_string(),
+                actual: inner_hash,
+            });
         }
 
         Ok(rawbuf)
     }
 
+    /// Open the repository at `path`, which must have the `file` and `pkg`
+    /// directories of one.
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Repository> {
         let root = path.as_ref().to_path_buf();
 
         let mut file = root.clone();
         file.push("file");
-        if !metadata(&file)?.is_dir() {
-            return Err(format!("{} is not a directory", file.display()).into());
+        if !at(&file, metadata(&file))?.is_dir() {
+            return Err(Error::NotRepository(file));
         }
 
-        let mut pkg = root.clone();
+        let mut pkg = root;
         pkg.push("pkg");
-        if !metadata(&pkg)?.is_dir() {
-            return Err(format!("{} is not a directory", pkg.display()).into());
+        if !at(&pkg, metadata(&pkg))?.is_dir() {
+            return Err(Error::NotRepository(pkg));
         }
 
         Ok(Repository { file, pkg })
     }
 
+    /// Every package in the repository, by name.
     pub fn scan(&self) -> Result<HashMap<String, Package>> {
         let mut pkgs = HashMap::new();
 
-        for p in read_dir(&self.pkg)? {
-            let p = p?;
+        for p in at(&self.pkg, read_dir(&self.pkg))? {
+            let p = at(&self.pkg, p)?;
 
-            if !p.file_type()?.is_dir() {
+            if !at(&p.path(), p.file_type())?.is_dir() {
                 continue;
             }
 
             let name = if let Some(name) = p.file_name().to_str() {
-                percent_encoding::percent_decode_str(&name)
-                    .decode_utf8()?
-                    .to_string()
+                decode(&p.path(), name)?
             } else {
                 continue;
             };
 
             let mut versions = Vec::new();
 
-            for file in read_dir(&p.path())? {
-                let file = file?;
+            let dir = p.path();
+            for file in at(&dir, read_dir(&dir))? {
+                let file = at(&dir, file)?;
 
                 let version = if let Some(name) = file.file_name().to_str() {
-                    percent_encoding::percent_decode_str(&name)
-                        .decode_utf8()?
-                        .to_string()
+                    decode(&file.path(), name)?
                 } else {
                     continue;
                 };
 
                 let file = file.path();
 
-                versions.push(Version {
-                    version,
-                    file,
-                    repo: &self,
-                });
+                versions.push(Version { version, file });
             }
 
             pkgs.insert(name.clone(), Package { name, versions });
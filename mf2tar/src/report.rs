@@ -0,0 +1,261 @@
+// Copyright 2020 Oxide Computer Company
+
+//! What `mf2tar build` reports as it goes: each entry archived, each path
+//! excluded, warnings and errors.  As text, warnings and errors go to
+//! standard error, entries are only listed if verbose, and standard output
+//! ends with a summary; as JSON, every event is one object, on a line of its
+//! own, on standard output.  A program using the library may instead have
+//! each event passed to a callback.
+
+use std::fmt;
+
+use serde::Serialize;
+
+pub use crate::plan::Kind;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event<'a> {
+    /// An entry archived
+    Entry {
+        #[serde(rename = "type")]
+        kind: Kind,
+        path: &'a str,
+        /// The package which delivered it, if any
+        #[serde(skip_serializing_if = "Option::is_none")]
+        package: Option<&'a str>,
+        /// Where the contents of a file came from
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bytes: Option<u64>,
+        /// What was changed on the way in (e.g., sections stripped)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        note: Option<&'a str>,
+    },
+    /// A path left out by a rule
+    Excluded {
+        path: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        package: Option<&'a str>,
+        rule: String,
+    },
+    Progress { message: &'a str },
+    Warning { message: &'a str },
+    Error { message: &'a str },
+    /// A file written
+    Wrote { path: &'a str },
+    Summary(&'a Summary),
+}
+
+/// A callback to which each event is passed.
+pub type Sink = Box<dyn FnMut(&Event)>;
+
+/// Where the events of a build go, and the count of its warnings.  By
+/// default, reports are quiet text.
+#[derive(Default)]
+pub struct Reporter {
+    pub format: Format,
+    /// List each entry (and progress), as text
+    pub verbose: bool,
+    warnings: usize,
+    /// Where every event goes instead, if anywhere
+    sink: Option<Sink>,
+}
+
+impl Reporter {
+    pub fn new(format: Format, verbose: bool) -> Reporter {
+        Reporter {
+            format,
+            verbose,
+            ..Default::default()
+        }
+    }
+
+    /// Pass every event to `sink`, rather than writing it out.
+    pub fn with_sink<F>(mut self, sink: F) -> Reporter
+    where
+        F: FnMut(&Event) + 'static,
+    {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    /// The number of warnings reported so far.
+    pub fn warnings(&self) -> usize {
+        self.warnings
+    }
+
+    /// Report an event.
+    pub fn emit(&mut self, event: Event) {
+        if let Event::Warning { .. } = event {
+            self.warnings += 1;
+        }
+        if let Some(sink) = &mut self.sink {
+            sink(&event);
+            return;
+        }
+        if self.format == Format::Json {
+            println!("{}", serde_json::to_string(&event).unwrap());
+            return;
+        }
+
+        let verbose = self.verbose;
+        match event {
+            Event::Entry { kind, path, target, note, .. } if verbose => {
+                let note = note.map(|n| format!(" ({})", n))
+                    .unwrap_or_default();
+                match kind {
+                    Kind::Dir => println!(" d {}", path),
+                    Kind::File => println!(" f {}{}", path, note),
+                    Kind::Link => {
+                        println!(" l {} -> {}{}", path, target.unwrap_or(""),
+                            note)
+                    }
+                }
+            }
+            Event::Progress { message } if verbose => println!("{}", message),
+            Event::Warning { message } => eprintln!("WARNING: {}", message),
+            Event::Error { message } => eprintln!("ERROR: {}", message),
+            Event::Wrote { path } => println!("wrote {}", path),
+            Event::Summary(summary) => println!("{}", summary),
+            _ => {}
+        }
+    }
+
+    /// Report a problem which is an error if `strict`, and otherwise a
+    /// warning.
+    pub fn problem(&mut self, strict: bool, message: &str) {
+        self.emit(if strict {
+            Event::Error { message }
+        } else {
+            Event::Warning { message }
+        });
+    }
+}
+
+/// Report a warning to a `Reporter`, e.g., `warning!(report, "{}", e)`.
+/// These macros are for the modules of this crate; a program using it calls
+/// `Reporter::emit`.
+macro_rules! warning {
+    ($report:expr, $($arg:tt)*) => {
+        $report.emit($crate::report::Event::Warning {
+            message: &format!($($arg)*),
+        })
+    };
+}
+
+/// Report an error to a `Reporter`.
+macro_rules! error {
+    ($report:expr, $($arg:tt)*) => {
+        $report.emit($crate::report::Event::Error {
+            message: &format!($($arg)*),
+        })
+    };
+}
+
+/// The counts of what went into an archive.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Summary {
+    pub dirs: usize,
+    pub files: usize,
+    pub links: usize,
+    /// The size of the files archived
+    pub bytes: u64,
+    pub excluded: usize,
+    pub warnings: usize,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "archived {} directories, {} files ({} bytes) and {} \
+            links; excluded {} paths; {} warnings", self.dirs, self.files,
+            self.bytes, self.links, self.excluded, self.warnings)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn events() {
+        let event = Event::Entry {
+            kind: Kind::File,
+            path: "usr/lib/libc.so.1",
+            package: Some("system/library"),
+            source: Some("repository file 1f2e".to_string()),
+            target: None,
+            bytes: Some(1024),
+            note: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            "{\"event\":\"entry\",\"type\":\"file\",\
+                \"path\":\"usr/lib/libc.so.1\",\
+                \"package\":\"system/library\",\
+                \"source\":\"repository file 1f2e\",\"bytes\":1024}"
+        );
+
+        let summary = Summary {
+            dirs: 2,
+            files: 3,
+            bytes: 1024,
+            ..Default::default()
+        };
+        let json: serde_json::Value = serde_json::from_str(
+            &serde_json::to_string(&Event::Summary(&summary)).unwrap())
+            .unwrap();
+        assert_eq!(json["event"], "summary");
+        assert_eq!(json["files"], 3);
+        assert_eq!(
+            summary.to_string(),
+            "archived 2 directories, 3 files (1024 bytes) and 0 links; \
+                excluded 0 paths; 0 warnings"
+        );
+    }
+
+    #[test]
+    fn reporters() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&seen);
+        let mut report = Reporter::default().with_sink(move |event| {
+            sink.borrow_mut().push(serde_json::to_string(event).unwrap());
+        });
+        warning!(report, "{} matched no path", "exclude usr/share");
+        report.problem(true, "link a -> b leads out of the sysroot");
+        report.problem(false, "libc.so.1 not found in archive");
+        assert_eq!(report.warnings(), 2);
+        assert_eq!(seen.borrow().len(), 3);
+        assert_eq!(
+            seen.borrow()[0],
+            "{\"event\":\"warning\",\
+                \"message\":\"exclude usr/share matched no path\"}"
+        );
+
+        // Each reporter keeps its own count.
+        assert_eq!(Reporter::default().warnings(), 0);
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright 2020 Oxide Computer Company
+
+//! The rules which decide which paths from the packages go into the archive.
+//! Each rule includes or excludes the paths its pattern matches; the last rule
+//! which matches a path decides, and a path no rule matches is included.
+//!
+//! A pattern is one of:
+//!
+//! - `re:REGEX`, a regular expression which must match the whole path;
+//! - a glob (with `*`, `?` or `**`, as for `vfs::glob`), e.g., `**/*.a`;
+//! - otherwise, a path, which matches itself and everything beneath it
+//!   (`usr/lib/libc` matches neither `usr/lib/libc.so.1` nor
+//!   `usr/lib/libcurses.so`).
+
+use std::cell::Cell;
+use std::fmt;
+
+use regex::Regex;
+
+use crate::vfs;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Include,
+    Exclude,
+}
+
+#[derive(Debug)]
+enum Pattern {
+    Path(String),
+    Glob(String),
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+pub struct Rule {
+    pub action: Action,
+    /// The pattern, as given
+    text: String,
+    pattern: Pattern,
+    /// Whether the rule has decided on any path
+    used: Cell<bool>,
+}
+
+impl Rule {
+    pub fn new(action: Action, text: &str) -> Result<Rule, String> {
+        let pattern = if let Some(re) = text.strip_prefix("re:") {
+            Pattern::Regex(Regex::new(&format!("^(?:{})$", re))
+                .map_err(|e| format!("pattern \"{}\": {}", text, e))?)
+        } else if text.is_empty() || text.starts_with('/') {
+            return Err(format!("pattern \"{}\" must be a relative path in \
+                the sysroot", text));
+        } else if text.contains(['*', '?']) {
+            Pattern::Glob(text.to_string())
+        } else {
+            Pattern::Path(vfs::normalize(text))
+        };
+        Ok(Rule {
+            action,
+            text: text.to_string(),
+            pattern,
+            used: Cell::new(false),
+        })
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        match &self.pattern {
+            Pattern::Path(p) => match path.strip_prefix(p.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                None => false,
+            },
+            Pattern::Glob(g) => vfs::glob(g, path),
+            Pattern::Regex(re) => re.is_match(path),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.action {
+            Action::Include => "include",
+            Action::Exclude => "exclude",
+        };
+        write!(f, "--{} {}", action, self.text)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    pub fn push(&mut self, rule: Rule) {
+        self.0.push(rule);
+    }
+
+    /// The last rule which matches `path`, if any.
+    fn decide(&self, path: &str) -> Option<&Rule> {
+        self.0.iter().rev().find(|r| r.matches(path))
+    }
+
+    /// The rule which excludes `path`, if it is excluded.  The rule which
+    /// decided, either way, is noted as used.
+    pub fn excluded(&self, path: &str) -> Option<&Rule> {
+        let rule = self.decide(path)?;
+        rule.used.set(true);
+        Some(rule).filter(|r| r.action == Action::Exclude)
+    }
+
+    /// As `excluded`, for a path which is only being explained, without
+    /// noting the rule as used.
+    pub fn explain(&self, path: &str) -> Option<&Rule> {
+        self.decide(path).filter(|r| r.action == Action::Exclude)
+    }
+
+    /// The rules which have not decided on any path.
+    pub fn unused(&self) -> impl Iterator<Item = &Rule> {
+        self.0.iter().filter(|r| !r.used.get())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rules(list: &[(Action, &str)]) -> Rules {
+        let mut rules = Rules::default();
+        for (action, text) in list {
+            rules.push(Rule::new(*action, text).unwrap());
+        }
+        rules
+    }
+
+    #[test]
+    fn last_match() {
+        use Action::*;
+        let rules = rules(&[
+            (Exclude, "usr/share"),
+            (Include, "usr/share/pkgconfig/"),
+            (Exclude, "usr/lib/libc"),
+            (Exclude, "**/*.a"),
+            (Include, "re:usr/lib/lib(ssp|gcc_s)\\.a"),
+            (Exclude, "opt"),
+        ]);
+        let excluded = |path| rules.excluded(path).map(|r| r.to_string());
+
+        assert_eq!(excluded("usr/share").unwrap(), "--exclude usr/share");
+        assert!(excluded("usr/share/man/man1/ls.1").is_some());
+        assert!(excluded("usr/share/pkgconfig").is_none());
+        assert!(excluded("usr/share/pkgconfig/zlib.pc").is_none());
+        assert!(excluded("usr/lib/libc/libc.h").is_some());
+        assert!(excluded("usr/lib/libcurses.so").is_none());
+        assert!(excluded("usr/lib/libc.so.1").is_none());
+        assert_eq!(excluded("usr/lib/amd64/libm.a").unwrap(),
+            "--exclude **/*.a");
+        assert!(excluded("usr/lib/libssp.a").is_none());
+        assert!(excluded("usr/lib/amd64/libssp.a").is_some());
+
+        assert_eq!(
+            rules.unused().map(|r| r.to_string()).collect::<Vec<_>>(),
+            vec!["--exclude opt"]
+        );
+        assert!(rules.explain("opt/x").is_some());
+        assert_eq!(rules.unused().count(), 1);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Rule::new(Action::Exclude, "/usr").is_err());
+        assert!(Rule::new(Action::Exclude, "").is_err());
+        assert!(Rule::new(Action::Include, "re:usr/(lib").is_err());
+        assert!(Rule::new(Action::Include, "re:/usr").is_ok());
+    }
+}
@@ -0,0 +1,41 @@
+// Copyright 2020 Oxide Computer Company
+
+//! A directory for a test to write files in, which is removed when the test
+//! is done with it (even if the test fails).
+
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    /// Create an empty directory, named for the test and the process.
+    pub fn new(name: &str) -> Scratch {
+        let dir = std::env::temp_dir()
+            .join(format!("mf2tar-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Scratch(dir)
+    }
+}
+
+impl Deref for Scratch {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for Scratch {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
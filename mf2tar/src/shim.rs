@@ -0,0 +1,207 @@
+// Copyright 2020 Oxide Computer Company
+
+//! Shim libraries: stand-ins for libraries which come from outside
+//! illumos-gate, but which programs for illumos expect to link against.  A
+//! shim carries the versions and symbols listed in its mapfile, and nothing
+//! else.
+
+use std::path::Path;
+
+use crate::elf::{Machine, Stub, StubSymbol, SymbolKind, VersionDef};
+use crate::mapfile::{self, Mapfile, Scope, Size, SymbolType};
+
+pub struct Shim {
+    pub machine: Machine,
+    pub soname: String,
+    pub defines: Vec<String>,
+    /// The symbols which are data the size of an address (as the C source of
+    /// the shims defines them), where the mapfile gives no type
+    pub data: Vec<String>,
+}
+
+impl Shim {
+    /// A shim for the library at `path`.  Without explicit options, the
+    /// SONAME is the file name, and 64-bit objects are those under an
+    /// "amd64" directory.
+    pub fn for_path(
+        path: &str,
+        soname: Option<String>,
+        machine: Option<Machine>,
+        defines: Vec<String>,
+        data: Vec<String>,
+    ) -> Shim {
+        let soname = soname.unwrap_or_else(|| {
+            path.rsplit('/').next().unwrap().to_string()
+        });
+        let machine = machine.unwrap_or_else(|| {
+            if path.split('/').any(|c| c == "amd64") {
+                Machine::Amd64
+            } else {
+                Machine::I386
+            }
+        });
+
+        Shim {
+            machine,
+            soname,
+            defines,
+            data,
+        }
+    }
+
+    /// Read the mapfile at `path`, with the names the link-editor would
+    /// predefine for the machine in addition to our own, and our data
+    /// symbols.
+    pub fn mapfile(&self, path: &Path) -> Result<Mapfile, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut defines = mapfile::predefined(self.machine.is_64());
+        defines.extend(self.defines.iter().map(String::as_str));
+        let mut mapfile = Mapfile::parse(&text, &defines)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        self.add_data(&mut mapfile)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(mapfile)
+    }
+
+    /// Make each of our data symbols in `mapfile` the size of an address,
+    /// unless the mapfile says otherwise.
+    fn add_data(&self, mapfile: &mut Mapfile) -> Result<(), String> {
+        for name in &self.data {
+            let syms = mapfile.versions.iter_mut()
+                .flat_map(|v| v.symbols.iter_mut())
+                .filter(|s| &s.name == name);
+            let mut found = false;
+            for sym in syms {
+                found = true;
+                if sym.kind.is_none() {
+                    sym.kind = Some(SymbolType::Data);
+                }
+                if sym.size.is_none() {
+                    sym.size = Some(Size::AddrSize(1));
+                }
+            }
+            if !found {
+                return Err(format!("data symbol {} is not in the mapfile",
+                    name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Build the stub object for the mapfile at `path`.
+    pub fn build(&self, path: &Path) -> Result<Vec<u8>, String> {
+        self.stub(&self.mapfile(path)?)?.write()
+    }
+
+    fn stub(&self, mapfile: &Mapfile) -> Result<Stub, String> {
+        let addrsize = self.machine.addrsize();
+        let mut versions = Vec::new();
+        let mut symbols = Vec::new();
+
+        for v in &mapfile.versions {
+            if let Some(name) = &v.name {
+                versions.push(VersionDef {
+                    name: name.clone(),
+                    parents: v.parents.clone(),
+                });
+            }
+            for sym in &v.symbols {
+                if sym.scope == Scope::Local || sym.name == "*" {
+                    continue;
+                }
+                let (kind, size) = match sym.kind {
+                    None | Some(SymbolType::Function) => {
+                        (SymbolKind::Function, 0)
+                    }
+                    Some(SymbolType::Data) | Some(SymbolType::Common) => {
+                        let size = sym.size.ok_or_else(|| {
+                            format!("data symbol {} has no SIZE", sym.name)
+                        })?;
+                        (SymbolKind::Data, size.bytes(addrsize))
+                    }
+                };
+                symbols.push(StubSymbol {
+                    name: sym.name.clone(),
+                    kind,
+                    size,
+                    version: v.name.clone(),
+                    weak: false,
+                });
+            }
+        }
+
+        Ok(Stub {
+            machine: self.machine,
+            soname: self.soname.clone(),
+            needed: Vec::new(),
+            runpath: None,
+            versions,
+            symbols,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn symbols() {
+        let parse = || Mapfile::parse(
+            "$mapfile_version 2
+            SYMBOL_VERSION A_1 {
+                global:
+                    f;
+                    d { ASSERT = { TYPE = DATA; SIZE = addrsize[2] } };
+                    v;
+                local:
+                    *;
+            };
+            SYMBOL_VERSION A_2 { global: g; } A_1;
+            ",
+            &[],
+        )
+        .unwrap();
+        let mut shim = Shim {
+            machine: Machine::I386,
+            soname: "liba.so.1".to_string(),
+            defines: Vec::new(),
+            data: vec!["v".to_string()],
+        };
+        let mut mapfile = parse();
+        shim.add_data(&mut mapfile).unwrap();
+        let stub = shim.stub(&mapfile).unwrap();
+
+        assert_eq!(
+            stub.versions,
+            vec![
+                VersionDef {
+                    name: "A_1".to_string(),
+                    parents: vec![],
+                },
+                VersionDef {
+                    name: "A_2".to_string(),
+                    parents: vec!["A_1".to_string()],
+                },
+            ]
+        );
+        let names: Vec<_> =
+            stub.symbols.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["f", "d", "v", "g"]);
+        assert_eq!(stub.symbols[1].kind, SymbolKind::Data);
+        assert_eq!(stub.symbols[1].size, 8);
+        assert_eq!(stub.symbols[2].kind, SymbolKind::Data);
+        assert_eq!(stub.symbols[2].size, 4);
+        assert_eq!(stub.symbols[3].version.as_deref(), Some("A_2"));
+
+        let bytes = stub.write().unwrap();
+        assert_eq!(&bytes[..4], b"\x7fELF");
+
+        // Without it, a symbol with no type is a function.
+        assert_eq!(shim.stub(&parse()).unwrap().symbols[2].kind,
+            SymbolKind::Function);
+        shim.data = vec!["w".to_string()];
+        assert!(shim.add_data(&mut parse()).is_err());
+    }
+}
@@ -0,0 +1,338 @@
+// Copyright 2020 Oxide Computer Company
+
+//! A sysroot spec: one TOML file which declares everything that goes into a
+//! sysroot archive, for `mf2tar build`.  Relative paths to local files in the
+//! spec are taken to be relative to the directory which contains it.
+//!
+//! ```toml
+//! output = "output/illumos-sysroot-i386.tar"
+//! compression = "gzip"
+//! packages = ["system/header", "system/library"]
+//! rules = [
+//!     { exclude = "usr/share" },
+//!     { include = "usr/share/pkgconfig" },
+//! ]
+//!
+//! [repository]
+//! path = "/ws/gate/packages/i386/nightly-nd/repo.redist"
+//! publisher = "on-nightly"
+//!
+//! [variants]
+//! arch = "i386"
+//!
+//! [[link]]
+//! path = "usr/lib/libfoo.so"
+//! target = "libfoo.so.1"
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::elf::Machine;
+use crate::rules::{Action, Rule};
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Repository {
+    pub path: PathBuf,
+    /// The publisher, for a repository which holds the packages of more than
+    /// one (under `publisher/NAME`)
+    pub publisher: Option<String>,
+}
+
+/// A rule which includes or excludes the paths matching a pattern, as for
+/// `--include` and `--exclude`.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PathRule {
+    Include(String),
+    Exclude(String),
+}
+
+impl PathRule {
+    pub fn rule(&self) -> Result<Rule, String> {
+        match self {
+            PathRule::Include(p) => Rule::new(Action::Include, p),
+            PathRule::Exclude(p) => Rule::new(Action::Exclude, p),
+        }
+    }
+}
+
+impl Repository {
+    /// The directory which holds the `file` and `pkg` directories.
+    pub fn root(&self) -> PathBuf {
+        match &self.publisher {
+            Some(publisher) => self.path.join("publisher").join(publisher),
+            None => self.path.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct File {
+    pub path: String,
+    pub source: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Link {
+    pub path: String,
+    pub target: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Shim {
+    pub path: String,
+    pub mapfile: PathBuf,
+    pub soname: Option<String>,
+    pub mach: Option<String>,
+    #[serde(default)]
+    pub define: Vec<String>,
+    /// The symbols which are data the size of an address
+    #[serde(default)]
+    pub data: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DevLinks {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Spec {
+    pub output: PathBuf,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub strict: bool,
+    /// The lockfile which records the package versions and file hashes
+    /// archived
+    pub lockfile: Option<PathBuf>,
+    pub repository: Repository,
+    pub packages: Vec<String>,
+    /// Which paths from the packages to leave out; the last rule which
+    /// matches a path decides
+    #[serde(default)]
+    pub rules: Vec<PathRule>,
+    /// The value of each variant (without the "variant." prefix); actions
+    /// for any other value are left out
+    #[serde(default)]
+    pub variants: BTreeMap<String, String>,
+    #[serde(default)]
+    pub stub_libraries: bool,
+    /// Sections to remove, by the glob of the paths of the objects
+    #[serde(default)]
+    pub strip_sections: BTreeMap<String, Vec<String>>,
+    pub dev_links: Option<DevLinks>,
+    #[serde(default, rename = "file")]
+    pub files: Vec<File>,
+    #[serde(default, rename = "link")]
+    pub links: Vec<Link>,
+    #[serde(default, rename = "shim")]
+    pub shims: Vec<Shim>,
+}
+
+impl Spec {
+    /// Read and check the spec in the file at `path`.
+    pub fn load(path: &Path) -> Result<Spec, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Spec::parse(&text, dir)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Parse and check a spec, resolving relative paths against `dir`.
+    pub fn parse(text: &str, dir: &Path) -> Result<Spec, String> {
+        let mut spec: Spec = toml::from_str(text).map_err(|e| e.to_string())?;
+        spec.check()?;
+
+        spec.output = dir.join(&spec.output);
+        spec.lockfile = spec.lockfile.map(|l| dir.join(l));
+        spec.repository.path = dir.join(&spec.repository.path);
+        for f in spec.files.iter_mut() {
+            f.source = dir.join(&f.source);
+        }
+        for s in spec.shims.iter_mut() {
+            s.mapfile = dir.join(&s.mapfile);
+        }
+        Ok(spec)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.output.as_os_str().is_empty() {
+            return Err("output must not be empty".into());
+        }
+        if self.packages.is_empty() {
+            return Err("packages must list at least one package".into());
+        }
+        let mut seen = BTreeSet::new();
+        for p in &self.packages {
+            if !seen.insert(p) {
+                return Err(format!("package {} is listed twice", p));
+            }
+        }
+
+        let archive_path = |what: &str, path: &str| {
+            if path.is_empty() || path.starts_with('/') {
+                Err(format!("{} \"{}\" must be a relative path in the \
+                    sysroot", what, path))
+            } else {
+                Ok(())
+            }
+        };
+        for r in &self.rules {
+            r.rule()?;
+        }
+
+        let mut seen = BTreeSet::new();
+        let paths = self
+            .files
+            .iter()
+            .map(|f| ("file", &f.path))
+            .chain(self.links.iter().map(|l| ("link", &l.path)))
+            .chain(self.shims.iter().map(|s| ("shim", &s.path)));
+        for (what, path) in paths {
+            archive_path(what, path)?;
+            if !seen.insert(path) {
+                return Err(format!("{} {} is already in the sysroot", what,
+                    path));
+            }
+        }
+        for s in &self.shims {
+            if let Some(mach) = &s.mach {
+                if Machine::from_name(mach).is_none() {
+                    return Err(format!("shim {}: unknown machine \"{}\"",
+                        s.path, mach));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SPEC: &str = r#"
+output = "out/sysroot.tar"
+compression = "gzip"
+lockfile = "sysroot.lock"
+packages = ["system/header", "system/library"]
+rules = [
+    { exclude = "usr/share" },
+    { include = "usr/share/pkgconfig" },
+    { exclude = "**/*.a" },
+]
+
+[repository]
+path = "/ws/repo.redist"
+publisher = "on-nightly"
+
+[variants]
+arch = "i386"
+
+[strip_sections]
+"**" = [".SUNW_ctf", ".debug_*"]
+
+[[shim]]
+path = "usr/lib/amd64/libssp.so.0.0.0"
+mapfile = "shims/libssp/common/mapfile.shim"
+soname = "libssp.so.0"
+
+[[link]]
+path = "usr/lib/libssp.so.0"
+target = "libssp.so.0.0.0"
+"#;
+
+    #[test]
+    fn parse() {
+        let spec = Spec::parse(SPEC, Path::new("/src")).unwrap();
+        assert_eq!(spec.output, Path::new("/src/out/sysroot.tar"));
+        assert_eq!(spec.compression, Compression::Gzip);
+        assert_eq!(spec.lockfile.unwrap(), Path::new("/src/sysroot.lock"));
+        assert_eq!(
+            spec.repository.root(),
+            Path::new("/ws/repo.redist/publisher/on-nightly")
+        );
+        assert_eq!(
+            spec.rules,
+            vec![
+                PathRule::Exclude("usr/share".to_string()),
+                PathRule::Include("usr/share/pkgconfig".to_string()),
+                PathRule::Exclude("**/*.a".to_string()),
+            ]
+        );
+        assert_eq!(spec.variants["arch"], "i386");
+        assert_eq!(spec.strip_sections["**"].len(), 2);
+        assert_eq!(
+            spec.shims[0].mapfile,
+            Path::new("/src/shims/libssp/common/mapfile.shim")
+        );
+        assert!(spec.dev_links.is_none());
+        assert!(spec.files.is_empty());
+    }
+
+    #[test]
+    fn sysroot() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let path = dir.join("../sysroot.toml");
+        let spec = Spec::load(&path).unwrap();
+        assert_eq!(spec.compression, Compression::Gzip);
+        assert!(spec.shims.iter().all(|s| s.mapfile.is_file()));
+    }
+
+    #[test]
+    fn errors() {
+        let err = |text: &str| Spec::parse(text, Path::new("")).unwrap_err();
+        let base = "output = \"x.tar\"\n\
+            packages = [\"a\"]\n\
+            [repository]\n\
+            path = \"repo\"\n";
+        assert!(Spec::parse(base, Path::new("")).is_ok());
+
+        assert!(err("output = \"x.tar\"\n").contains("repository"));
+        assert!(err(&format!("{}\npublish = \"x\"\n", base))
+            .contains("unknown field `publish`"));
+        assert!(err(&base.replace("[\"a\"]", "[\"a\", \"a\"]"))
+            .contains("package a is listed twice"));
+        assert!(err(&base.replace("[\"a\"]", "[]"))
+            .contains("at least one package"));
+        assert_eq!(
+            err(&format!("{}[[link]]\npath = \"/lib/x\"\ntarget = \"y\"\n",
+                base)),
+            "link \"/lib/x\" must be a relative path in the sysroot"
+        );
+        assert_eq!(
+            err(&format!("rules = [{{ exclude = \"/usr\" }}]\n{}", base)),
+            "pattern \"/usr\" must be a relative path in the sysroot"
+        );
+        assert!(err(&format!("rules = [{{ omit = \"usr\" }}]\n{}", base))
+            .contains("unknown variant `omit`"));
+        assert_eq!(
+            err(&format!("{}[[shim]]\npath = \"l\"\nmapfile = \"m\"\n\
+                mach = \"sparc\"\n", base)),
+            "shim l: unknown machine \"sparc\""
+        );
+    }
+}
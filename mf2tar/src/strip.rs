@@ -0,0 +1,334 @@
+// Copyright 2020 Oxide Computer Company
+
+//! Reduction of the objects delivered in the archive to what is needed to
+//! link against them.  The sysroot is only used for linking; the build
+//! machine never executes the program text of the target.
+
+use std::collections::HashMap;
+
+use crate::elf::{
+    self, Machine, Object, Stub, StubSymbol, SymbolKind, VersionDef,
+};
+use crate::repo::hash_buf;
+use crate::vfs;
+
+#[derive(Default)]
+pub struct Stripper {
+    /// Replace each shared object with a stub of its dynamic symbols
+    pub stub: bool,
+    /// Sections to remove from the objects at matching paths, as a path glob
+    /// and a list of section name globs
+    pub sections: Vec<(String, Vec<String>)>,
+    /// Each file which has been modified
+    pub modified: Vec<Modified>,
+}
+
+/// A record of a file modified on its way into the archive, so that it can
+/// be traced to what the package delivered.
+#[derive(Debug, PartialEq)]
+pub struct Modified {
+    pub path: String,
+    /// The SHA-1 hash of the original contents
+    pub original: String,
+    /// The SHA-1 hash of the contents archived
+    pub hash: String,
+    pub change: String,
+}
+
+/// The name of the file in the archive which lists the modified files.
+pub const MANIFEST: &str = "SYSROOT-MODIFIED";
+
+/// What became of an object.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// The file is left as it is, as it is not a shared object (or there was
+    /// nothing to do)
+    Unchanged,
+    /// The file is left as it is, for the reason given
+    Kept(String),
+    /// The file is replaced, with a description of the change
+    Replaced(Vec<u8>, String),
+}
+
+/// An exported symbol, as the link-editor sees it: the name of its version
+/// (`None` for the base version), name, binding, type and size.  The size of
+/// a function is of no consequence when linking, and is taken to be zero.
+type Export = (Option<String>, String, u8, u8, u64);
+
+/// The exported symbols of an object, in a form in which they can be
+/// compared between objects.
+fn exports(obj: &Object) -> Result<Vec<Export>, String> {
+    let names: HashMap<u16, (String, bool)> = obj
+        .verdefs()?
+        .into_iter()
+        .map(|d| (d.index, (d.name, d.flags & elf::VER_FLG_BASE != 0)))
+        .collect();
+
+    let mut out = Vec::new();
+    for sym in obj.exports()? {
+        let version = match sym.version.and_then(|i| names.get(&i)) {
+            Some((name, false)) => Some(name.clone()),
+            _ => None,
+        };
+        let size = if sym.kind == elf::STT_FUNC { 0 } else { sym.size };
+        out.push((version, sym.name, sym.bind, sym.kind, size));
+    }
+    out.sort();
+    Ok(out)
+}
+
+/// Build a stub with the same SONAME, dependencies, versions and exported
+/// symbols as the shared object `obj`, or explain why that is not possible.
+fn stub(obj: &Object) -> Result<Stub, String> {
+    let machine = Machine::from_em(obj.machine)
+        .ok_or_else(|| format!("machine {} unsupported", obj.machine))?;
+    let soname = obj
+        .dynamic_strings(elf::DT_SONAME)?
+        .pop()
+        .ok_or_else(|| "no SONAME".to_string())?;
+
+    let verdefs = obj.verdefs()?;
+    let versions = verdefs
+        .iter()
+        .filter(|d| d.flags & elf::VER_FLG_BASE == 0)
+        .map(|d| VersionDef {
+            name: d.name.clone(),
+            parents: d.parents.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    let mut symbols = Vec::new();
+    for (version, name, bind, kind, size) in exports(obj)? {
+        let weak = match bind {
+            elf::STB_GLOBAL => false,
+            elf::STB_WEAK => true,
+            b => {
+                return Err(format!("symbol {} has unsupported binding {}",
+                    name, b));
+            }
+        };
+        let kind = match kind {
+            elf::STT_FUNC => SymbolKind::Function,
+            elf::STT_OBJECT => SymbolKind::Data,
+            elf::STT_NOTYPE => SymbolKind::NoType,
+            k => {
+                return Err(format!("symbol {} has unsupported type {}",
+                    name, k));
+            }
+        };
+        symbols.push(StubSymbol {
+            name,
+            kind,
+            size,
+            version,
+            weak,
+        });
+    }
+
+    let mut runpath = obj.dynamic_strings(elf::DT_RUNPATH)?;
+    runpath.extend(obj.dynamic_strings(elf::DT_RPATH)?);
+
+    Ok(Stub {
+        machine,
+        soname,
+        needed: obj.dynamic_strings(elf::DT_NEEDED)?,
+        runpath: if runpath.is_empty() {
+            None
+        } else {
+            Some(runpath.join(":"))
+        },
+        versions,
+        symbols,
+    })
+}
+
+/// Replace the shared object in `data` with a stub, checking that the stub
+/// exports exactly the same symbols.  An object which cannot be read is kept
+/// as it is; a stub which does not match the object is an error.
+fn stub_object(data: &[u8]) -> Result<Outcome, String> {
+    if !Object::is_elf(data) {
+        return Ok(Outcome::Unchanged);
+    }
+    let obj = match Object::parse(data) {
+        Ok(obj) if obj.kind == elf::ET_DYN => obj,
+        Ok(_) => return Ok(Outcome::Unchanged),
+        Err(e) => return Ok(Outcome::Kept(e)),
+    };
+    let stub = match stub(&obj) {
+        Ok(stub) => stub,
+        Err(why) => return Ok(Outcome::Kept(why)),
+    };
+
+    let out = stub.write()?;
+    let new = Object::parse(&out)?;
+    let before = exports(&obj)?;
+    let after = exports(&new)?;
+    if before != after {
+        let missing = before.iter().find(|e| !after.contains(e));
+        let extra = after.iter().find(|e| !before.contains(e));
+        return Err(format!(
+            "stub exports differ from the original (missing {:?}, extra {:?})",
+            missing, extra
+        ));
+    }
+    for tag in &[elf::DT_SONAME, elf::DT_NEEDED] {
+        if obj.dynamic_strings(*tag)? != new.dynamic_strings(*tag)? {
+            return Err("stub dynamic section differs from the original"
+                .to_string());
+        }
+    }
+
+    Ok(Outcome::Replaced(out, "stub".to_string()))
+}
+
+impl Stripper {
+    /// Whether `process` could change any file
+    pub fn is_active(&self) -> bool {
+        self.stub || !self.sections.is_empty()
+    }
+
+    /// Remove the configured sections from the object at `path`, if it is
+    /// an executable or shared object.
+    fn remove_sections(&self, path: &str, data: &[u8]) -> Outcome {
+        let patterns: Vec<&String> = self
+            .sections
+            .iter()
+            .filter(|(glob, _)| vfs::glob(glob, path))
+            .flat_map(|(_, names)| names)
+            .collect();
+        let linked = |o: Object| matches!(o.kind, elf::ET_EXEC | elf::ET_DYN);
+        if patterns.is_empty()
+            || !Object::is_elf(data)
+            || !Object::parse(data).is_ok_and(linked)
+        {
+            return Outcome::Unchanged;
+        }
+        let matches = |name: &str| patterns.iter().any(|p| vfs::glob(p, name));
+        match elf::remove_sections(data, &matches) {
+            Ok(Some((out, names))) => {
+                Outcome::Replaced(out, format!("removed {}", names.join(" ")))
+            }
+            Ok(None) => Outcome::Unchanged,
+            Err(e) => Outcome::Kept(e),
+        }
+    }
+
+    /// Process the file at `path` with contents `data`, recording it if it
+    /// is modified.
+    pub fn process(
+        &mut self,
+        path: &str,
+        data: &[u8],
+    ) -> Result<Outcome, String> {
+        let path = &vfs::normalize(path);
+        let mut outcome = if self.stub {
+            stub_object(data)?
+        } else {
+            Outcome::Unchanged
+        };
+        if !matches!(outcome, Outcome::Replaced(..)) {
+            match self.remove_sections(path, data) {
+                Outcome::Unchanged => {}
+                o => outcome = o,
+            }
+        }
+
+        if let Outcome::Replaced(out, change) = &outcome {
+            self.modified.push(Modified {
+                path: path.to_string(),
+                original: hash_buf(data),
+                hash: hash_buf(out),
+                change: change.clone(),
+            });
+        }
+        Ok(outcome)
+    }
+
+    /// The contents of the `MANIFEST` file, if any files were modified.
+    pub fn manifest(&self) -> Option<String> {
+        if self.modified.is_empty() {
+            return None;
+        }
+        let mut out = String::from("# path\toriginal SHA-1\tSHA-1\tchange\n");
+        for m in &self.modified {
+            out.push_str(&format!("{}\t{}\t{}\t{}\n", m.path, m.original,
+                m.hash, m.change));
+        }
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn library() -> Stub {
+        let sym = StubSymbol::new;
+        Stub {
+            needed: vec!["libc.so.1".to_string()],
+            runpath: Some("/usr/lib/t".to_string()),
+            ..Stub::library(Machine::Amd64, "libt.so.1")
+        }
+        .version("T_1", &[])
+        .version("T_2", &["T_1"])
+        .symbol(sym("f", SymbolKind::Function, 0, Some("T_1")))
+        .symbol(sym("d", SymbolKind::Data, 24, Some("T_2")))
+        .symbol(sym("_end", SymbolKind::NoType, 0, None))
+        .symbol(StubSymbol {
+            weak: true,
+            ..sym("w", SymbolKind::Function, 0, Some("T_2"))
+        })
+    }
+
+    #[test]
+    fn stubbing() {
+        let mut stripper = Stripper {
+            stub: true,
+            ..Default::default()
+        };
+        let original = library().write().unwrap();
+
+        let path = "usr/lib/amd64/libt.so.1";
+        let out = match stripper.process(path, &original).unwrap() {
+            Outcome::Replaced(out, _) => out,
+            o => panic!("unexpected {:?}", o),
+        };
+        let obj = Object::parse(&out).unwrap();
+        let exports = exports(&obj).unwrap();
+        let t = |s: &str| Some(s.to_string());
+        let (global, weak) = (elf::STB_GLOBAL, elf::STB_WEAK);
+        assert_eq!(
+            exports,
+            vec![
+                (None, "_end".to_string(), global, elf::STT_NOTYPE, 0),
+                (t("T_1"), "f".to_string(), global, elf::STT_FUNC, 0),
+                (t("T_2"), "d".to_string(), global, elf::STT_OBJECT, 24),
+                (t("T_2"), "w".to_string(), weak, elf::STT_FUNC, 0),
+            ]
+        );
+        assert_eq!(
+            obj.dynamic_strings(elf::DT_RUNPATH).unwrap(),
+            vec!["/usr/lib/t"]
+        );
+
+        assert_eq!(
+            stripper.process("bin/sh", b"#!/bin/sh\n").unwrap(),
+            Outcome::Unchanged
+        );
+        assert_eq!(
+            Stripper::default().process(path, &original).unwrap(),
+            Outcome::Unchanged
+        );
+
+        assert_eq!(stripper.modified.len(), 1);
+        assert_eq!(stripper.modified[0].path, path);
+        assert_eq!(stripper.modified[0].original, hash_buf(&original));
+        assert_eq!(stripper.modified[0].hash, hash_buf(&out));
+        assert!(stripper.manifest().unwrap().ends_with(&format!(
+            "{}\t{}\t{}\tstub\n",
+            path,
+            hash_buf(&original),
+            hash_buf(&out)
+        )));
+    }
+}
@@ -0,0 +1,1268 @@
+// Copyright 2020 Oxide Computer Company
+
+//! Assembling a sysroot archive: the files, directories and links which a
+//! manifest delivers from a proto area, or which the packages in a
+//! repository deliver, along with any extra files, links and shim libraries.
+//! A `SysrootBuilder` says what goes into the archive, and how, and then
+//! writes it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use tar::{Builder, EntryType, Header};
+
+use crate::elf::Machine;
+use crate::error::{self, Error};
+use crate::liblinks;
+use crate::lock;
+use crate::needed;
+use crate::pkgmf::{self, Entry, Location};
+use crate::plan::{self, Plan};
+use crate::release;
+use crate::report::{self, Event, Reporter, Summary};
+use crate::repo::{self, Repository};
+use crate::rules::{Rule, Rules};
+use crate::shim::Shim;
+use crate::spec::{self, Spec};
+use crate::strip::{self, Outcome, Stripper};
+use crate::vfs::{self, Broken, Node, Tree};
+
+enum Extra {
+    Link(Entry),
+    File(Entry, PathBuf),
+    Shim(Entry, PathBuf, Shim),
+}
+
+/// A manifest to read, with what is needed to read it.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    pub manifest: PathBuf,
+    /// The value of each `$(NAME)` macro
+    pub defines: HashMap<String, String>,
+    /// Files of transforms to apply, before the manifest itself is read
+    pub transforms: Vec<PathBuf>,
+    /// Where to look for included files; if none are given, the directory of
+    /// the manifest
+    pub include_dirs: Vec<PathBuf>,
+}
+
+enum Source {
+    ManifestProto(Manifest, PathBuf),
+    RepositoryPackages(PathBuf, Vec<String>),
+}
+
+/// What goes into a sysroot archive, and how it is written.  Make one with
+/// `from_manifest` or `from_repository`, choose anything more, and `build`
+/// the archive:
+///
+/// ```no_run
+/// use mf2tar::rules::{Action, Rule};
+/// use mf2tar::SysrootBuilder;
+///
+/// SysrootBuilder::from_repository("/ws/packages/i386/nightly/repo.redist",
+///     vec!["system/header".to_string(), "system/library".to_string()],
+///     "output/sysroot.tar")
+///     .rule(Rule::new(Action::Exclude, "usr/share").unwrap())
+///     .variant("arch", "i386")
+///     .strict(true)
+///     .build()
+///     .unwrap();
+/// ```
+pub struct SysrootBuilder {
+    source: Source,
+    tar: PathBuf,
+    append: bool,
+    /// Also write a copy of the archive compressed with gzip
+    gzip: bool,
+    /// Which paths from the manifest or packages to leave out
+    rules: Rules,
+    variants: BTreeMap<String, String>,
+    extra: Vec<Extra>,
+    strict: bool,
+    stripper: Stripper,
+    /// Which missing compilation links to add, if any
+    dev_links: Option<liblinks::Rules>,
+    /// The lockfile in which to record the package versions and file hashes
+    /// archived, or (if `locked`) against which to check them
+    lockfile: Option<PathBuf>,
+    locked: bool,
+    /// Name the archive as this release
+    release: Option<release::Release>,
+    /// List what would be archived, in this format, instead of writing it
+    dry_run: Option<report::Format>,
+    /// Where progress, warnings and errors go
+    report: Reporter,
+    /// Carry on past entries which cannot be archived, failing at the end
+    keep_going: bool,
+}
+
+impl SysrootBuilder {
+    fn new(source: Source, tar: PathBuf) -> SysrootBuilder {
+        SysrootBuilder {
+            source,
+            tar,
+            append: false,
+            gzip: false,
+            rules: Rules::default(),
+            variants: BTreeMap::new(),
+            extra: Vec::new(),
+            strict: false,
+            stripper: Stripper::default(),
+            dev_links: None,
+            lockfile: None,
+            locked: false,
+            release: None,
+            dry_run: None,
+            report: Reporter::default(),
+            keep_going: false,
+        }
+    }
+
+    /// An archive, written to `tar`, of what `manifest` delivers from the
+    /// proto area `proto`.
+    pub fn from_manifest<P, T>(manifest: Manifest, proto: P, tar: T)
+        -> SysrootBuilder
+    where
+        P: Into<PathBuf>,
+        T: Into<PathBuf>,
+    {
+        SysrootBuilder::new(Source::ManifestProto(manifest, proto.into()),
+            tar.into())
+    }
+
+    /// An archive, written to `tar`, of what the named packages in the
+    /// repository at `repository` deliver.  Each package must have exactly
+    /// one version in the repository.
+    pub fn from_repository<P, T>(repository: P, packages: Vec<String>, tar: T)
+        -> SysrootBuilder
+    where
+        P: Into<PathBuf>,
+        T: Into<PathBuf>,
+    {
+        SysrootBuilder::new(
+            Source::RepositoryPackages(repository.into(), packages),
+            tar.into(),
+        )
+    }
+
+    /// The archive which a spec (e.g., `sysroot.toml`, read with
+    /// `Spec::load`) declares.
+    pub fn from_spec(spec: Spec) -> SysrootBuilder {
+        let mut builder = SysrootBuilder::from_repository(
+            spec.repository.root(),
+            spec.packages,
+            spec.output,
+        )
+        .gzip(spec.compression == spec::Compression::Gzip)
+        .strict(spec.strict)
+        .stub_libraries(spec.stub_libraries);
+
+        for f in spec.files {
+            builder = builder.file(&f.path, f.source);
+        }
+        for l in spec.links {
+            builder = builder.link(&l.path, &l.target);
+        }
+        for s in spec.shims {
+            // The machine has been checked with the rest of the spec.
+            let machine = s.mach.as_deref().and_then(Machine::from_name);
+            let shim = Shim::for_path(&s.path, s.soname, machine,
+                s.define, s.data);
+            builder = builder.shim(&s.path, s.mapfile, shim);
+        }
+
+        // The patterns have been checked with the rest of the spec.
+        for r in &spec.rules {
+            builder = builder.rule(r.rule().unwrap());
+        }
+        for (name, value) in spec.variants {
+            builder = builder.variant(name, value);
+        }
+        for (glob, names) in spec.strip_sections {
+            builder = builder.strip_sections(&glob, names);
+        }
+        if let Some(d) = spec.dev_links {
+            builder = builder.dev_links(d.allow, d.deny);
+        }
+        if let Some(lockfile) = spec.lockfile {
+            builder = builder.lockfile(lockfile);
+        }
+        builder
+    }
+
+    /// Add to the end of an existing tar file, rather than replacing it.
+    pub fn append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+
+    /// Also write a copy of the archive compressed with gzip, alongside it.
+    pub fn gzip(mut self, gzip: bool) -> Self {
+        self.gzip = gzip;
+        self
+    }
+
+    /// Add a rule after those already given; the last rule which matches a
+    /// path decides whether it is archived.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Leave out the actions for any value of the variant `name` (without
+    /// the "variant." prefix) but `value`.
+    pub fn variant<N, V>(mut self, name: N, value: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.variants.insert(name.into(), value.into());
+        self
+    }
+
+    /// Add the local file `source` to the archive, as `path`.
+    pub fn file<S: Into<PathBuf>>(mut self, path: &str, source: S) -> Self {
+        self.extra.push(Extra::File(file_entry(path), source.into()));
+        self
+    }
+
+    /// Add a symbolic link to the archive.
+    pub fn link(mut self, path: &str, target: &str) -> Self {
+        self.extra.push(Extra::Link(Entry::Link(pkgmf::Link {
+            path: path.to_string(),
+            attr: pkgmf::FsAttr::default(),
+            target: target.to_string(),
+        })));
+        self
+    }
+
+    /// Add a shim library to the archive, as `path`, generated from the
+    /// versions and symbols of a mapfile.
+    pub fn shim<M>(mut self, path: &str, mapfile: M, shim: Shim) -> Self
+    where
+        M: Into<PathBuf>,
+    {
+        self.extra.push(Extra::Shim(file_entry(path), mapfile.into(), shim));
+        self
+    }
+
+    /// Fail on any unknown or malformed manifest action (instead of
+    /// warning), undefined macro, link leading out of the sysroot or to
+    /// nothing in the archive, or library dependency not found in the
+    /// archive.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Replace each shared object with a stub holding only what is needed to
+    /// link against it.
+    pub fn stub_libraries(mut self, stub: bool) -> Self {
+        self.stripper.stub = stub;
+        self
+    }
+
+    /// Remove the named sections (which are not loaded) from the objects
+    /// whose path matches `glob` (e.g., "usr/lib/**", or as a path in the
+    /// sysroot, "./usr/lib/**"); names may be globs.
+    pub fn strip_sections(mut self, glob: &str, names: Vec<String>) -> Self {
+        self.stripper.sections.push((vfs::normalize(glob), names));
+        self
+    }
+
+    /// Add each missing compilation link (e.g., libfoo.so -> libfoo.so.1)
+    /// for the libraries in the archive, with a path matching a glob in
+    /// `allow` (if any are given) and none in `deny`.
+    pub fn dev_links(mut self, allow: Vec<String>, deny: Vec<String>)
+        -> Self
+    {
+        self.dev_links = Some(liblinks::Rules { allow, deny });
+        self
+    }
+
+    /// Record the package versions and file hashes archived in a lockfile;
+    /// only for an archive of packages.
+    pub fn lockfile<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.lockfile = Some(path.into());
+        self
+    }
+
+    /// Check the package versions and file hashes against the lockfile,
+    /// rather than recording them.
+    pub fn locked(mut self, locked: bool) -> Self {
+        self.locked = locked;
+        self
+    }
+
+    /// Name the archive as a release, from the package metadata.
+    pub fn release(mut self, release: release::Release) -> Self {
+        self.release = Some(release);
+        self
+    }
+
+    /// List what would be archived, in this format, without writing the tar
+    /// file.
+    pub fn dry_run(mut self, format: report::Format) -> Self {
+        self.dry_run = Some(format);
+        self
+    }
+
+    /// Report progress, warnings and errors in this format.
+    pub fn log_format(mut self, format: report::Format) -> Self {
+        self.report.format = format;
+        self
+    }
+
+    /// List each entry as it is archived.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.report.verbose = verbose;
+        self
+    }
+
+    /// Report progress, warnings and errors to `report`, e.g., one which
+    /// passes each event to a callback.
+    pub fn reporter(mut self, report: Reporter) -> Self {
+        self.report = report;
+        self
+    }
+
+    /// Carry on past each entry which cannot be archived, reporting it, and
+    /// fail at the end; otherwise, the first such error stops the build.
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Check the options which only apply to archives made from a
+    /// repository.
+    pub fn check(&self) -> Result<(), String> {
+        let repository = matches!(self.source, Source::RepositoryPackages(..));
+        if self.lockfile.is_some() && !repository {
+            Err("a lockfile can only be used with -r & -P".into())
+        } else if self.locked && self.lockfile.is_none() {
+            Err("--locked requires a lockfile".into())
+        } else if self.release.is_some() && !repository {
+            Err("--release can only be used with -r & -P".into())
+        } else if self.release.is_some() && self.append {
+            // The file appended to would be renamed as the release.
+            Err("--release cannot be used with -a".into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Make the archive.  Any error is reported, as well as returned.
+    pub fn build(mut self) -> error::Result<()> {
+        let mut report = std::mem::take(&mut self.report);
+        let res = self
+            .check()
+            .map_err(Error::Usage)
+            .and_then(|_| build(self, &mut report));
+        if let Err(e) = &res {
+            error!(report, "{}", e);
+        }
+        res
+    }
+}
+
+fn file_entry(path: &str) -> Entry {
+    Entry::File(pkgmf::File {
+        path: path.to_string(),
+        attr: pkgmf::FsAttr::default(),
+        chash: None,
+        cname: None,
+    })
+}
+
+impl Manifest {
+    /// A manifest, with no macros defined, transforms or include
+    /// directories.
+    pub fn new<P: Into<PathBuf>>(manifest: P) -> Manifest {
+        Manifest {
+            manifest: manifest.into(),
+            ..Default::default()
+        }
+    }
+
+    /// The directory of the manifest, once it is known to be readable.
+    pub fn directory(&self) -> io::Result<PathBuf> {
+        let parent = self.manifest.parent().ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid manifest directory",
+        ))?;
+        File::open(&self.manifest)?;
+
+        Ok(parent.to_path_buf())
+    }
+
+    /// A mogrifier over the transforms and then the manifest, where
+    /// `manifest_dir` is the directory of the manifest.
+    pub fn mogrifier(
+        &self,
+        manifest_dir: &Path,
+        expansion: pkgmf::Expansion,
+    ) -> pkgmf::Mogrifier {
+        let mut mogrifier = pkgmf::Mogrifier::new(expansion);
+        if self.include_dirs.is_empty() {
+            mogrifier.add_include_dir(manifest_dir);
+        }
+        for dir in &self.include_dirs {
+            mogrifier.add_include_dir(dir);
+        }
+        for t in &self.transforms {
+            mogrifier.add_file(t);
+        }
+        mogrifier.add_file(&self.manifest);
+        mogrifier
+    }
+}
+
+fn prepare_proto(proto_dir: &Path) -> io::Result<PathBuf> {
+    let cpath = proto_dir.canonicalize()?;
+
+    if !cpath.is_dir() {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("not a directory: {}", proto_dir.to_str().unwrap_or("")),
+        ))
+    } else {
+        Ok(cpath)
+    }
+}
+
+/// Open the tar file, returning a builder and (when appending) a tree of the
+/// entries already in the archive.
+fn prepare_tar(
+    tar_path: &Path,
+    append: bool,
+) -> io::Result<(Builder<Box<dyn io::Write>>, Tree)> {
+    let mut tar_file = OpenOptions::new()
+        .write(true)
+        .read(append)
+        .create(true)
+        .truncate(!append)
+        .open(tar_path)?;
+    let mut tree = Tree::new();
+
+    if append {
+        let mut parser = tar::Archive::new(tar_file);
+        let mut pos = 0;
+        for ent in parser.entries()? {
+            let ent = ent?;
+            let path = ent.path()?.to_string_lossy().into_owned();
+            match ent.header().entry_type() {
+                EntryType::Directory => tree.insert(&path, Node::Dir),
+                EntryType::Symlink => {
+                    let target = ent
+                        .link_name()?
+                        .map(|t| t.to_string_lossy().into_owned())
+                        .unwrap_or_default();
+                    tree.insert(&path, Node::Link(target));
+                }
+                _ => tree.insert(&path, Node::File),
+            }
+
+            let size = ent.header().entry_size()?;
+            let next = ent.raw_file_position() + size;
+            pos = (next + (512 - 1)) & !(512 - 1);
+        }
+        tar_file = parser.into_inner();
+        tar_file.seek(SeekFrom::Start(pos))?;
+    }
+
+    Ok((Builder::new(Box::new(tar_file)), tree))
+}
+
+fn iterate_items<'a, W, F>(
+    archive: &mut Archive<'a, W>,
+    args: &Manifest,
+    manifest_dir: &Path,
+    variants: &BTreeMap<String, String>,
+    mut process_func: F,
+) -> error::Result<()>
+where
+    W: io::Write,
+    F: FnMut(&mut Archive<'a, W>, &Location, &Entry) -> error::Result<()>,
+{
+    let strict = archive.strict;
+    archive.report.emit(Event::Progress {
+        message: &format!(
+            "processing {} in {}",
+            args.manifest.to_str().unwrap_or(""),
+            manifest_dir.to_str().unwrap_or("")
+        ),
+    });
+
+    // Handle $(variable) replacement with provided defines
+    let expansion = if strict {
+        pkgmf::Expansion::Strict(args.defines.clone())
+    } else {
+        pkgmf::Expansion::Defined(args.defines.clone())
+    };
+
+    let mut mogrifier = args.mogrifier(manifest_dir, expansion);
+    for (name, value) in variants {
+        mogrifier.set_variant(name, value);
+    }
+    while let Some(entry) = mogrifier.next() {
+        match entry {
+            Err(pkgmf::Error::Parse(e)) if !strict => {
+                warning!(archive.report, "{}", e);
+            }
+            Err(e) => return Err(e.into()),
+            Ok(Entry::Unknown(_)) => {}
+            Ok(entry) => {
+                let location = mogrifier.location().unwrap();
+                process_func(archive, location, &entry)?
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The archive being written, with the state kept while writing it.
+struct Archive<'a, W: io::Write> {
+    builder: Builder<W>,
+    /// Everything in the archive so far
+    tree: Tree,
+    /// A single mtime for every entry in the archive
+    mtime: u64,
+    strict: bool,
+    stripper: Stripper,
+    /// For a dry run, what would be archived; nothing is written
+    plan: Option<Plan>,
+    /// The package whose entries are being archived, if any
+    package: Option<String>,
+    summary: Summary,
+    report: &'a mut Reporter,
+}
+
+impl<W: io::Write> Archive<'_, W> {
+    /// Note that `rule` left out `path`.
+    fn exclude(&mut self, path: &str, rule: &Rule) {
+        match &mut self.plan {
+            Some(plan) => plan.exclude(path, &rule.to_string()),
+            None => self.report.emit(Event::Excluded {
+                path,
+                package: self.package.as_deref(),
+                rule: rule.to_string(),
+            }),
+        }
+        self.summary.excluded += 1;
+    }
+}
+
+enum TarFileSource<'a> {
+    Proto(&'a PathBuf),
+    Repository(&'a Repository),
+    SingleFile(&'a PathBuf),
+    Data(&'a [u8]),
+    None,
+}
+
+impl TarFileSource<'_> {
+    /// Where the contents of `file` come from, for the plan of a dry run.
+    fn describe(&self, file: &pkgmf::File) -> String {
+        match self {
+            TarFileSource::Proto(proto_dir) => {
+                proto_dir.join(&file.path).display().to_string()
+            }
+            TarFileSource::Repository(_) => format!("repository file {}",
+                file.cname.as_deref().unwrap_or("")),
+            TarFileSource::SingleFile(path) => path.display().to_string(),
+            TarFileSource::Data(buf) => {
+                format!("generated ({} bytes)", buf.len())
+            }
+            TarFileSource::None => String::new(),
+        }
+    }
+}
+
+/// Append the contents of a file, after passing them through the stripper.
+/// Returns a note for the listing if the contents were replaced.  A stub
+/// which does not match the original object is a failed check.
+fn append_data<W: io::Write>(
+    archive: &mut Archive<'_, W>,
+    header: &mut Header,
+    path: &str,
+    data: &[u8],
+) -> error::Result<Option<String>> {
+    let new = match archive.stripper.process(path, data) {
+        Ok(Outcome::Unchanged) => None,
+        Ok(Outcome::Kept(why)) => {
+            warning!(archive.report, "{} left unmodified: {}", path,
+                why);
+            None
+        }
+        Ok(Outcome::Replaced(out, change)) => Some((out, change)),
+        Err(e) => return Err(Error::Check(format!("{}: {}", path, e))),
+    };
+    let note = new.as_ref().map(|(out, change)| {
+        format!("{}, {} -> {} bytes", change, data.len(), out.len())
+    });
+    let data = new.as_ref().map(|(out, _)| out.as_slice()).unwrap_or(data);
+
+    header.set_size(data.len() as u64);
+    header.set_cksum();
+    archive.builder.append(header, data).map_err(|error| Error::Tar {
+        path: Some(path.to_string()),
+        error,
+    })?;
+    Ok(note)
+}
+
+/// Name the manifest line which delivers a file which could not be read.
+fn located(location: &Location, e: Error) -> Error {
+    match e {
+        Error::Io { path, error } => Error::Proto {
+            location: location.clone(),
+            path,
+            error,
+        },
+        e => e,
+    }
+}
+
+/// Open a local file to archive, returning it and its size.
+fn open_local(path: &Path) -> error::Result<(File, u64)> {
+    let io_error = |error| Error::Io { path: path.to_path_buf(), error };
+    let file = File::open(path).map_err(io_error)?;
+    let meta = file.metadata().map_err(io_error)?;
+    if !meta.file_type().is_file() {
+        return Err(io_error(io::Error::new(io::ErrorKind::InvalidData,
+            "not a file")));
+    }
+    Ok((file, meta.len()))
+}
+
+fn append_tar<W: io::Write>(
+    archive: &mut Archive<'_, W>,
+    source: &TarFileSource,
+    entry: &Entry,
+) -> error::Result<()> {
+    let tar = |error| Error::Tar {
+        path: entry.get_path().map(String::from),
+        error,
+    };
+    let mtime = archive.mtime;
+    match entry {
+        Entry::Dir(dir) => {
+            let mut header = Header::new_ustar();
+            header.set_entry_type(EntryType::Directory);
+            header.set_path(&dir.path).map_err(tar)?;
+            header.set_size(0);
+            header.set_mode(0o755);
+            header.set_mtime(mtime);
+            header.set_cksum();
+
+            archive.builder.append(&header, &[] as &[u8]).map_err(tar)?;
+            archive.tree.insert(&dir.path, Node::Dir);
+            match &mut archive.plan {
+                Some(plan) => plan.dir(&dir.path),
+                None => archive.report.emit(Event::Entry {
+                    kind: plan::Kind::Dir,
+                    path: &dir.path,
+                    package: archive.package.as_deref(),
+                    source: None,
+                    target: None,
+                    bytes: None,
+                    note: None,
+                }),
+            }
+            archive.summary.dirs += 1;
+            Ok(())
+        }
+        Entry::File(file) => {
+            /*
+             * A dry run need not read the contents of the file, which (from
+             * a repository) may take a long time.
+             */
+            if let Some(plan) = &mut archive.plan {
+                plan.file(&file.path, source.describe(file));
+                archive.tree.insert(&file.path, Node::File);
+                return Ok(());
+            }
+
+            let mut header = Header::new_ustar();
+            header.set_entry_type(EntryType::Regular);
+            header.set_path(&file.path).map_err(tar)?;
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+
+            let mut note = None;
+            match source {
+                TarFileSource::None =>
+                    panic!("should not be None for Entry::File"),
+                TarFileSource::SingleFile(_) | TarFileSource::Proto(_) => {
+                    let path = match source {
+                        TarFileSource::Proto(proto_dir) => {
+                            proto_dir.join(&file.path)
+                        }
+                        TarFileSource::SingleFile(path) => path.to_path_buf(),
+                        _ => unreachable!(),
+                    };
+                    let (source, source_len) = open_local(&path)?;
+
+                    if archive.stripper.is_active() {
+                        let mut buf = Vec::new();
+                        source.take(source_len).read_to_end(&mut buf)
+                            .map_err(|error| Error::Io { path, error })?;
+                        note = append_data(archive, &mut header, &file.path,
+                            &buf)?;
+                    } else {
+                        header.set_size(source_len);
+                        header.set_cksum();
+                        archive.builder.append(&header,
+                            source.take(source_len)).map_err(tar)?;
+                    }
+                }
+                TarFileSource::Data(buf) => {
+                    note = append_data(archive, &mut header, &file.path,
+                        buf)?;
+                }
+                TarFileSource::Repository(repo) => {
+                    let buf = repo.file(file.cname.as_ref().unwrap(),
+                        file.chash.as_ref().unwrap())
+                        .map_err(|error| Error::Payload {
+                            package: archive.package.clone(),
+                            path: file.path.clone(),
+                            error: Box::new(error),
+                        })?;
+
+                    note = append_data(archive, &mut header, &file.path,
+                        &buf)?;
+                }
+            };
+
+            archive.tree.insert(&file.path, Node::File);
+            let bytes = header.size().map_err(tar)?;
+            archive.report.emit(Event::Entry {
+                kind: plan::Kind::File,
+                path: &file.path,
+                package: archive.package.as_deref(),
+                source: Some(source.describe(file)),
+                target: None,
+                bytes: Some(bytes),
+                note: note.as_deref(),
+            });
+            archive.summary.files += 1;
+            archive.summary.bytes += bytes;
+            Ok(())
+        }
+        pkgmf::Entry::Link(link) => {
+            /*
+             * An absolute target would lead out of the sysroot once it is
+             * unpacked, so it is replaced with the equivalent relative one.
+             */
+            let mut target = link.target.clone();
+            let mut note = None;
+            match vfs::link_destination(&link.path, &link.target) {
+                Some(dest) if link.target.starts_with('/') => {
+                    target = vfs::relative_target(&link.path, &dest);
+                    note = Some(format!("was {}", link.target));
+                }
+                Some(_) => {}
+                None => {
+                    let msg = format!("link {} -> {} leads out of the \
+                        sysroot", link.path, link.target);
+                    if archive.strict {
+                        return Err(Error::Check(msg));
+                    }
+                    warning!(archive.report, "{}", msg);
+                }
+            }
+
+            let mut header = Header::new_ustar();
+            header.set_entry_type(EntryType::Symlink);
+            header.set_size(0);
+            header.set_path(&link.path).map_err(tar)?;
+            header.set_mtime(mtime);
+            header.set_mode(0o777);
+            header.set_link_name(&target).map_err(tar)?;
+            header.set_cksum();
+
+            // TODO: handle symlinks which are too long
+            archive.builder.append(&header, io::empty()).map_err(tar)?;
+            match &mut archive.plan {
+                Some(plan) => plan.link(&link.path, &target),
+                None => archive.report.emit(Event::Entry {
+                    kind: plan::Kind::Link,
+                    path: &link.path,
+                    package: archive.package.as_deref(),
+                    source: None,
+                    target: Some(&target),
+                    bytes: None,
+                    note: note.as_deref(),
+                }),
+            }
+            archive.summary.links += 1;
+            archive.tree.insert(&link.path, Node::Link(target));
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn build(mut params: SysrootBuilder, report: &mut Reporter)
+    -> error::Result<()>
+{
+    /*
+     * Use a single mtime for all files in the archive.
+     */
+    let mtime = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let stripper = std::mem::take(&mut params.stripper);
+    let mut failures = error::Failures::new(params.keep_going);
+
+    // The package versions and file hashes archived, for the lockfile, and
+    // what the packages say about the base of the sysroot.
+    let mut built = lock::Lock::default();
+    let mut metadata = release::Metadata::default();
+    let locked = if params.locked {
+        let path = params.lockfile.as_ref().unwrap();
+        match lock::Lock::load(path) {
+            Ok(lock) => Some(lock),
+            Err(error) => {
+                return Err(Error::Io { path: path.clone(), error });
+            }
+        }
+    } else {
+        None
+    };
+
+    /*
+     * A dry run goes through all the same steps, but keeps the plan instead
+     * of writing the archive; the tar file is not even opened.
+     */
+    let open_archive = |report| -> error::Result<_> {
+        let (builder, tree, plan) = if params.dry_run.is_some() {
+            let sink: Box<dyn io::Write> = Box::new(io::sink());
+            (Builder::new(sink), Tree::new(), Some(Plan::default()))
+        } else {
+            let (builder, tree) = prepare_tar(&params.tar, params.append)
+                .map_err(|error| Error::Tar {
+                    path: Some(params.tar.display().to_string()),
+                    error,
+                })?;
+            (builder, tree, None)
+        };
+        Ok(Archive {
+            builder,
+            tree,
+            mtime,
+            strict: params.strict,
+            stripper,
+            plan,
+            package: None,
+            summary: Summary::default(),
+            report,
+        })
+    };
+
+    let mut archive = match &params.source {
+        Source::ManifestProto(pm, proto_area) => {
+            let manifest_dir = pm.directory()
+                .map_err(|error| Error::Io {
+                    path: pm.manifest.clone(),
+                    error,
+                })?;
+            let proto_dir = prepare_proto(proto_area)
+                .map_err(|error| Error::Io {
+                    path: proto_area.clone(),
+                    error,
+                })?;
+            let source = TarFileSource::Proto(&proto_dir);
+
+            let mut archive = open_archive(report)?;
+
+            for (key, value) in pm.defines.iter() {
+                archive.report.emit(Event::Progress {
+                    message: &format!("'{}' => '{}'", key, value),
+                });
+            }
+
+            let proc_func = |archive: &mut Archive<_>, location: &Location,
+                entry: &Entry|
+            {
+                if let Some(path) = entry.get_path() {
+                    match params.rules.excluded(path) {
+                        Some(rule) => archive.exclude(path, rule),
+                        None => {
+                            let res = append_tar(archive, &source, entry)
+                                .map_err(|e| located(location, e));
+                            failures.check(archive.report, res)?;
+                        }
+                    }
+                }
+                Ok(())
+            };
+
+            iterate_items(
+                &mut archive,
+                pm,
+                &manifest_dir,
+                &params.variants,
+                proc_func,
+            )?;
+
+            archive
+        }
+        Source::RepositoryPackages(repo_dir, package_names) => {
+            let repo = Repository::new(repo_dir)?;
+            let source = TarFileSource::Repository(&repo);
+            let packages = repo.scan()?;
+
+            let mut archive = open_archive(report)?;
+
+            for pn in package_names {
+                let pkg = packages.get(pn).ok_or_else(|| {
+                    repo::Error::Package(format!("package \"{}\" not found \
+                        in repository", pn))
+                })?;
+
+                if pkg.versions.len() != 1 {
+                    let versions: Vec<_> = pkg.versions.iter()
+                        .map(|v| v.version.as_str())
+                        .collect();
+                    return Err(repo::Error::Package(format!("package \"{}\" \
+                        has {} versions, not 1: {}", pkg.name,
+                        pkg.versions.len(), versions.join(", "))).into());
+                }
+
+                let version = pkg.versions.first().unwrap();
+                let fmri = pkg.fmri(version);
+                if let Some(lock) = &locked {
+                    lock.check_package(pn, &fmri).map_err(Error::Locked)?;
+                }
+                metadata.package(&fmri);
+                archive.package = Some(pn.clone());
+                built.packages.push(lock::Package {
+                    name: pn.clone(),
+                    fmri,
+                    files: BTreeMap::new(),
+                });
+                let files = &mut built.packages.last_mut().unwrap().files;
+
+                let in_package = |error| Error::Manifest {
+                    package: Some(pn.clone()),
+                    error,
+                };
+                let mfest = match version.manifest(&params.variants) {
+                    Ok(mfest) => mfest,
+                    Err(repo::Error::Manifest(e)) => {
+                        return Err(in_package(e));
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+
+                for ent in mfest {
+                    let ent = match ent {
+                        Ok(ent) => ent,
+                        Err(pkgmf::Error::Parse(e)) if !params.strict => {
+                            warning!(archive.report, "{}", e);
+                            continue;
+                        }
+                        Err(e) => return Err(in_package(e)),
+                    };
+                    if let Entry::Set(set) = &ent {
+                        metadata.set(set);
+                    }
+                    if let Some(path) = ent.get_path() {
+                        if let Some(rule) = params.rules.excluded(path) {
+                            archive.exclude(path, rule);
+                        } else {
+                            if let Entry::File(pkgmf::File {
+                                chash: Some(chash),
+                                cname: Some(cname),
+                                ..
+                            }) = &ent
+                            {
+                                let payload = lock::Payload {
+                                    chash: chash.clone(),
+                                    hash: cname.clone(),
+                                };
+                                if let Some(lock) = &locked {
+                                    lock.check_file(pn, path, &payload)
+                                        .map_err(Error::Locked)?;
+                                }
+                                files.insert(path.to_string(), payload);
+                            }
+                            let res = append_tar(&mut archive, &source,
+                                &ent);
+                            failures.check(archive.report, res)?;
+                        }
+                    }
+                }
+            }
+            if let Some(lock) = &locked {
+                lock.check_complete(&built).map_err(Error::Locked)?;
+            }
+            archive.package = None;
+
+            archive
+        }
+    };
+
+    /*
+     * A rule which decided on no path is most likely a mistake, e.g., a path
+     * which the packages no longer deliver.
+     */
+    for rule in params.rules.unused() {
+        warning!(archive.report, "{} matched no path", rule);
+        if let Some(plan) = &mut archive.plan {
+            plan.unused.push(rule.to_string());
+        }
+    }
+
+    if !params.extra.is_empty() {
+        archive.report.emit(Event::Progress {
+            message: "EXTRA FILES AND LINKS:",
+        });
+    }
+    for extra in &params.extra {
+        let res = match extra {
+            Extra::File(entry, file) => {
+                append_tar(&mut archive, &TarFileSource::SingleFile(file),
+                    entry)
+            }
+            Extra::Link(entry) => {
+                append_tar(&mut archive, &TarFileSource::None, entry)
+            }
+            Extra::Shim(entry, mapfile, shim) => {
+                shim.build(mapfile).map_err(Error::Mapfile).and_then(|buf| {
+                    append_tar(&mut archive, &TarFileSource::Data(&buf),
+                        entry)
+                })
+            }
+        };
+        failures.check(archive.report, res)?;
+    }
+
+    if let Some(rules) = &params.dev_links {
+        archive.report.emit(Event::Progress {
+            message: "COMPILATION LINKS:",
+        });
+        for (path, target) in liblinks::missing(&archive.tree, rules) {
+            let entry = Entry::Link(pkgmf::Link {
+                path,
+                attr: pkgmf::FsAttr::default(),
+                target,
+            });
+            append_tar(&mut archive, &TarFileSource::None, &entry)?;
+        }
+    }
+
+    if let Some(manifest) = archive.stripper.manifest() {
+        let entry = Entry::File(pkgmf::File {
+            path: strip::MANIFEST.to_string(),
+            attr: pkgmf::FsAttr::default(),
+            chash: None,
+            cname: None,
+        });
+        let source = TarFileSource::Data(manifest.as_bytes());
+        append_tar(&mut archive, &source, &entry)?;
+    }
+
+    // The name of the release, if this is one.  An archive appended to
+    // already has its SYSROOT-INFO.
+    let mut release_name = None;
+    if matches!(params.source, Source::RepositoryPackages(..))
+        && !params.append
+    {
+        let arch = params.variants.get("arch").map(String::as_str);
+        let info = metadata.info(arch, params.release.as_ref());
+        if params.release.is_some() {
+            release_name = Some(info.file_name().map_err(|e| {
+                Error::Usage(format!("release: cannot name the archive: {}",
+                    e))
+            })?);
+        }
+        let entry = Entry::File(pkgmf::File {
+            path: release::INFO.to_string(),
+            attr: pkgmf::FsAttr::default(),
+            chash: None,
+            cname: None,
+        });
+        let json = info.json();
+        let source = TarFileSource::Data(json.as_bytes());
+        append_tar(&mut archive, &source, &entry)?;
+    }
+
+    failures.finish()?;
+
+    if let Some(mut plan) = archive.plan.take() {
+        let links = check_links(&mut archive, &params);
+        let mut tar = params.tar.clone();
+        if let Some(name) = release_name {
+            tar.set_file_name(name);
+        }
+        plan.output = tar.display().to_string();
+        match params.dry_run {
+            Some(report::Format::Json) => print!("{}", plan.json()),
+            _ => plan.lines().iter().for_each(|l| println!("{}", l)),
+        }
+        return links;
+    }
+
+    let tar_error = |error| Error::Tar {
+        path: Some(params.tar.display().to_string()),
+        error,
+    };
+    archive.builder.finish().map_err(tar_error)?;
+
+    let links = check_links(&mut archive, &params);
+    check_needed(&mut archive, &params.tar)?;
+    links?;
+
+    let mut tar = params.tar.clone();
+    if let Some(name) = release_name {
+        tar.set_file_name(name);
+        std::fs::rename(&params.tar, &tar)
+            .map_err(|error| Error::Io { path: tar.clone(), error })?;
+    }
+    archive.report.emit(Event::Wrote { path: &tar.display().to_string() });
+
+    if params.gzip {
+        gzip_copy(&tar, archive.report)?;
+    }
+
+    if let (Some(path), false) = (&params.lockfile, params.locked) {
+        built.save(path)
+            .map_err(|error| Error::Io { path: path.clone(), error })?;
+        archive.report.emit(Event::Wrote {
+            path: &path.display().to_string(),
+        });
+    }
+
+    archive.summary.warnings = archive.report.warnings();
+    archive.report.emit(Event::Summary(&archive.summary));
+    Ok(())
+}
+
+/// Write a copy of the tar file compressed with gzip, alongside it.
+fn gzip_copy(tar: &Path, report: &mut Reporter) -> error::Result<()> {
+    let mut name = tar.as_os_str().to_owned();
+    name.push(".gz");
+    let path = PathBuf::from(name);
+    let io_error = |error| Error::Io { path: path.clone(), error };
+    let mut gz = flate2::write::GzEncoder::new(
+        File::create(&path).map_err(io_error)?,
+        flate2::Compression::default(),
+    );
+    let mut input = File::open(tar).map_err(|error| Error::Tar {
+        path: Some(tar.display().to_string()),
+        error,
+    })?;
+    io::copy(&mut input, &mut gz).map_err(io_error)?;
+    gz.finish().map_err(io_error)?;
+    report.emit(Event::Wrote { path: &path.display().to_string() });
+    Ok(())
+}
+
+/// Check that each link in the archive leads to something which is also in
+/// the archive.  Dangling links are only an error if `strict`.
+fn check_links<W: io::Write>(
+    archive: &mut Archive<'_, W>,
+    params: &SysrootBuilder,
+) -> error::Result<()> {
+    let strict = params.strict;
+    let tree = &archive.tree;
+    let mut broken = 0;
+    for (path, node) in tree.nodes() {
+        let target = match node {
+            Node::Link(target) => target,
+            _ => continue,
+        };
+        let why = match tree.follow(path) {
+            Ok(_) => continue,
+            Err(Broken::Loop) => "is part of a loop of links".to_string(),
+            Err(Broken::Missing(missing)) => {
+                match params.rules.explain(&missing) {
+                    Some(rule) => format!("leads to {}, excluded by {}",
+                        missing, rule),
+                    None => format!("leads to {}, which is not in the \
+                        archive", missing),
+                }
+            }
+        };
+        archive.report.problem(strict, &format!("link {} -> {} {}", path,
+            target, why));
+        broken += 1;
+    }
+
+    if strict && broken > 0 {
+        return Err(Error::Check(format!("{} dangling link(s)", broken)));
+    }
+    Ok(())
+}
+
+/// Check that the dependencies of each library in the finished archive are
+/// also in the archive.  Missing libraries are only an error if `strict`.
+fn check_needed<W: io::Write>(
+    archive: &mut Archive<'_, W>,
+    tar: &Path,
+) -> error::Result<()> {
+    let strict = archive.strict;
+    let deps = File::open(tar)
+        .and_then(needed::check)
+        .map_err(|error| Error::Tar {
+            path: Some(tar.display().to_string()),
+            error,
+        })?;
+
+    for (path, e) in &deps.malformed {
+        archive.report.problem(strict, &format!("{}: {}", path, e));
+    }
+    for (name, needers) in &deps.unresolved {
+        archive.report.problem(strict, &format!("{} not found in archive \
+            (needed by {})", name, needers.join(", ")));
+    }
+
+    if strict && !(deps.malformed.is_empty() && deps.unresolved.is_empty()) {
+        return Err(Error::Check("library dependencies are incomplete".into()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spec() {
+        let spec = Spec::parse("output = \"x.tar\"\n\
+            packages = [\"a\"]\n\
+            [repository]\n\
+            path = \"repo\"\n\
+            [strip_sections]\n\
+            \"./usr/lib/**\" = [\".SUNW_ctf\"]\n", Path::new("/src"))
+            .unwrap();
+        let builder = SysrootBuilder::from_spec(spec);
+        assert_eq!(builder.tar, Path::new("/src/x.tar"));
+        assert_eq!(
+            builder.stripper.sections,
+            vec![("usr/lib/**".to_string(), vec![".SUNW_ctf".to_string()])]
+        );
+    }
+
+    #[test]
+    fn checks() {
+        let builder = SysrootBuilder::from_repository("repo",
+            vec!["a".to_string()], "x.tar")
+            .release(release::Release {
+                version: "v1".to_string(),
+                commit: None,
+            });
+        assert_eq!(builder.check(), Ok(()));
+        assert_eq!(
+            builder.append(true).check(),
+            Err("--release cannot be used with -a".to_string())
+        );
+    }
+}
@@ -0,0 +1,305 @@
+// Copyright 2020 Oxide Computer Company
+
+//! A model of the paths in an archive, so that symbolic links can be followed
+//! as they would be once the archive is unpacked.
+
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Node {
+    Dir,
+    File,
+    Link(String),
+}
+
+/// The limit on symbolic links followed while resolving one path, as with
+/// `MAXSYMLINKS`.
+const MAX_LINKS: usize = 20;
+
+/// Why a path could not be resolved.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Broken {
+    /// Nothing is at this path (or part of it is a file, where a directory
+    /// would be needed)
+    Missing(String),
+    /// Too many links were followed
+    Loop,
+}
+
+#[derive(Default)]
+pub struct Tree {
+    nodes: BTreeMap<String, Node>,
+}
+
+/// Strip any leading "/" or "./" and trailing "/" from an archive path.
+pub fn normalize(path: &str) -> String {
+    let mut parts = Vec::new();
+    for c in path.split('/') {
+        match c {
+            "" | "." => {}
+            c => parts.push(c),
+        }
+    }
+    parts.join("/")
+}
+
+/// Where a link at `path` to `target` leads, as a path in the tree (without
+/// following any further links), or `None` if a relative target leads out of
+/// the tree.  An absolute target is taken to be within the tree, as it will
+/// be once the archive is unpacked into a sysroot.
+pub fn link_destination(path: &str, target: &str) -> Option<String> {
+    let path = normalize(path);
+    let mut cur: Vec<&str> = Vec::new();
+    if !target.starts_with('/') {
+        cur.extend(path.split('/'));
+        cur.pop();
+    }
+    for c in target.split('/') {
+        match c {
+            "" | "." => {}
+            ".." => {
+                if cur.pop().is_none() && !target.starts_with('/') {
+                    return None;
+                }
+            }
+            c => cur.push(c),
+        }
+    }
+    Some(cur.join("/"))
+}
+
+/// A relative target for a link at `path` which leads to `dest`, a path in
+/// the tree.
+pub fn relative_target(path: &str, dest: &str) -> String {
+    let path = normalize(path);
+    let mut dir: Vec<&str> = path.split('/').collect();
+    dir.pop();
+    let dest: Vec<&str> = dest.split('/').filter(|c| !c.is_empty()).collect();
+
+    let common = dir.iter().zip(&dest).take_while(|(a, b)| a == b).count();
+    let mut out = vec![".."; dir.len() - common];
+    out.extend(&dest[common..]);
+    if out.is_empty() {
+        return ".".to_string();
+    }
+    out.join("/")
+}
+
+/// Whether `text` matches the glob `pattern`, in which `?` matches any one
+/// character and `*` any run of characters, except "/"; `**` also matches
+/// across "/".
+pub fn glob(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    glob_from(&p, &t)
+}
+
+fn glob_from(p: &[char], t: &[char]) -> bool {
+    match p.first() {
+        None => t.is_empty(),
+        Some('*') => {
+            let (deep, rest) = if p.get(1) == Some(&'*') {
+                (true, &p[2..])
+            } else {
+                (false, &p[1..])
+            };
+            for i in 0..=t.len() {
+                if glob_from(rest, &t[i..]) {
+                    return true;
+                }
+                if i < t.len() && t[i] == '/' && !deep {
+                    return false;
+                }
+            }
+            false
+        }
+        Some('?') => {
+            !t.is_empty() && t[0] != '/' && glob_from(&p[1..], &t[1..])
+        }
+        Some(c) => t.first() == Some(c) && glob_from(&p[1..], &t[1..]),
+    }
+}
+
+impl Tree {
+    pub fn new() -> Self {
+        Tree::default()
+    }
+
+    pub fn insert(&mut self, path: &str, node: Node) {
+        let path = normalize(path);
+        // Parent directories are implied, whether or not the archive
+        // includes them.
+        let mut dir = path.as_str();
+        while let Some(i) = dir.rfind('/') {
+            dir = &dir[..i];
+            self.nodes.entry(dir.to_string()).or_insert(Node::Dir);
+        }
+        self.nodes.insert(path, node);
+    }
+
+    /// Follow `path` through any symbolic links, returning the path (with
+    /// no links) at which it is found, and what is there.  Links outside of
+    /// the tree are followed as if the tree were mounted at "/".
+    pub fn resolve(&self, path: &str) -> Option<(String, &Node)> {
+        self.follow(path).ok()
+    }
+
+    /// As `resolve`, but explain why a path cannot be resolved.
+    pub fn follow(&self, path: &str) -> Result<(String, &Node), Broken> {
+        let mut links = 0;
+        self.resolve_from("", path, &mut links)
+    }
+
+    /// What is at `path`, without following any links.
+    pub fn get(&self, path: &str) -> Option<&Node> {
+        self.nodes.get(&normalize(path))
+    }
+
+    /// Each path in the tree, with what is there.
+    pub fn nodes(&self) -> impl Iterator<Item = (&String, &Node)> {
+        self.nodes.iter()
+    }
+
+    fn resolve_from(
+        &self,
+        dir: &str,
+        path: &str,
+        links: &mut usize,
+    ) -> Result<(String, &Node), Broken> {
+        let mut cur: Vec<String> = if path.starts_with('/') {
+            Vec::new()
+        } else {
+            dir.split('/')
+                .filter(|c| !c.is_empty())
+                .map(String::from)
+                .collect()
+        };
+
+        let comps: Vec<&str> = path.split('/').collect();
+        for (i, c) in comps.iter().enumerate() {
+            match *c {
+                "" | "." => continue,
+                ".." => {
+                    cur.pop();
+                    continue;
+                }
+                c => cur.push(c.to_string()),
+            }
+            let here = cur.join("/");
+            let missing = || {
+                let mut rest = cur.clone();
+                for c in &comps[i + 1..] {
+                    match *c {
+                        "" | "." => {}
+                        ".." => {
+                            rest.pop();
+                        }
+                        c => rest.push(c.to_string()),
+                    }
+                }
+                Broken::Missing(rest.join("/"))
+            };
+            match self.nodes.get(&here) {
+                None => return Err(missing()),
+                Some(Node::Link(target)) => {
+                    *links += 1;
+                    if *links > MAX_LINKS {
+                        return Err(Broken::Loop);
+                    }
+                    cur.pop();
+                    let (to, _) =
+                        self.resolve_from(&cur.join("/"), target, links)?;
+                    cur = to.split('/').map(String::from).collect();
+                    if to.is_empty() {
+                        cur.clear();
+                    }
+                }
+                Some(Node::File) if i + 1 < comps.len() => {
+                    return Err(missing());
+                }
+                _ => {}
+            }
+        }
+
+        let path = cur.join("/");
+        if path.is_empty() {
+            return Ok((path, &Node::Dir));
+        }
+        match self.nodes.get(&path) {
+            Some(n) => Ok((path, n)),
+            None => Err(Broken::Missing(path)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolution() {
+        let mut tree = Tree::new();
+        tree.insert("./usr/lib/amd64/libc.so.1", Node::File);
+        tree.insert("usr/lib/libc.so.1", Node::File);
+        tree.insert("usr/lib/64", Node::Link("amd64".to_string()));
+        tree.insert("lib", Node::Link("usr/lib".to_string()));
+        tree.insert("usr/lib/libc.so", Node::Link("./libc.so.1".to_string()));
+        tree.insert("usr/lib/abs.so", Node::Link("/lib/libc.so".to_string()));
+        tree.insert("usr/lib/loop", Node::Link("loop".to_string()));
+        tree.insert("usr/lib/up", Node::Link("../../..".to_string()));
+
+        let resolve = |p| tree.resolve(p).map(|(p, n)| (p, n.clone()));
+        assert_eq!(
+            resolve("lib/64/libc.so.1"),
+            Some(("usr/lib/amd64/libc.so.1".to_string(), Node::File))
+        );
+        assert_eq!(
+            resolve("usr/lib/abs.so"),
+            Some(("usr/lib/libc.so.1".to_string(), Node::File))
+        );
+        assert_eq!(resolve("lib/"), Some(("usr/lib".to_string(), Node::Dir)));
+        assert_eq!(resolve("usr/lib/up/usr"), Some(("usr".into(), Node::Dir)));
+        assert_eq!(resolve("usr/lib/loop"), None);
+        assert_eq!(resolve("usr/lib/libc.so.1/x"), None);
+        assert_eq!(resolve("usr/lib/libm.so.2"), None);
+
+        assert_eq!(tree.follow("usr/lib/loop"), Err(Broken::Loop));
+        assert_eq!(
+            tree.follow("lib/64/libm.so.2"),
+            Err(Broken::Missing("usr/lib/amd64/libm.so.2".to_string()))
+        );
+        assert_eq!(
+            tree.follow("usr/share/man/../doc"),
+            Err(Broken::Missing("usr/share/doc".to_string()))
+        );
+    }
+
+    #[test]
+    fn links() {
+        let rel = |path, target| {
+            link_destination(path, target).map(|d| relative_target(path, &d))
+        };
+        assert_eq!(
+            rel("usr/lib/libfoo.so", "/lib/libfoo.so.1").as_deref(),
+            Some("../../lib/libfoo.so.1")
+        );
+        assert_eq!(rel("usr/lib/64", "/usr/lib/amd64/").as_deref(),
+            Some("amd64"));
+        assert_eq!(rel("lib/64", "/lib").as_deref(), Some("."));
+        assert_eq!(rel("lib/root", "/../..").as_deref(), Some(".."));
+        assert_eq!(rel("usr/lib/x", "./y/../z").as_deref(), Some("z"));
+        assert_eq!(rel("usr/lib/x", "../../../etc/x"), None);
+        assert_eq!(rel("x", ".."), None);
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob("usr/lib/*.so.*", "usr/lib/libc.so.1"));
+        assert!(!glob("usr/lib/*.so.*", "usr/lib/amd64/libc.so.1"));
+        assert!(glob("usr/lib/**.so.?", "usr/lib/amd64/libc.so.1"));
+        assert!(glob("**", "usr/lib/libc.so.1"));
+        assert!(glob(".debug_*", ".debug_info"));
+        assert!(!glob(".debug_*", ".rela.debug_info"));
+        assert!(!glob("lib?", "lib"));
+    }
+}